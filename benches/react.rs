@@ -0,0 +1,223 @@
+use chrono::{DateTime, Utc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+use traefik_guard::compact::CompactDenyList;
+use traefik_guard::proto::{SecurityGroup, Visitor};
+use traefik_guard::state::SecurityGroupService;
+
+// visitor as it comes from the MaxMind lookup
+#[derive(Debug)]
+struct Request {
+    ip: IpAddr,
+    country: &'static str,
+    uri: &'static str,
+    time: DateTime<Utc>,
+}
+
+impl Visitor for Request {
+    fn country(&self) -> Option<String> {
+        Some(self.country.to_string())
+    }
+    fn city(&self) -> Option<String> {
+        Some("Bratislava".to_string())
+    }
+    fn subdivision(&self) -> Option<String> {
+        None
+    }
+    fn postal(&self) -> Option<String> {
+        None
+    }
+    fn asn(&self) -> Option<u32> {
+        Some(15169)
+    }
+    fn ip(&self) -> IpAddr {
+        self.ip
+    }
+    fn uri(&self) -> String {
+        self.uri.to_string()
+    }
+    fn method(&self) -> Option<String> {
+        Some("GET".to_string())
+    }
+    fn host(&self) -> Option<String> {
+        Some("www.example.com".to_string())
+    }
+    fn scheme(&self) -> Option<String> {
+        Some("https".to_string())
+    }
+    fn query(&self) -> Option<String> {
+        None
+    }
+    fn geo_unknown(&self) -> bool {
+        false
+    }
+    fn time(&self) -> DateTime<Utc> {
+        self.time
+    }
+    fn paths_ignore_case(&self) -> bool {
+        false
+    }
+    fn user_agent(&self) -> Option<String> {
+        Some("Mozilla/5.0 (X11; Linux x86_64)".to_string())
+    }
+}
+
+// deny lists of IPs and countries, protected paths and a few slow rules
+fn ruleset() -> String {
+    let mut rules = vec![];
+    for country in ["CN", "RU", "KP", "IR", "SY", "BY", "VE", "CU", "SD", "MM"] {
+        rules.push(format!("403|{}", country));
+    }
+    for i in 0..2000u32 {
+        rules.push(format!("403|10.{}.{}.{}", i >> 16, (i >> 8) & 255, i & 255));
+    }
+    for i in 0..200 {
+        rules.push(format!("401|/admin/{}", i));
+    }
+    for i in 0..100 {
+        rules.push(format!("403|203.0.{}.0/24", i));
+    }
+    for i in 0..20 {
+        rules.push(format!("403|~^/user/[0-9]+/secret{}$", i));
+        rules.push(format!("403|ua:badbot{}", i));
+    }
+    rules.push("301|http:|https://{host}{uri}".to_string());
+    rules.join("\n")
+}
+
+fn bench_react(c: &mut Criterion) {
+    let mut svc = SecurityGroupService::default();
+    svc.seed_group("default", &ruleset());
+    let time = Utc::now();
+    let visit = |ip: [u8; 4], country, uri| Request {
+        ip: IpAddr::V4(Ipv4Addr::from(ip)),
+        country,
+        uri,
+        time,
+    };
+    let cases = [
+        ("allowed", visit([8, 8, 8, 8], "SK", "/products/42")),
+        ("blocked_ip", visit([10, 0, 1, 7], "SK", "/")),
+        ("blocked_country", visit([1, 2, 3, 4], "CN", "/")),
+        ("blocked_path", visit([8, 8, 8, 8], "SK", "/admin/150")),
+    ];
+    for (name, v) in &cases {
+        c.bench_function(&format!("react_{}", name), |b| {
+            b.iter(|| svc.react("default", black_box(v)).unwrap())
+        });
+    }
+}
+
+// big deny list of networks with the different prefix lengths,
+// with a path rule, so it is not kept as a compact deny list
+fn networks() -> String {
+    let mut rules = vec!["401|/admin".to_string()];
+    for i in 0..5000u32 {
+        let prefix = [16, 20, 24, 28][i as usize % 4];
+        rules.push(format!("403|{}/{}", Ipv4Addr::from((i + 1) << 16), prefix));
+    }
+    rules.join("\n")
+}
+
+fn bench_networks(c: &mut Criterion) {
+    let mut svc = SecurityGroupService::default();
+    svc.seed_group("networks", &networks());
+    let time = Utc::now();
+    let visit = |ip: [u8; 4]| Request {
+        ip: IpAddr::V4(Ipv4Addr::from(ip)),
+        country: "SK",
+        uri: "/",
+        time,
+    };
+    let cases = [
+        ("allowed", visit([250, 0, 0, 1])),
+        ("blocked", visit([19, 135, 0, 1])),
+    ];
+    for (name, v) in &cases {
+        c.bench_function(&format!("networks_{}", name), |b| {
+            b.iter(|| svc.react("networks", black_box(v)).unwrap())
+        });
+    }
+}
+
+fn bench_load(c: &mut Criterion) {
+    let rules = ruleset();
+    c.bench_function("load_ruleset", |b| {
+        b.iter(|| SecurityGroup::from_reader("default", &mut black_box(rules.as_bytes())))
+    });
+}
+
+// threat feed of single IP addresses, kept as a compact deny list
+fn feed(size: u32) -> Vec<String> {
+    (0..size)
+        .map(|i| format!("403|{}", Ipv4Addr::from(0x0b00_0000 + i * 3)))
+        .collect()
+}
+
+fn bench_compact(c: &mut Criterion) {
+    let lines = feed(1_000_000);
+    c.bench_function("compact_load", |b| {
+        b.iter(|| CompactDenyList::from_lines(black_box(&lines)).unwrap())
+    });
+    let list = CompactDenyList::from_lines(&lines).unwrap();
+    let cases = [
+        ("blocked", Ipv4Addr::new(11, 0, 0, 3)),
+        ("allowed", Ipv4Addr::new(11, 0, 0, 4)),
+    ];
+    for (name, ip) in cases {
+        c.bench_function(&format!("compact_{}", name), |b| {
+            b.iter(|| list.react(black_box(ip)))
+        });
+    }
+}
+
+// reactions evaluated by all the cores at once, without the cache, with one lock and sharded
+fn bench_cache(c: &mut Criterion) {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let time = Utc::now();
+    let visits: Vec<Request> = (0..1000u32)
+        .map(|i| Request {
+            ip: IpAddr::V4(Ipv4Addr::from(0x0800_0000 + i)),
+            country: "SK",
+            uri: "/user/1/profile",
+            time,
+        })
+        .collect();
+    for (name, size, shards) in [
+        ("off", 0, 1),
+        ("1_shard", 10_000, 1),
+        ("16_shards", 10_000, 16),
+    ] {
+        let mut svc = SecurityGroupService::default();
+        svc.seed_group("default", &ruleset());
+        svc.set_reaction_cache(size, shards);
+        c.bench_function(&format!("cache_{}_x{}", name, threads), |b| {
+            b.iter_custom(|iters| {
+                let started = Instant::now();
+                std::thread::scope(|s| {
+                    for t in 0..threads {
+                        let (svc, visits) = (&svc, &visits);
+                        s.spawn(move || {
+                            for i in 0..iters as usize {
+                                let v = &visits[(i * threads + t) % visits.len()];
+                                black_box(svc.react("default", v).unwrap());
+                            }
+                        });
+                    }
+                });
+                started.elapsed()
+            })
+        });
+    }
+}
+
+criterion_group!(
+    benches,
+    bench_react,
+    bench_networks,
+    bench_load,
+    bench_compact,
+    bench_cache
+);
+criterion_main!(benches);
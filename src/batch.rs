@@ -0,0 +1,81 @@
+use crate::state::SecurityGroupService;
+use crate::visitor::IntoVisitor;
+use std::collections::BTreeMap as Map;
+use std::io::{BufRead, Write};
+
+/// dry-runs the reactions of the group for `ip uri` pairs, one per line,
+/// printing `ip<TAB>uri<TAB>status` for each of them and the amount of each status at the end.
+/// Returns the amount of the requests by the reaction status
+pub fn check_batch<MM: IntoVisitor, R: BufRead, W: Write>(
+    svc: &SecurityGroupService,
+    nsg: &str,
+    mm: &MM,
+    input: R,
+    out: &mut W,
+) -> anyhow::Result<Map<u16, usize>> {
+    let mut summary = Map::new();
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (ip, uri) = match line.split_once(char::is_whitespace) {
+            Some((ip, uri)) => (ip, uri.trim()),
+            None => (line, "/"),
+        };
+        let reaction = ip
+            .parse()
+            .map_err(anyhow::Error::from)
+            .and_then(|ip| mm.visit(ip, uri))
+            .and_then(|v| svc.react(nsg, &v));
+        match reaction {
+            Ok(reaction) => {
+                writeln!(out, "{}\t{}\t{}", ip, uri, reaction.code())?;
+                *summary.entry(reaction.code()).or_insert(0) += 1;
+            }
+            Err(e) => writeln!(out, "{}\t{}\terror: {}", ip, uri, e)?,
+        }
+    }
+    for (code, count) in &summary {
+        writeln!(out, "# {}\t{}", code, count)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visitor::Visit;
+    use std::net::IpAddr;
+
+    struct NoGeo;
+
+    impl IntoVisitor for NoGeo {
+        fn visit(&self, ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit {
+                ip,
+                ..Visit::no_ip(uri)
+            })
+        }
+    }
+
+    #[test]
+    fn test_check_batch() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|1.1.1.1\n401|/admin", false)
+            .unwrap();
+        let input = "1.1.1.1 /\n# comment\n\n2.2.2.2 /admin\n2.2.2.2\nnot-ip /\n3.3.3.3 /admin/\n";
+        let mut out = vec![];
+        let summary = check_batch(&svc, "default", &NoGeo, input.as_bytes(), &mut out).unwrap();
+        assert_eq!(summary, Map::from([(200, 1), (401, 2), (403, 1)]));
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "1.1.1.1\t/\t403");
+        assert_eq!(lines[1], "2.2.2.2\t/admin\t401");
+        assert_eq!(lines[2], "2.2.2.2\t/\t200");
+        assert!(lines[3].starts_with("not-ip\t/\terror: "));
+        assert_eq!(lines[4], "3.3.3.3\t/admin/\t401");
+        assert_eq!(&lines[5..], ["# 200\t1", "# 401\t2", "# 403\t1"]);
+    }
+}
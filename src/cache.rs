@@ -0,0 +1,189 @@
+use crate::proto::{Reaction, Visitor};
+use crate::state::MatchInfo;
+use lru::LruCache;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+// reaction of the group with the rule that produced it
+pub type Cached = (Reaction, Option<MatchInfo>);
+
+// everything the reaction depends on, except the time of the visit:
+// the groups with the scheduled rules are not cached
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    nsg: String,
+    ip: IpAddr,
+    country: Option<String>,
+    city: Option<String>,
+    subdivision: Option<String>,
+    postal: Option<String>,
+    asn: Option<u32>,
+    geo_unknown: bool,
+    uri: String,
+    query: Option<String>,
+    method: Option<String>,
+    host: Option<String>,
+    scheme: Option<String>,
+    user_agent: Option<String>,
+    paths_ignore_case: bool,
+}
+
+impl CacheKey {
+    fn new(nsg: &str, v: &impl Visitor) -> Self {
+        Self {
+            nsg: nsg.to_string(),
+            ip: v.ip(),
+            country: v.country(),
+            city: v.city(),
+            subdivision: v.subdivision(),
+            postal: v.postal(),
+            asn: v.asn(),
+            geo_unknown: v.geo_unknown(),
+            uri: v.uri(),
+            query: v.query(),
+            method: v.method(),
+            host: v.host(),
+            scheme: v.scheme(),
+            user_agent: v.user_agent(),
+            paths_ignore_case: v.paths_ignore_case(),
+        }
+    }
+}
+
+/// LRU cache of the reactions, split into shards by the visitor IP.
+/// The same visitor always lands in the same shard, while the concurrent requests
+/// of different visitors mostly take different locks
+#[derive(Default)]
+pub struct ReactionCache {
+    shards: Vec<Mutex<LruCache<CacheKey, Cached>>>,
+}
+
+impl ReactionCache {
+    /// capacity is split evenly between the shards, 0 disables the cache
+    pub fn new(capacity: usize, shards: usize) -> Self {
+        let shards = shards.max(1);
+        match NonZeroUsize::new(capacity.div_ceil(shards)) {
+            Some(per_shard) => Self {
+                shards: (0..shards)
+                    .map(|_| Mutex::new(LruCache::new(per_shard)))
+                    .collect(),
+            },
+            None => Self::default(),
+        }
+    }
+
+    fn shard(&self, ip: IpAddr) -> Option<&Mutex<LruCache<CacheKey, Cached>>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        ip.hash(&mut hasher);
+        Some(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+
+    /// returns the cached reaction for the visitor, or the one of `react`, remembering it.
+    /// The reaction is evaluated without holding the lock of the shard
+    pub fn get_or_insert_with(
+        &self,
+        nsg: &str,
+        v: &impl Visitor,
+        react: impl FnOnce() -> Cached,
+    ) -> Cached {
+        let Some(shard) = self.shard(v.ip()) else {
+            return react();
+        };
+        let key = CacheKey::new(nsg, v);
+        if let Some(hit) = shard.lock().unwrap().get(&key) {
+            return hit.clone();
+        }
+        let out = react();
+        shard.lock().unwrap().put(key, out.clone());
+        out
+    }
+
+    /// forgets all reactions, once the rules are changed
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visitor::Visit;
+
+    fn visit(ip: &str, uri: &str) -> Visit {
+        Visit {
+            ip: ip.parse().unwrap(),
+            ..Visit::no_ip(uri)
+        }
+    }
+
+    #[test]
+    fn test_cache_by_shards() {
+        let cache = ReactionCache::new(10, 4);
+        assert_eq!(cache.shards.len(), 4);
+        let mut evaluated = 0;
+        let mut react = |v: &Visit| {
+            cache.get_or_insert_with("default", v, || {
+                evaluated += 1;
+                (Reaction::HttpStatus(403), None)
+            })
+        };
+        for _ in 0..3 {
+            react(&visit("1.1.1.1", "/"));
+            react(&visit("1.1.1.1", "/admin"));
+            react(&visit("2.2.2.2", "/"));
+        }
+        assert_eq!(evaluated, 3);
+        assert_eq!(cache.len(), 3);
+        // the visitor keeps its shard
+        let ip = "1.1.1.1".parse().unwrap();
+        let shard = cache.shard(ip).unwrap() as *const _;
+        assert!(std::ptr::eq(shard, cache.shard(ip).unwrap()));
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recent() {
+        let cache = ReactionCache::new(2, 1);
+        let react = |uri: &str, code: u16| {
+            let v = visit("1.1.1.1", uri);
+            let (reaction, _) =
+                cache.get_or_insert_with("default", &v, || (Reaction::HttpStatus(code), None));
+            reaction.code()
+        };
+        assert_eq!(react("/a", 401), 401);
+        assert_eq!(react("/b", 403), 403);
+        assert_eq!(react("/a", 200), 401);
+        assert_eq!(react("/c", 404), 404);
+        // "/b" was used the least recently
+        assert_eq!(react("/b", 200), 200);
+        assert_eq!(react("/c", 200), 404);
+    }
+
+    #[test]
+    fn test_cache_disabled() {
+        let cache = ReactionCache::new(0, 16);
+        let v = visit("1.1.1.1", "/");
+        cache.get_or_insert_with("default", &v, || (Reaction::HttpStatus(403), None));
+        let (reaction, _) =
+            cache.get_or_insert_with("default", &v, || (Reaction::HttpStatus(200), None));
+        assert_eq!(reaction.code(), 200);
+        assert!(cache.is_empty());
+    }
+}
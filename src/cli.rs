@@ -1,4 +1,5 @@
 use clap::{Parser, ValueEnum};
+use serde::{Serialize, Serializer};
 
 #[derive(Debug, Clone, PartialEq, ValueEnum)]
 pub enum RuleRefType {
@@ -7,11 +8,47 @@ pub enum RuleRefType {
     All,
 }
 
+// where the security groups are kept
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum StorageBackend {
+    /// Rules files of the storage path, one per security group
+    File,
+    /// Nothing is stored, the rules are lost when the process stops
+    Memory,
+}
+
+// how one of the several redirect locations is chosen
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedirectBalance {
+    /// Locations are taken in turns
+    RoundRobin,
+    /// The same client IP is always sent to the same location
+    IpHash,
+}
+
+// format of the access log lines
+#[derive(Debug, Clone, PartialEq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// Apache combined log format
+    Apache,
+    /// JSON object per line, with geo location of the visitor
+    Json,
+}
+
 // struct for clap subcommands
+// (parsed once, so the size of the server options doesn't matter)
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, clap::Subcommand)]
 pub enum Action {
     /// Add rule to the list of rules in the security group
-    Add { rule: String },
+    Add {
+        rule: String,
+        /// Skip the rules that are already in the security group
+        #[clap(long)]
+        dedupe: bool,
+    },
     /// List all rules in the security groups
     List { tags: Option<String> },
     /// Delete
@@ -35,33 +72,236 @@ pub enum Action {
         #[clap(long, default_value = "./", env = "TRAEFIK_GUARD_MAXMIND_PATH")]
         maxmind_path: String,
     },
-    /// Start HTTP server
-    Server {
-        /// Net listening address of HTTP server in case of "server" command
-        #[clap(long, default_value = "0.0.0.0:8000", env = "LISTEN")]
-        listen: String,
+    /// Check `IP URI` pairs from stdin, one per line, and show reaction status for each of them
+    CheckBatch {
         /// Path to MaxMind database (GeoLite2-City.mmdb)
         #[clap(long, default_value = "./", env = "TRAEFIK_GUARD_MAXMIND_PATH")]
         maxmind_path: String,
-        /// Secret token to manage rules via HTTP API
-        #[clap(long, default_value = "", env = "TRAEFIK_GUARD_SECRET_TOKEN")]
-        secret_token: String,
-        /// Path to a daily access log accumulation directory. Leave empty to disable access logging
-        #[clap(long, default_value = "", env = "TRAEFIK_GUARD_ACCESS_LOG_DIR")]
-        access_log_path: String,
     },
+    /// Show the rules of the security group that are never reached or combined in a confusing way.
+    /// Fails if there are any
+    Lint,
+    /// Rename the tag on all rules of the security group
+    RenameTag { from: String, to: String },
+    /// Export all security groups with their rules as JSON to stdout
+    Export,
+    /// Import security groups from JSON export on stdin, replacing the groups with the same names
+    Import,
+    /// Start HTTP server
+    Server(ServerOpts),
+}
+
+// hides the value of the secret, showing only whether it was set
+fn redacted<S: Serializer>(secret: &str, s: S) -> Result<S::Ok, S::Error> {
+    if secret.is_empty() {
+        s.serialize_str("")
+    } else {
+        s.serialize_str("<redacted>")
+    }
+}
+
+// validates the path of the route to be absolute
+fn route_path(s: &str) -> Result<String, String> {
+    if s.starts_with('/') {
+        Ok(s.to_string())
+    } else {
+        Err(format!("route path {:?} must start with '/'", s))
+    }
+}
+
+// options of the HTTP server
+#[derive(Debug, Clone, Parser, Serialize)]
+pub struct ServerOpts {
+    /// The only security group served, from the global --nsg option
+    #[clap(skip)]
+    pub nsg: Option<String>,
+    /// Net listening address of HTTP server in case of "server" command
+    #[clap(long, default_value = "0.0.0.0:8000", env = "LISTEN")]
+    pub listen: String,
+    /// Path to MaxMind database (GeoLite2-City.mmdb)
+    #[clap(long, default_value = "./", env = "TRAEFIK_GUARD_MAXMIND_PATH")]
+    pub maxmind_path: String,
+    /// How often to check the MaxMind databases for updates, in seconds.
+    /// Changed files are reopened without restart. Disabled if not set
+    #[clap(long, env = "TRAEFIK_GUARD_MAXMIND_RELOAD_SECS")]
+    pub maxmind_reload_secs: Option<u64>,
+    /// Fail to start without the MaxMind City database.
+    /// Otherwise the server starts without geo data, and country or city rules never match
+    #[clap(long, env = "TRAEFIK_GUARD_REQUIRE_MAXMIND")]
+    pub require_maxmind: bool,
+    /// Secret token to manage rules via HTTP API
+    #[clap(long, default_value = "", env = "TRAEFIK_GUARD_SECRET_TOKEN")]
+    #[serde(serialize_with = "redacted")]
+    pub secret_token: String,
+    /// Path to a daily access log accumulation directory. Leave empty to disable access logging
+    #[clap(long, default_value = "", env = "TRAEFIK_GUARD_ACCESS_LOG_DIR")]
+    pub access_log_path: String,
+    /// Format of the access log lines
+    #[clap(
+        long,
+        value_enum,
+        default_value = "apache",
+        env = "TRAEFIK_GUARD_ACCESS_LOG_FORMAT"
+    )]
+    pub access_log_format: AccessLogFormat,
+    /// Write allowed (200) requests to the access log too, not only the reactions
+    #[clap(long, env = "TRAEFIK_GUARD_ACCESS_LOG_ALLOWED")]
+    pub access_log_allowed: bool,
+    /// Remove the access logs older than this amount of days, checked daily. Kept forever if not set
+    #[clap(long, env = "TRAEFIK_GUARD_ACCESS_LOG_RETENTION_DAYS")]
+    pub access_log_retention_days: Option<u32>,
+    /// Start a new access log file of the same day once the current one reaches this size
+    #[clap(long, env = "TRAEFIK_GUARD_ACCESS_LOG_MAX_BYTES")]
+    pub access_log_max_bytes: Option<u64>,
+    /// Always answer 200 to the guard requests, the decided status is only passed in the
+    /// x-guard-status header for the middlewares down the chain
+    #[clap(long, env = "TRAEFIK_GUARD_ALWAYS_200")]
+    pub always_200: bool,
+    /// Status of the responses to the clients over the rate limit of the `ratelimit:RPM:BURST` rules
+    #[clap(long, default_value = "429", value_parser = clap::value_parser!(u16).range(400..600), env = "TRAEFIK_GUARD_RATE_LIMIT_STATUS")]
+    pub rate_limit_status: u16,
+    /// Plain text body of the responses to the clients over the rate limit
+    #[clap(long, env = "TRAEFIK_GUARD_RATE_LIMIT_BODY")]
+    pub rate_limit_body: Option<String>,
+    /// Send X-RateLimit-Limit (burst size), X-RateLimit-Remaining and X-RateLimit-Reset (seconds
+    /// until the bucket is full) headers with the responses of the rate-limit rules
+    #[clap(long, env = "TRAEFIK_GUARD_RATE_LIMIT_HEADERS")]
+    pub rate_limit_headers: bool,
+    /// Retry-After seconds of the 429 and 503 reactions, 0 to leave the header out.
+    /// Rate-limited clients get the refill time of their bucket instead
+    #[clap(long, default_value = "60", env = "TRAEFIK_GUARD_RETRY_AFTER_SECS")]
+    pub retry_after_secs: u64,
+    /// Attach the geo headers of the visitor (x-country-code, x-city-en-name, x-asn) to the allowed
+    /// responses too, so the backend could consume them. Blocked responses always have them
+    #[clap(long, env = "TRAEFIK_GUARD_FORWARD_GEO_HEADERS")]
+    pub forward_geo_headers: bool,
+    /// Expose details of the matched rule (the rule itself, its tags) as response headers
+    #[clap(long, env = "TRAEFIK_GUARD_DEBUG_HEADERS")]
+    pub debug_headers: bool,
+    /// Number of trusted proxies appending to X-Forwarded-For in front of the guard.
+    /// Client IP is taken that many positions from the right instead of the leftmost one
+    #[clap(long, env = "TRAEFIK_GUARD_XFF_TRUSTED_HOPS")]
+    pub xff_trusted_hops: Option<usize>,
+    /// Selection of the client IP from X-Forwarded-For. `rightmost-untrusted` walks the list
+    /// from the right skipping the --trusted-proxies networks, --xff-trusted-hops is ignored then
+    #[clap(
+        long,
+        value_enum,
+        default_value = "leftmost",
+        env = "TRAEFIK_GUARD_XFF_STRATEGY"
+    )]
+    pub xff_strategy: crate::endpoints::client_ip::XffStrategy,
+    /// Comma-separated networks of the proxies allowed to pass the client IP in the forwarding headers.
+    /// When set, requests from other peers are evaluated by their socket address
+    #[clap(long, value_delimiter = ',', env = "TRAEFIK_GUARD_TRUSTED_PROXIES")]
+    pub trusted_proxies: Vec<ipnetwork::IpNetwork>,
+    /// Comma-separated headers to take the client IP from, in the order of precedence,
+    /// e.g. `true-client-ip,x-forwarded-for`. Default: cf-connecting-ip,x-forwarded-for,x-real-ip
+    #[clap(long, value_delimiter = ',', env = "TRAEFIK_GUARD_CLIENT_IP_HEADERS")]
+    pub client_ip_headers: Vec<crate::endpoints::client_ip::IpHeader>,
+    /// Selection of the location for redirects with several comma-separated locations
+    #[clap(
+        long,
+        value_enum,
+        default_value = "round-robin",
+        env = "TRAEFIK_GUARD_REDIRECT_BALANCE"
+    )]
+    pub redirect_balance: RedirectBalance,
+    /// Time limit of handling a request, in milliseconds. Slower requests are answered with 408,
+    /// so the forward-auth request of Traefik is never stalled
+    #[clap(long, default_value = "5000", env = "TRAEFIK_GUARD_REQUEST_TIMEOUT_MS")]
+    pub request_timeout_ms: u64,
+    /// Size limit of the request body, in bytes, e.g. for the posted rules.
+    /// Larger requests are answered with 413. The guard requests are limited to 1 KiB regardless
+    #[clap(long, default_value = "1048576", env = "TRAEFIK_GUARD_MAX_BODY_BYTES")]
+    pub max_body_bytes: usize,
+    /// Origin allowed to call the API from a browser, e.g. `https://admin.example.com`.
+    /// Repeatable or comma-separated. Any origin is allowed if not set
+    #[clap(long, value_delimiter = ',', env = "TRAEFIK_GUARD_CORS_ORIGIN")]
+    pub cors_origin: Vec<String>,
+    /// Allow browsers to send credentials to the API. Requires --cors-origin,
+    /// as credentials cannot be combined with any origin
+    #[clap(
+        long,
+        requires = "cors_origin",
+        env = "TRAEFIK_GUARD_CORS_ALLOW_CREDENTIALS"
+    )]
+    pub cors_allow_credentials: bool,
+    /// Path of the Prometheus metrics route
+    #[clap(long, default_value = "/metrics", value_parser = route_path, env = "TRAEFIK_GUARD_METRICS_PATH")]
+    pub metrics_path: String,
+    /// Path of the OpenAPI specification route
+    #[clap(long, default_value = "/openapi.json", value_parser = route_path, env = "TRAEFIK_GUARD_OPENAPI_PATH")]
+    pub openapi_path: String,
+    /// Cache-Control header of the blocked and redirected guard responses.
+    /// Responses of the rules with source conditions are always "private, no-store",
+    /// unless the rule sets it with "cache:" tags, e.g. `403|/admin#cache:public,cache:max-age=60`
+    #[clap(long, env = "TRAEFIK_GUARD_CACHE_CONTROL")]
+    pub cache_control: Option<String>,
+    /// File with the body of the blocked (4xx and 5xx) guard responses, empty body if not set.
+    /// `{ip}`, `{country}` and `{uri}` are replaced with the visitor details.
+    /// Content type is taken from the extension: .html, .json, plain text otherwise
+    #[clap(long, env = "TRAEFIK_GUARD_BLOCK_BODY_FILE")]
+    pub block_body_file: Option<String>,
+    /// Vary header of the blocked and redirected guard responses
+    #[clap(long, env = "TRAEFIK_GUARD_VARY")]
+    pub vary: Option<String>,
+    /// Comma-separated HTTP methods the rules are enforced on, e.g. "POST,PUT,DELETE,PATCH".
+    /// Requests with other methods (X-Forwarded-Method) are always allowed. Empty to enforce on all methods
+    #[clap(long, value_delimiter = ',', env = "TRAEFIK_GUARD_ENFORCE_METHODS")]
+    pub enforce_methods: Vec<String>,
+    /// Compare the paths of the rules with the request URI regardless of the letter case,
+    /// so `/admin` also matches `/Admin`. Query and regex targets are not affected
+    #[clap(long, env = "TRAEFIK_GUARD_PATHS_IGNORE_CASE")]
+    pub paths_ignore_case: bool,
+    /// URL to POST blocked requests to, in JSON batches. Events are dropped when the queue is full
+    #[clap(long, env = "TRAEFIK_GUARD_ALERT_WEBHOOK")]
+    pub alert_webhook: Option<String>,
+    /// Rules of the served security group (--nsg, "default" if not set), one per line, as in the rules file.
+    /// They replace the group of the same name loaded from the storage, the other groups are kept.
+    /// The inline group is never written to the storage, its changes through the API are kept in memory.
+    /// Only the other groups changed through the API are saved, also on shutdown.
+    /// With an empty --storage-path nothing is read from or written to the disk
+    #[clap(long, env = "TRAEFIK_GUARD_RULES")]
+    pub rules_inline: Option<String>,
+    /// Amount of the visitor reactions kept in memory, 0 to evaluate the rules on every request.
+    /// Reactions of the groups with the scheduled rules are never kept
+    #[clap(long, default_value = "0", env = "TRAEFIK_GUARD_REACTION_CACHE_SIZE")]
+    pub reaction_cache_size: usize,
+    /// Number of the independently locked parts of the reaction cache, picked by the client IP
+    #[clap(
+        long,
+        default_value = "16",
+        env = "TRAEFIK_GUARD_REACTION_CACHE_SHARDS"
+    )]
+    pub reaction_cache_shards: usize,
+    /// Reload security groups when their rules files are changed on disk
+    #[clap(long, env = "TRAEFIK_GUARD_WATCH")]
+    pub watch: bool,
 }
 
 // struct for clap CLI args
 #[derive(Debug, Parser)]
 #[clap(version = "0.1")]
 pub struct Opts {
-    /// Storage path, where *.rules.txt files are stored
+    /// Storage path, where *.rules.txt files are stored. Nothing is stored if empty
     #[clap(long, default_value = "./data", env = "TRAEFIK_GUARD_STORAGE_PATH")]
     pub storage_path: String,
-    /// Name of the security group
-    #[clap(long, default_value = "default")]
-    pub nsg: String,
+    /// Storage of the security groups
+    #[clap(
+        long,
+        value_enum,
+        default_value = "file",
+        env = "TRAEFIK_GUARD_STORAGE_BACKEND"
+    )]
+    pub storage_backend: StorageBackend,
+    /// Create the storage path if it doesn't exist, instead of failing
+    #[clap(long, env = "TRAEFIK_GUARD_CREATE_STORAGE")]
+    pub create_storage: bool,
+    /// Name of the security group, "default" if not set.
+    /// Restricts the server to this group only, all groups of the storage path are served if not set
+    #[clap(long)]
+    pub nsg: Option<String>,
     /// Action
     #[command(subcommand)]
     pub action: Action,
@@ -69,3 +309,15 @@ pub struct Opts {
     #[clap(env = "RUST_LOG")]
     log_level: Option<String>,
 }
+
+impl Opts {
+    pub fn rule_store(&self) -> Box<dyn crate::store::RuleStore> {
+        match self.storage_backend {
+            StorageBackend::File if !self.storage_path.is_empty() => {
+                let store = crate::store::FileStore::new(&self.storage_path);
+                Box::new(store.create_missing(self.create_storage))
+            }
+            _ => Box::new(crate::store::MemoryStore),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::proto::{Access, Reaction, Rule, Source, Target};
+use ipnetwork::Ipv4Network;
+use std::net::Ipv4Addr;
+
+/// minimal amount of rules in a group to consider storing it in compact form.
+/// Smaller groups are kept as regular rules, preserving the order of the lines in the file
+pub const COMPACT_MIN_RULES: usize = 1024;
+
+/// Compact storage for the security groups that consist only of single IP or network rules
+/// sharing the same reaction, typically threat feeds like `403|1.2.3.4` with millions of lines.
+/// Instead of the full `Rule` per line, it keeps sorted numeric addresses
+#[derive(Clone)]
+pub struct CompactDenyList {
+    pub reaction: Reaction,
+    // empty lines and comments at the top of the file, written before the rules
+    pub comments: Vec<String>,
+    // sorted IP addresses
+    ips: Vec<u32>,
+    // sorted network addresses, grouped by the prefix length (0..=32)
+    networks: Vec<Vec<u32>>,
+}
+
+impl std::fmt::Debug for CompactDenyList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut out = f.debug_struct("CompactDenyList");
+        out.field("reaction", &self.reaction);
+        out.field("comments", &self.comments.len());
+        out.field("ips", &self.ips.len());
+        out.field(
+            "networks",
+            &self.networks.iter().map(Vec::len).sum::<usize>(),
+        );
+        out.finish()
+    }
+}
+
+// single line of the compact group
+enum Entry {
+    Ip(u32),
+    Network(u32, u8),
+}
+
+// parses line of `CODE|IP` or `CODE|NETWORK` shape, without building the rule
+fn parse_line(line: &str) -> Option<(u16, Entry)> {
+    let (code, source) = line.split_once('|')?;
+    let code = code.parse::<u16>().ok()?;
+    if code == 200 {
+        return None;
+    }
+    if let Ok(ip) = source.parse::<Ipv4Addr>() {
+        return Some((code, Entry::Ip(u32::from(ip))));
+    }
+    let net = source.parse::<Ipv4Network>().ok()?;
+    if net.ip() != net.network() {
+        // keep non-canonical networks as they are written, in a regular rule
+        return None;
+    }
+    Some((code, Entry::Network(u32::from(net.network()), net.prefix())))
+}
+
+impl CompactDenyList {
+    /// builds compact list from the lines of the rules file, keeping the comments at the top.
+    /// Returns None if any of the lines doesn't fit into the compact form,
+    /// or there is a comment between the rules, as the order of the rules is not kept
+    pub fn from_lines<'a>(lines: impl IntoIterator<Item = &'a String>) -> Option<Self> {
+        let mut status = None;
+        let mut comments = vec![];
+        let mut ips = vec![];
+        let mut networks = vec![vec![]; 33];
+        for line in lines {
+            let ln = line.trim();
+            if status.is_none() && (ln.is_empty() || ln.starts_with('#')) {
+                comments.push(ln.to_string());
+                continue;
+            }
+            if ln.starts_with('#') {
+                return None;
+            }
+            if ln.is_empty() {
+                continue;
+            }
+            let (code, entry) = parse_line(ln)?;
+            if *status.get_or_insert(code) != code {
+                return None;
+            }
+            match entry {
+                Entry::Ip(ip) => ips.push(ip),
+                Entry::Network(net, prefix) => networks[prefix as usize].push(net),
+            }
+        }
+        let out = Self {
+            reaction: Reaction::HttpStatus(status?),
+            comments,
+            ips,
+            networks,
+        };
+        if out.len() < COMPACT_MIN_RULES {
+            return None;
+        }
+        Some(out.sorted())
+    }
+
+    fn sorted(mut self) -> Self {
+        self.ips.sort_unstable();
+        self.ips.dedup();
+        for nets in self.networks.iter_mut() {
+            nets.sort_unstable();
+            nets.dedup();
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ips.len() + self.networks.iter().map(Vec::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn ip_rule(&self, ip: u32) -> Rule {
+        self.rule(Source::FromIpv4(Ipv4Addr::from(ip)))
+    }
+
+    fn network_rule(&self, net: u32, prefix: u8) -> Rule {
+        let net = Ipv4Network::new(Ipv4Addr::from(net), prefix).unwrap();
+        self.rule(Source::FromIpv4Network(net))
+    }
+
+    fn rule(&self, source: Source) -> Rule {
+        Rule {
+            access: vec![Access::From(source)],
+            target: vec![Target::Any],
+            reaction: self.reaction.clone(),
+            tags: vec![],
+            methods: vec![],
+            schedule: None,
+            disabled: false,
+            note: None,
+            origin: None,
+            comments: vec![],
+        }
+    }
+
+    /// materializes the list as regular rules, IP addresses first, then networks
+    pub fn rules(&self) -> impl Iterator<Item = Rule> + '_ {
+        let ips = self.ips.iter().map(|ip| self.ip_rule(*ip));
+        let networks = self
+            .networks
+            .iter()
+            .enumerate()
+            .flat_map(move |(prefix, nets)| {
+                nets.iter()
+                    .map(move |net| self.network_rule(*net, prefix as u8))
+            });
+        ips.chain(networks)
+    }
+
+    /// returns the rule that contains the given IP address, if any
+    pub fn react(&self, ip: Ipv4Addr) -> Option<Rule> {
+        let ip = u32::from(ip);
+        if self.ips.binary_search(&ip).is_ok() {
+            return Some(self.ip_rule(ip));
+        }
+        for (prefix, nets) in self.networks.iter().enumerate() {
+            if nets.is_empty() {
+                continue;
+            }
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            if nets.binary_search(&(ip & mask)).is_ok() {
+                return Some(self.network_rule(ip & mask, prefix as u8));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(size: u32) -> Vec<String> {
+        let mut lines = vec!["# threat feed".to_string(), "".to_string()];
+        lines.extend((0..size).map(|i| format!("403|{}", Ipv4Addr::from(0x0b00_0000 + i * 3))));
+        lines.push("403|10.0.0.0/8".to_string());
+        lines.push("403|192.168.1.0/24".to_string());
+        lines
+    }
+
+    #[test]
+    fn test_compact_blocks_contained_ips() {
+        let list = CompactDenyList::from_lines(&feed(2000)).unwrap();
+        assert_eq!(list.len(), 2002);
+        assert_eq!(list.comments, vec!["# threat feed", ""]);
+        let r = list.react(Ipv4Addr::new(11, 0, 0, 3)).unwrap();
+        assert_eq!(r.reaction, Reaction::HttpStatus(403));
+        assert_eq!(r.to_string(), "403|11.0.0.3");
+        assert!(list.react(Ipv4Addr::new(11, 0, 0, 4)).is_none());
+        let r = list.react(Ipv4Addr::new(10, 20, 30, 40)).unwrap();
+        assert_eq!(r.to_string(), "403|10.0.0.0/8");
+        let r = list.react(Ipv4Addr::new(192, 168, 1, 77)).unwrap();
+        assert_eq!(r.to_string(), "403|192.168.1.0/24");
+        assert!(list.react(Ipv4Addr::new(192, 168, 2, 1)).is_none());
+    }
+
+    #[test]
+    fn test_compact_requires_uniform_shape() {
+        assert!(CompactDenyList::from_lines(&feed(10)).is_none());
+        let mut lines = feed(2000);
+        lines.push("403|US".to_string());
+        assert!(CompactDenyList::from_lines(&lines).is_none());
+        let mut lines = feed(2000);
+        lines.push("401|1.1.1.1".to_string());
+        assert!(CompactDenyList::from_lines(&lines).is_none());
+        let mut lines = feed(2000);
+        lines.push("403|1.1.1.1#feed".to_string());
+        assert!(CompactDenyList::from_lines(&lines).is_none());
+        // comments between the rules would lose their place
+        let mut lines = feed(2000);
+        lines.insert(100, "# networks".to_string());
+        assert!(CompactDenyList::from_lines(&lines).is_none());
+    }
+}
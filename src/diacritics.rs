@@ -4,12 +4,10 @@
 ///
 /// ```
 /// let string = "TÅRÖÄÆØ";
-/// let new_string = diacritics::remove_diacritics(string);
+/// let new_string = traefik_guard::diacritics::remove_diacritics(string);
 ///
 /// assert_eq!("TAROAAO", new_string);
 /// ```
-///
-
 pub fn remove_diacritics(string: &str) -> String {
     let chars = string.chars();
     chars.fold(String::with_capacity(string.len()), |mut acc, current| {
@@ -23,7 +21,7 @@ fn escape_diacritic(acc: &mut String, current: char) {
         | 'Ắ' | 'Ẵ' | 'Ẳ' | 'Ȧ' | 'Ǡ' | 'Ä' | 'Ǟ' | 'Ả' | 'Å' | 'Ǻ' | 'Ǎ' | 'Ȁ' | 'Ȃ' | 'Ạ'
         | 'Ậ' | 'Ặ' | 'Ḁ' | 'Ą' | 'Ⱥ' | 'Ɐ' => acc.push('A'),
         'Ꜳ' => acc.push_str("AA"),
-        'Æ' | 'Ǽ' | 'Ǣ' => acc.push_str("A"),
+        'Æ' | 'Ǽ' | 'Ǣ' => acc.push('A'),
         'Ꜵ' => acc.push_str("AO"),
         'Ꜷ' => acc.push_str("AU"),
         'Ꜹ' | 'Ꜻ' => acc.push_str("AV"),
@@ -1,16 +1,26 @@
-pub(crate) mod client_ip;
-pub(crate) mod metrics;
-pub(crate) mod openapi;
-pub(crate) mod prelude;
-pub(crate) mod react;
-pub(crate) mod server;
-
-// TODO: security layer, secret token to manage rules
-// TODO: differentiate 400 on the service layer somehow (for NSG-editing)
+pub mod access_log;
+pub mod alerts;
+pub mod auth;
+pub mod block_body;
+pub mod client_ip;
+pub mod config;
+pub mod geo;
+pub mod health;
+pub mod metrics;
+pub mod openapi;
+pub mod prelude;
+pub mod ratelimit;
+pub mod react;
+pub mod server;
+pub mod status;
+pub mod watch;
+
 // TODO: poison error handling
 // TOOD: skip empty lines in rules
 
+use crate::endpoints::auth::Authorized;
 pub use crate::proto::Visitor;
+pub use crate::state::{CreatedRules, GroupInfo, ReloadReport, RulesCount, TagInfo};
 pub use crate::tags::TagMap;
 pub use crate::visitor::IntoVisitor;
 use prelude::*;
@@ -21,15 +31,58 @@ where
 {
     pub svc: crate::state::SecurityGroupService,
     pub mm: MM,
-    pub access_log: String,
+    pub opts: crate::cli::ServerOpts,
+    // turn of the round-robin redirect location selection
+    pub redirect_turn: std::sync::atomic::AtomicUsize,
+    // queue of the block events for the alert webhook
+    pub alerts: alerts::Alerts,
+    // token buckets of the clients matching rate-limit rules
+    pub limiter: Mutex<ratelimit::RateLimiter>,
+    // set once the server is fully initialized, for the readiness probe
+    pub ready: health::Readiness,
+    // body of the blocked guard responses, empty if not set
+    pub block_body: Option<block_body::BlockBody>,
+}
+
+impl<MM> AppState<MM>
+where
+    MM: IntoVisitor,
+{
+    pub fn new(
+        svc: crate::state::SecurityGroupService,
+        mm: MM,
+        opts: crate::cli::ServerOpts,
+    ) -> Self {
+        let alerts = match &opts.alert_webhook {
+            Some(webhook) => alerts::Alerts::spawn(webhook),
+            None => alerts::Alerts::default(),
+        };
+        Self {
+            svc,
+            mm,
+            opts,
+            redirect_turn: Default::default(),
+            alerts,
+            limiter: Default::default(),
+            ready: Default::default(),
+            block_body: None,
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, IntoParams)]
 pub struct RulesListOptions {
+    /// comma-separated tags of the rules: `a,b` for any of them, `a+b` for all of them, `-c` to exclude
     #[param(example = "blacklist")]
     tags: Option<String>,
     #[param(example = "blacklist")]
     tag: Option<String>,
+    /// when listing, keep only indexed (fast) or only non-indexed (slow) rules
+    #[param(example = false)]
+    indexed: Option<bool>,
+    /// `json` for the structured rules, same as `Accept: application/json`
+    #[param(example = "json")]
+    format: Option<String>,
 }
 
 impl RulesListOptions {
@@ -42,6 +95,18 @@ impl RulesListOptions {
             },
         }
     }
+
+    // plain text stays the default, JSON is returned only when asked for explicitly
+    pub fn wants_json(&self, headers: &axum::http::HeaderMap) -> bool {
+        match &self.format {
+            Some(format) => format.eq_ignore_ascii_case("json"),
+            None => headers
+                .get_all(axum::http::header::ACCEPT)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| v.contains("application/json")),
+        }
+    }
 }
 
 /// nsg/{nsg}/rules
@@ -53,22 +118,30 @@ impl RulesListOptions {
         RulesListOptions,
     ),
     responses(
-        (status = 200, description = "retrieve rules for the security group in plain text, one rule per line", content_type = "text/plain"),
+        (status = 200, description = "retrieve rules for the security group in plain text, one rule per line, or JSON list of the rules when json is requested", content_type = "text/plain"),
+        (status = 404, description = "security group doesn't exist", body = HttpErrMessage),
     ),
 )]
 pub async fn handle_rules_list<MM>(
     Path(nsg): Path<String>,
     Query(opt): Query<RulesListOptions>,
-    Extension(state): Extension<Arc<Mutex<AppState<MM>>>>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+    headers: axum::http::HeaderMap,
 ) -> impl IntoResponse
 where
     MM: IntoVisitor,
 {
-    let state = state.lock().unwrap();
+    let state = state.read().unwrap();
+    if !state.svc.group_exists(&nsg) {
+        return err404(&format!("group {} not found", nsg)).into_response();
+    }
     let tm: TagMap = opt.tags();
-    match state.svc.list_rules_as_str(&nsg, &tm) {
+    if opt.wants_json(&headers) {
+        return Json(state.svc.list_rules(&nsg, &tm, opt.indexed)).into_response();
+    }
+    match state.svc.list_rules_as_str(&nsg, &tm, opt.indexed) {
         Ok(out) => {
-            if out.len() > 0 {
+            if !out.is_empty() {
                 out.into_response()
             } else {
                 "*\n".into_response()
@@ -78,30 +151,42 @@ where
     }
 }
 
+#[derive(Clone, Deserialize, IntoParams)]
+pub struct RulesAddOptions {
+    /// skip the rules that are already in the security group
+    #[serde(default)]
+    dedupe: bool,
+}
+
 /// nsg/{nsg}/rules
 #[utoipa::path(
     post,
     path = "/nsg/{nsg}/rules",
     params(
         ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+        RulesAddOptions,
     ),
     request_body(content = String, description = "rules in plain text, one rule per line", content_type = "text/plain"),
     responses(
-        (status = 200, description = "returns total amount of rules in the security group, plain text", content_type = "text/plain"),
-    )
+        (status = 200, description = "returns indexes of the created rules, to address them in update and delete, and the amount of skipped duplicates", body = CreatedRules),
+        (status = 400, description = "rule cannot be parsed", body = HttpErrMessage),
+        (status = 401, description = "secret token is missing or invalid"),
+    ),
 )]
 pub async fn handle_rules_add<MM>(
+    _: Authorized,
     Path(nsg): Path<String>,
-    Extension(state): Extension<Arc<Mutex<AppState<MM>>>>,
+    Query(opt): Query<RulesAddOptions>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
     body: String,
 ) -> impl IntoResponse
 where
     MM: IntoVisitor,
 {
-    let mut state = state.lock().unwrap();
-    match state.svc.create_rule(&nsg, &body) {
-        Ok(out) => out.to_string().into_response(),
-        Err(e) => err500(&e.to_string()).into_response(),
+    let mut state = state.write().unwrap();
+    match state.svc.create_rule(&nsg, &body, opt.dedupe) {
+        Ok(created) => Json(created).into_response(),
+        Err(e) => err_service(&e),
     }
 }
 
@@ -116,25 +201,32 @@ where
     request_body(content = String, description = "rule in plain text, one line is required", content_type = "text/plain"),
     responses(
         (status = 200, description = "delete rules for the security group by given tags", content_type = "text/plain"),
+        (status = 400, description = "rule cannot be parsed", body = HttpErrMessage),
+        (status = 401, description = "secret token is missing or invalid"),
+        (status = 404, description = "security group doesn't exist", body = HttpErrMessage),
     ),
 )]
 pub async fn handle_rules_update<MM>(
+    _: Authorized,
     Path(nsg): Path<String>,
     Query(opt): Query<RulesListOptions>,
-    Extension(state): Extension<Arc<Mutex<AppState<MM>>>>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
     body: String,
 ) -> impl IntoResponse
 where
     MM: IntoVisitor,
 {
-    let mut state = state.lock().unwrap();
+    let mut state = state.write().unwrap();
+    if !state.svc.group_exists(&nsg) {
+        return err404(&format!("group {} not found", nsg)).into_response();
+    }
     let tm: TagMap = opt.tags();
     match state
         .svc
         .update_rule(&nsg, &crate::state::RulesRef::Tag(tm), &body)
     {
         Ok(_) => "OK".into_response(),
-        Err(e) => err500(&e.to_string()).into_response(),
+        Err(e) => err_service(&e),
     }
 }
 
@@ -148,23 +240,470 @@ where
     ),
     responses(
         (status = 200, description = "delete rules for the security group by given tags", content_type = "text/plain"),
+        (status = 401, description = "secret token is missing or invalid"),
+        (status = 404, description = "security group doesn't exist", body = HttpErrMessage),
     ),
 )]
 pub async fn handle_rules_rm<MM>(
+    _: Authorized,
     Path(nsg): Path<String>,
     Query(opt): Query<RulesListOptions>, // can be extended to RulesRefOptions
-    Extension(state): Extension<Arc<Mutex<AppState<MM>>>>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
 ) -> impl IntoResponse
 where
     MM: IntoVisitor,
 {
-    let mut state = state.lock().unwrap();
+    let mut state = state.write().unwrap();
+    if !state.svc.group_exists(&nsg) {
+        return err404(&format!("group {} not found", nsg)).into_response();
+    }
     let tm: TagMap = opt.tags();
     match state
         .svc
         .delete_rule(&nsg, &crate::state::RulesRef::Tag(tm))
     {
         Ok(_) => "OK".into_response(),
+        Err(e) => err_service(&e),
+    }
+}
+
+/// nsg/{nsg}/rules/{index}
+#[utoipa::path(
+    put,
+    path = "/nsg/{nsg}/rules/{index}",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+        ("index" = usize, Path, description = "Index of the rule in the security group"),
+    ),
+    request_body(content = String, description = "rule in plain text, one line is required", content_type = "text/plain"),
+    responses(
+        (status = 200, description = "replace the rule at the given index", content_type = "text/plain"),
+        (status = 400, description = "rule cannot be parsed", body = HttpErrMessage),
+        (status = 401, description = "secret token is missing or invalid"),
+        (status = 404, description = "security group or rule doesn't exist", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_rule_update<MM>(
+    _: Authorized,
+    Path((nsg, index)): Path<(String, usize)>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+    body: String,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let mut state = state.write().unwrap();
+    match state
+        .svc
+        .update_rule(&nsg, &crate::state::RulesRef::Index(index), &body)
+    {
+        Ok(_) => "OK".into_response(),
+        Err(e) => err_service(&e),
+    }
+}
+
+/// nsg/{nsg}/rules/{index}
+#[utoipa::path(
+    delete,
+    path = "/nsg/{nsg}/rules/{index}",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+        ("index" = usize, Path, description = "Index of the rule in the security group"),
+    ),
+    responses(
+        (status = 200, description = "delete the rule at the given index", content_type = "text/plain"),
+        (status = 401, description = "secret token is missing or invalid"),
+        (status = 404, description = "security group or rule doesn't exist", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_rule_rm<MM>(
+    _: Authorized,
+    Path((nsg, index)): Path<(String, usize)>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let mut state = state.write().unwrap();
+    if !state.svc.group_exists(&nsg) {
+        return err404(&format!("group {} not found", nsg)).into_response();
+    }
+    match state
+        .svc
+        .delete_rule(&nsg, &crate::state::RulesRef::Index(index))
+    {
+        Ok(_) => "OK".into_response(),
+        Err(e) => err_service(&e),
+    }
+}
+
+/// nsg/{nsg}/rules/{index}/toggle
+#[utoipa::path(
+    post,
+    path = "/nsg/{nsg}/rules/{index}/toggle",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+        ("index" = usize, Path, description = "Index of the rule in the security group"),
+    ),
+    responses(
+        (status = 200, description = "disable or enable back the rule, returns 'disabled' or 'enabled'", content_type = "text/plain"),
+        (status = 401, description = "secret token is missing or invalid"),
+        (status = 404, description = "security group or rule doesn't exist", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_rules_toggle<MM>(
+    _: Authorized,
+    Path((nsg, index)): Path<(String, usize)>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let mut state = state.write().unwrap();
+    match state.svc.toggle_rule(&nsg, index) {
+        Ok(true) => "disabled".into_response(),
+        Ok(false) => "enabled".into_response(),
+        Err(e) => err_service(&e),
+    }
+}
+
+/// reload
+#[utoipa::path(
+    post,
+    path = "/reload",
+    responses(
+        (status = 200, description = "re-read all security groups from the storage path, with the problems of each group", body = ReloadReport),
+        (status = 401, description = "secret token is missing or invalid"),
+    ),
+)]
+pub async fn handle_reload<MM>(
+    _: Authorized,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let mut state = state.write().unwrap();
+    match state.svc.reload() {
+        Ok(report) => {
+            info!(
+                "reloaded {} groups, {} rules, {} groups with errors",
+                report.groups,
+                report.rules,
+                report.errors.len()
+            );
+            Json(report).into_response()
+        }
         Err(e) => err500(&e.to_string()).into_response(),
     }
 }
+
+#[derive(Clone, Deserialize, IntoParams)]
+pub struct GroupsListOptions {
+    /// return JSON list of groups with their rules count instead of the plain names
+    #[param(example = true)]
+    json: Option<bool>,
+}
+
+/// nsg
+#[utoipa::path(
+    get,
+    path = "/nsg",
+    params(GroupsListOptions),
+    responses(
+        (status = 200, description = "sorted names of the security groups in plain text, one per line, or GroupInfo JSON list when json is requested", content_type = "text/plain"),
+    ),
+)]
+pub async fn handle_groups_list<MM>(
+    Query(opt): Query<GroupsListOptions>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let state = state.read().unwrap();
+    let groups = state.svc.list_groups();
+    if opt.json.unwrap_or(false) {
+        Json(groups).into_response()
+    } else {
+        groups
+            .iter()
+            .map(|g| format!("{}\n", g.name))
+            .collect::<String>()
+            .into_response()
+    }
+}
+
+/// nsg/{nsg}
+#[utoipa::path(
+    delete,
+    path = "/nsg/{nsg}",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+    ),
+    responses(
+        (status = 200, description = "remove the security group with its rules file", content_type = "text/plain"),
+        (status = 401, description = "secret token is missing or invalid"),
+        (status = 404, description = "security group doesn't exist", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_group_rm<MM>(
+    _: Authorized,
+    Path(nsg): Path<String>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let mut state = state.write().unwrap();
+    match state.svc.delete_group(&nsg) {
+        Ok(true) => "OK".into_response(),
+        Ok(false) => err404(&format!("group {} not found", nsg)).into_response(),
+        Err(e) => err500(&e.to_string()).into_response(),
+    }
+}
+
+/// nsg/{nsg}/tags
+#[utoipa::path(
+    get,
+    path = "/nsg/{nsg}/tags",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+    ),
+    responses(
+        (status = 200, description = "tags of the rules in the security group, sorted, with the amount of rules having each of them", body = [TagInfo]),
+        (status = 404, description = "security group doesn't exist", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_tags_list<MM>(
+    Path(nsg): Path<String>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let state = state.read().unwrap();
+    if !state.svc.group_exists(&nsg) {
+        return err404(&format!("group {} not found", nsg)).into_response();
+    }
+    Json(state.svc.list_tags(&nsg)).into_response()
+}
+
+#[derive(Clone, Deserialize, IntoParams)]
+pub struct RenameTagOptions {
+    /// new name of the tag
+    #[param(example = "temporary")]
+    to: String,
+}
+
+/// nsg/{nsg}/tags/{tag}/rename
+#[utoipa::path(
+    post,
+    path = "/nsg/{nsg}/tags/{tag}/rename",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+        ("tag" = String, Path, description = "Current name of the tag, e.g. 'temp'"),
+        RenameTagOptions,
+    ),
+    responses(
+        (status = 200, description = "rename the tag on all rules of the security group, returns the number of rules updated", content_type = "text/plain"),
+        (status = 400, description = "new tag name is invalid", body = HttpErrMessage),
+        (status = 401, description = "secret token is missing or invalid"),
+        (status = 404, description = "security group doesn't exist", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_tag_rename<MM>(
+    _: Authorized,
+    Path((nsg, tag)): Path<(String, String)>,
+    Query(opt): Query<RenameTagOptions>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let mut state = state.write().unwrap();
+    match state.svc.rename_tag(&nsg, &tag, &opt.to) {
+        Ok(count) => count.to_string().into_response(),
+        Err(e) => err_service(&e),
+    }
+}
+
+/// geo location of the IP address, as seen by the rules
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GeoLookup {
+    #[schema(example = "8.8.8.8")]
+    ip: String,
+    #[schema(example = "US")]
+    country: Option<String>,
+    city: Option<String>,
+    #[schema(example = "US-CA")]
+    subdivision: Option<String>,
+    #[schema(example = "94043")]
+    postal: Option<String>,
+    #[schema(example = 15169)]
+    asn: Option<u32>,
+    /// the address is not found in the geo database
+    geo_unknown: bool,
+}
+
+/// lookup/{ip}
+#[utoipa::path(
+    get,
+    path = "/lookup/{ip}",
+    params(
+        ("ip" = String, Path, description = "IPv4 or IPv6 address, e.g. '8.8.8.8'"),
+    ),
+    responses(
+        (status = 200, description = "geo location of the IP address from the MaxMind databases, no rules are evaluated", body = GeoLookup),
+        (status = 400, description = "IP address cannot be parsed", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_lookup<MM>(
+    Path(ip): Path<String>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let ip = match ip.parse::<std::net::IpAddr>() {
+        Ok(ip) => crate::visitor::canonical_ip(ip),
+        Err(_) => return err400(&format!("invalid IP address {:?}", ip)).into_response(),
+    };
+    let state = state.read().unwrap();
+    let visitor = state
+        .mm
+        .visit(ip, "/")
+        .unwrap_or_else(|_| crate::visitor::Visit {
+            ip,
+            ..crate::visitor::Visit::geo_unknown("/")
+        });
+    Json(GeoLookup {
+        ip: ip.to_string(),
+        country: visitor.country(),
+        city: visitor.city(),
+        subdivision: visitor.subdivision(),
+        postal: visitor.postal(),
+        asn: visitor.asn(),
+        geo_unknown: visitor.geo_unknown(),
+    })
+    .into_response()
+}
+
+#[derive(Clone, Deserialize, IntoParams)]
+pub struct RuleTestOptions {
+    /// IP address of the visitor
+    #[param(example = "8.8.8.8")]
+    ip: String,
+    /// URI of the request, "/" if not set
+    #[param(example = "/admin")]
+    uri: Option<String>,
+}
+
+/// reaction of the rule to the visitor, the rule is not saved
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RuleTest {
+    /// the rule as it would be written in the rules file
+    #[schema(example = "403|US,/admin")]
+    rule: String,
+    #[schema(example = "8.8.8.8")]
+    ip: String,
+    #[schema(example = "/admin")]
+    uri: String,
+    /// whether the rule matches the visitor
+    matched: bool,
+    /// status of the reaction, none if the rule doesn't match
+    #[schema(example = 403)]
+    status: Option<u16>,
+    /// location of the redirect reactions
+    location: Option<String>,
+}
+
+/// nsg/{nsg}/test
+#[utoipa::path(
+    post,
+    path = "/nsg/{nsg}/test",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+        RuleTestOptions,
+    ),
+    request_body(content = String, description = "rule in plain text, one line is required", content_type = "text/plain"),
+    responses(
+        (status = 200, description = "reaction of the rule to the visitor, the security group is not changed", body = RuleTest),
+        (status = 400, description = "rule or IP address cannot be parsed", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_rule_test<MM>(
+    Path(_nsg): Path<String>,
+    Query(opt): Query<RuleTestOptions>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+    body: String,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let ip = match opt.ip.parse::<std::net::IpAddr>() {
+        Ok(ip) => crate::visitor::canonical_ip(ip),
+        Err(_) => return err400(&format!("invalid IP address {:?}", opt.ip)).into_response(),
+    };
+    let uri = opt.uri.unwrap_or_else(|| "/".to_string());
+    let state = state.read().unwrap();
+    let visitor = state
+        .mm
+        .visit(ip, &uri)
+        .unwrap_or_else(|_| crate::visitor::Visit {
+            ip,
+            ..crate::visitor::Visit::geo_unknown(&uri)
+        });
+    match crate::state::SecurityGroupService::test_rule(&body, &visitor) {
+        Ok((rule, reaction)) => Json(RuleTest {
+            rule: rule.to_string(),
+            ip: ip.to_string(),
+            uri,
+            matched: reaction.is_some(),
+            status: reaction.as_ref().map(|r| r.code()),
+            location: reaction.and_then(|r| r.redirect()),
+        })
+        .into_response(),
+        Err(e) => err_service(&e),
+    }
+}
+
+#[derive(Clone, Deserialize, IntoParams)]
+pub struct RulesCountOptions {
+    /// return indexed/non-indexed breakdown as JSON instead of the total
+    #[param(example = true)]
+    breakdown: Option<bool>,
+}
+
+/// nsg/{nsg}/count
+#[utoipa::path(
+    get,
+    path = "/nsg/{nsg}/count",
+    params(
+        ("nsg" = String, Path, description = "Name of the security group, e.g. 'default'"),
+        RulesCountOptions,
+    ),
+    responses(
+        (status = 200, description = "total amount of rules in the security group as plain text, or RulesCount JSON when breakdown is requested", content_type = "text/plain"),
+        (status = 404, description = "security group doesn't exist", body = HttpErrMessage),
+    ),
+)]
+pub async fn handle_rules_count<MM>(
+    Path(nsg): Path<String>,
+    Query(opt): Query<RulesCountOptions>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let state = state.read().unwrap();
+    if !state.svc.group_exists(&nsg) {
+        return err404(&format!("group {} not found", nsg)).into_response();
+    }
+    let count = state.svc.count_rules(&nsg);
+    if opt.breakdown.unwrap_or(false) {
+        Json(count).into_response()
+    } else {
+        count.total.to_string().into_response()
+    }
+}
@@ -0,0 +1,114 @@
+use super::*;
+use std::path::Path;
+use std::time::Duration;
+
+/// removes the daily access logs older than the given amount of days, then repeats it every day
+pub fn spawn_retention(access_log_path: &str, days: u32) {
+    let path = access_log_path.to_string();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            let today = chrono::Local::now().date_naive();
+            if let Err(e) = remove_expired(&path, days, today) {
+                warn!("cannot clean up access logs in {}: {:?}", path, e);
+            }
+        }
+    });
+    info!(
+        "keeping access logs in {} for {} days",
+        access_log_path, days
+    );
+}
+
+// removes `guard.YYYY-MM-DD[.N].log` files dated more than `days` before today,
+// returns the names of the removed files
+pub fn remove_expired(
+    path: &str,
+    days: u32,
+    today: chrono::NaiveDate,
+) -> anyhow::Result<Vec<String>> {
+    let oldest = today - chrono::Duration::days(days as i64);
+    let mut removed = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let date = match name
+            .strip_prefix("guard.")
+            .filter(|rest| rest.ends_with(".log"))
+            .and_then(|rest| rest.get(..10))
+            .and_then(|d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        {
+            Some(date) => date,
+            None => continue, // not an access log
+        };
+        if date < oldest {
+            std::fs::remove_file(entry.path())?;
+            info!("removed expired access log {}", name);
+            removed.push(name);
+        }
+    }
+    Ok(removed)
+}
+
+// moves the access log aside as `guard.YYYY-MM-DD.N.log` once it reaches the size limit,
+// so the next line starts a new file of the same day
+pub fn roll_if_full(filename: &str, max_bytes: u64) {
+    let size = match std::fs::metadata(filename) {
+        Ok(meta) => meta.len(),
+        Err(_) => return,
+    };
+    if size < max_bytes {
+        return;
+    }
+    let base = filename.trim_end_matches(".log");
+    let rolled = (1..)
+        .map(|n| format!("{}.{}.log", base, n))
+        .find(|f| !Path::new(f).exists())
+        .unwrap();
+    // another request could have rolled it already
+    if let Err(e) = std::fs::rename(filename, &rolled) {
+        debug!("cannot roll access log {} {:?}", filename, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "guard.2023-03-01.log",
+            "guard.2023-03-01.1.log",
+            "guard.2023-03-05.log",
+            "guard.2023-03-08.log",
+            "other.2023-03-01.log",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let today = chrono::NaiveDate::from_ymd_opt(2023, 3, 8).unwrap();
+        let mut removed = remove_expired(dir.path().to_str().unwrap(), 3, today).unwrap();
+        removed.sort();
+        assert_eq!(removed, ["guard.2023-03-01.1.log", "guard.2023-03-01.log"]);
+        assert!(dir.path().join("guard.2023-03-05.log").exists());
+        assert!(dir.path().join("other.2023-03-01.log").exists());
+    }
+
+    #[test]
+    fn test_roll_if_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("guard.2023-03-08.log");
+        let filename = file.to_str().unwrap();
+        std::fs::write(&file, "12345").unwrap();
+        roll_if_full(filename, 10);
+        assert!(file.exists());
+        roll_if_full(filename, 5);
+        assert!(!file.exists());
+        assert!(dir.path().join("guard.2023-03-08.1.log").exists());
+        std::fs::write(&file, "12345").unwrap();
+        roll_if_full(filename, 5);
+        assert!(dir.path().join("guard.2023-03-08.2.log").exists());
+    }
+}
@@ -0,0 +1,135 @@
+use super::metrics::{ALERTS_DROPPED, ALERTS_SENT};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::*;
+
+// amount of the block events waiting to be posted, the rest is dropped
+const QUEUE_SIZE: usize = 1024;
+// maximal amount of events in one webhook call
+const BATCH_SIZE: usize = 100;
+// minimal delay between webhook calls
+const INTERVAL: Duration = Duration::from_secs(1);
+
+// details of the blocked request, posted to the alert webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertEvent {
+    pub nsg: String,
+    pub ip: String,
+    pub uri: String,
+    pub code: u16,
+    pub country: Option<String>,
+    pub tags: Vec<String>,
+}
+
+// fire-and-forget queue of the block events, disabled if no webhook is configured
+#[derive(Debug, Clone, Default)]
+pub struct Alerts {
+    tx: Option<mpsc::Sender<AlertEvent>>,
+}
+
+impl Alerts {
+    // starts background task posting queued events to the webhook in batches
+    pub fn spawn(webhook: &str) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(post_batches(webhook.to_string(), rx));
+        Self { tx: Some(tx) }
+    }
+
+    // enqueues the event without waiting, dropping it if the queue is full
+    pub fn send(&self, event: AlertEvent) {
+        if let Some(tx) = &self.tx {
+            if tx.try_send(event).is_err() {
+                ALERTS_DROPPED.inc();
+            }
+        }
+    }
+}
+
+async fn post_batches(webhook: String, mut rx: mpsc::Receiver<AlertEvent>) {
+    let client = reqwest::Client::new();
+    while let Some(event) = rx.recv().await {
+        let mut batch = vec![event];
+        while batch.len() < BATCH_SIZE {
+            match rx.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        match client.post(&webhook).json(&batch).send().await {
+            Ok(res) if res.status().is_success() => ALERTS_SENT.inc_by(batch.len() as u64),
+            Ok(res) => warn!("alert webhook responded with {}", res.status()),
+            Err(e) => warn!("alert webhook failed {:?}", e),
+        }
+        tokio::time::sleep(INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::{extract::State, routing::post, Json, Router, Server};
+    use std::net::SocketAddr;
+
+    // webhook receiver, forwarding every call to the channel
+    pub(crate) async fn mock_webhook() -> (String, mpsc::Receiver<serde_json::Value>) {
+        let (tx, rx) = mpsc::channel(16);
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(tx): State<mpsc::Sender<serde_json::Value>>,
+                     Json(body): Json<serde_json::Value>| async move {
+                        tx.send(body).await.unwrap();
+                    },
+                ),
+            )
+            .with_state(tx);
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let server = Server::bind(&addr).serve(app.into_make_service());
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn it_posts_block_events() {
+        let (url, mut rx) = mock_webhook().await;
+        let alerts = Alerts::spawn(&url);
+        alerts.send(AlertEvent {
+            nsg: "default".to_string(),
+            ip: "8.8.8.8".to_string(),
+            uri: "/admin".to_string(),
+            code: 403,
+            country: Some("US".to_string()),
+            tags: vec!["blacklist".to_string()],
+        });
+        let body = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!([{
+                "nsg": "default",
+                "ip": "8.8.8.8",
+                "uri": "/admin",
+                "code": 403,
+                "country": "US",
+                "tags": ["blacklist"],
+            }])
+        );
+    }
+
+    #[test]
+    fn it_ignores_events_without_webhook() {
+        Alerts::default().send(AlertEvent {
+            nsg: "default".to_string(),
+            ip: "8.8.8.8".to_string(),
+            uri: "/".to_string(),
+            code: 403,
+            country: None,
+            tags: vec![],
+        });
+    }
+}
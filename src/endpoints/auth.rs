@@ -0,0 +1,83 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+};
+use serde::Deserialize;
+
+/// Secret token to manage the guard via HTTP API, provided as a request extension.
+/// Empty token leaves the API open
+#[derive(Debug, Clone, Default)]
+pub struct SecretToken(pub String);
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// returns the token, provided as `Authorization: Bearer <token>` header or `?token=` query param
+fn provided_token(parts: &Parts) -> Option<String> {
+    let bearer = parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .map(|s| s.trim().to_string());
+    bearer.or_else(|| {
+        Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|q| q.0.token)
+    })
+}
+
+/// An extractor, rejecting the request with 401 unless it carries the configured secret token
+#[derive(Debug)]
+pub struct Authorized;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Authorized
+where
+    S: Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let secret = parts
+            .extensions
+            .get::<SecretToken>()
+            .cloned()
+            .unwrap_or_default();
+        if secret.0.is_empty() || provided_token(parts).as_deref() == Some(secret.0.as_str()) {
+            Ok(Self)
+        } else {
+            Err((StatusCode::UNAUTHORIZED, "Unauthorized"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    async fn authorize(uri: &str, bearer: Option<&str>, secret: &str) -> bool {
+        let mut req = Request::get(uri).extension(SecretToken(secret.to_string()));
+        if let Some(token) = bearer {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let (mut parts, _) = req.body(()).unwrap().into_parts();
+        Authorized::from_request_parts(&mut parts, &())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn it_checks_the_token() {
+        assert!(authorize("/config", None, "").await);
+        assert!(!authorize("/config", None, "secret").await);
+        assert!(authorize("/config", Some("secret"), "secret").await);
+        assert!(!authorize("/config", Some("wrong"), "secret").await);
+        assert!(authorize("/config?token=secret", None, "secret").await);
+        assert!(!authorize("/config?token=wrong", None, "secret").await);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::proto::Visitor;
+use anyhow::Context;
+
+// body of the blocked guard responses, loaded once from the file.
+// `{ip}`, `{country}` and `{uri}` placeholders are replaced with the visitor details,
+// escaped for the content type, so the page cannot be used to inject markup
+#[derive(Debug, Clone)]
+pub struct BlockBody {
+    template: String,
+    pub content_type: &'static str,
+}
+
+impl BlockBody {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let template =
+            std::fs::read_to_string(path).with_context(|| format!("block body {}", path))?;
+        Ok(Self::new(template, path))
+    }
+
+    // content type is taken from the extension of the file name, plain text by default
+    pub fn new(template: String, file_name: &str) -> Self {
+        let extension = file_name.rsplit('.').next().unwrap_or_default();
+        let content_type = match extension.to_lowercase().as_str() {
+            "html" | "htm" => "text/html; charset=utf-8",
+            "json" => "application/json",
+            _ => "text/plain; charset=utf-8",
+        };
+        Self {
+            template,
+            content_type,
+        }
+    }
+
+    pub fn render(&self, v: &impl Visitor) -> String {
+        self.template
+            .replace("{ip}", &self.escape(&v.ip().to_string()))
+            .replace("{country}", &self.escape(&v.country().unwrap_or_default()))
+            .replace("{uri}", &self.escape(&v.uri()))
+    }
+
+    fn escape(&self, value: &str) -> String {
+        if self.content_type.starts_with("text/html") {
+            value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&#39;")
+        } else if self.content_type == "application/json" {
+            // inside of a JSON string, without the quotes
+            let quoted = serde_json::to_string(value).unwrap_or_default();
+            quoted[1..quoted.len() - 1].to_string()
+        } else {
+            value.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visitor::Visit;
+
+    #[test]
+    fn test_render_escaped() {
+        let visit = Visit {
+            ip: "8.8.8.8".parse().unwrap(),
+            country: Some("US".to_string()),
+            ..Visit::no_ip("/<script>\"")
+        };
+        let html = BlockBody::new("<p>{ip} from {country}: {uri}</p>".to_string(), "b.html");
+        assert_eq!(html.content_type, "text/html; charset=utf-8");
+        assert_eq!(
+            html.render(&visit),
+            "<p>8.8.8.8 from US: /&lt;script&gt;&quot;</p>"
+        );
+        let json = BlockBody::new(r#"{"uri":"{uri}"}"#.to_string(), "b.json");
+        assert_eq!(json.render(&visit), r#"{"uri":"/<script>\""}"#);
+        let text = BlockBody::new("blocked {country}".to_string(), "blocked");
+        assert_eq!(text.content_type, "text/plain; charset=utf-8");
+        assert_eq!(text.render(&Visit::no_ip("/")), "blocked ");
+    }
+}
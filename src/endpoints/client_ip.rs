@@ -1,8 +1,9 @@
 use axum::{
     async_trait,
     extract::{ConnectInfo, FromRequestParts},
-    http::{request::Parts, Extensions, StatusCode},
+    http::{header::HeaderName, request::Parts, Extensions, HeaderMap, StatusCode},
 };
+pub use rudimental::RightmostUntrustedXForwardedFor;
 use rudimental::*;
 use std::{
     marker::Sync,
@@ -10,8 +11,9 @@ use std::{
 };
 
 /// An client IP extractor - no security, but somehow better IP determination
-/// Technically it means looking for leftmost IP addresses provided by forward proxy first, and then look into single
-/// IP headers like `X-Real-Ip`, and then falling back to the [`axum::extract::ConnectInfo`].
+/// Technically it means looking into the headers of [`ClientIpConfig::headers`] in their order,
+/// by default `CF-Connecting-IP`, then leftmost IP provided by forward proxy, then `X-Real-Ip`,
+/// and then falling back to the [`axum::extract::ConnectInfo`].
 ///
 /// It returns a 500 error if you forget to provide the `ConnectInfo` with e.g.
 /// [`axum::routing::Router::into_make_service_with_connect_info`]
@@ -19,15 +21,123 @@ use std::{
 #[derive(Debug)]
 pub struct ClientIp(pub IpAddr);
 
+/// Configuration of the client IP determination, provided to [`ClientIp`] as a request extension
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpConfig {
+    /// Number of trusted proxies, appending to `X-Forwarded-For` header in front of the guard.
+    /// When set, the IP that many positions from the right is taken instead of the leftmost one
+    pub xff_trusted_hops: Option<usize>,
+    /// How the client IP is picked from the `X-Forwarded-For` list
+    pub xff_strategy: XffStrategy,
+    /// Networks of the proxies whose forwarding headers are honored, any peer is trusted if empty.
+    /// Requests from other peers get the IP of the socket peer, so they cannot spoof their source
+    pub trusted_proxies: Vec<ipnetwork::IpNetwork>,
+    /// Headers to look for the client IP in, in the order of precedence. Default order if empty
+    pub headers: Vec<IpHeader>,
+}
+
+/// Selection of the client IP from the `X-Forwarded-For` list
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum XffStrategy {
+    /// Leftmost IP, or the one before the trusted hops if their number is set.
+    /// The leftmost IP is sent by the client, so it can be spoofed
+    #[default]
+    Leftmost,
+    /// Rightmost IP that is not in the trusted proxies networks, the IP seen by the first trusted proxy
+    RightmostUntrusted,
+}
+
+/// Header that carries the client IP
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpHeader {
+    /// `X-Forwarded-For` list, its IP as selected by [`XffStrategy`]
+    XForwardedFor,
+    /// `Forwarded` list, its leftmost IP
+    Forwarded,
+    /// Header with a single IP, like `CF-Connecting-IP` (Cloudflare) or `True-Client-IP` (Akamai)
+    Single(HeaderName),
+}
+
+impl IpHeader {
+    /// Cloudflare header, then proxy chain, then Nginx header
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self::Single(HeaderName::from_static("cf-connecting-ip")),
+            Self::XForwardedFor,
+            Self::Single(HeaderName::from_static("x-real-ip")),
+        ]
+    }
+
+    fn maybe_ip(&self, headers: &HeaderMap, config: &ClientIpConfig) -> Option<IpAddr> {
+        match self {
+            Self::XForwardedFor => match (config.xff_strategy, config.xff_trusted_hops) {
+                (XffStrategy::RightmostUntrusted, _) => {
+                    XForwardedFor::maybe_rightmost_untrusted_ip(headers, &config.trusted_proxies)
+                }
+                (XffStrategy::Leftmost, Some(hops)) => {
+                    XForwardedFor::maybe_ip_before_hops(headers, hops)
+                }
+                (XffStrategy::Leftmost, None) => XForwardedFor::maybe_leftmost_ip(headers),
+            },
+            Self::Forwarded => Forwarded::maybe_leftmost_ip(headers),
+            Self::Single(name) => headers
+                .get(name)
+                .and_then(|hv| hv.to_str().ok())
+                .and_then(|s| s.trim().parse::<IpAddr>().ok()),
+        }
+    }
+}
+
+impl std::str::FromStr for IpHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = HeaderName::from_bytes(s.trim().as_bytes())
+            .map_err(|_| format!("invalid header name {:?}", s))?;
+        Ok(match name.as_str() {
+            "x-forwarded-for" => Self::XForwardedFor,
+            "forwarded" => Self::Forwarded,
+            _ => Self::Single(name),
+        })
+    }
+}
+
+impl std::fmt::Display for IpHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::XForwardedFor => write!(f, "x-forwarded-for"),
+            Self::Forwarded => write!(f, "forwarded"),
+            Self::Single(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl serde::Serialize for IpHeader {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl ClientIpConfig {
+    fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        if self.trusted_proxies.is_empty() {
+            return true;
+        }
+        match peer {
+            Some(peer) => self.trusted_proxies.iter().any(|net| net.contains(peer)),
+            None => false,
+        }
+    }
+}
+
 mod rejection {
     use axum::{
         http::StatusCode,
         response::{IntoResponse, Response},
     };
-    use std::convert::Infallible;
 
     pub struct StringRejection(String);
-    pub(crate) type InfallibleRejection = (StatusCode, Infallible);
 
     impl<T: Into<String>> From<T> for StringRejection {
         fn from(val: T) -> Self {
@@ -43,65 +153,27 @@ mod rejection {
 }
 
 mod rudimental {
-    use super::rejection::InfallibleRejection;
     pub use super::rejection::StringRejection;
     use axum::{
         async_trait,
         extract::FromRequestParts,
         http::{request::Parts, HeaderMap},
     };
+    use ipnetwork::IpNetwork;
     use std::net::IpAddr;
 
-    /// Extracts a list of valid IP addresses from `X-Forwarded-For` header
-    #[derive(Debug)]
-    pub struct XForwardedFor(pub Vec<IpAddr>);
-
-    /// Extracts the leftmost IP from `X-Forwarded-For` header
-    #[derive(Debug)]
-    pub struct LeftmostXForwardedFor(pub IpAddr);
-
-    /// Extracts the leftmost IP from `X-Forwarded-For` header
-    #[derive(Debug)]
-    pub struct RightmostXForwardedFor(pub IpAddr);
-
-    /// Extracts a list of valid IP addresses from `Forwarded` header
+    /// Reads the list of valid IP addresses from `X-Forwarded-For` header
     #[derive(Debug)]
-    pub struct Forwarded(pub Vec<IpAddr>);
+    pub struct XForwardedFor;
 
-    /// Extracts the leftmost IP from `Forwarded` header
+    /// Extracts the rightmost IP from `X-Forwarded-For` header that is not one of the
+    /// [`super::ClientIpConfig::trusted_proxies`], the secure way to read a multi-proxy chain
     #[derive(Debug)]
-    pub struct LeftmostForwarded(pub IpAddr);
+    pub struct RightmostUntrustedXForwardedFor(pub IpAddr);
 
-    /// Extracts the rightmost IP from `Forwarded` header
+    /// Reads the list of valid IP addresses from `Forwarded` header
     #[derive(Debug)]
-    pub struct RightmostForwarded(pub IpAddr);
-
-    /// Extracts a valid IP from `X-Real-Ip` (Nginx) header
-    #[derive(Debug)]
-    pub struct XRealIp(pub IpAddr);
-
-    /// Extracts a valid IP from `CF-Connecting-IP` (Cloudflare) header
-    #[derive(Debug)]
-    pub struct CfConnectingIp(pub IpAddr);
-
-    pub(crate) trait SingleIpHeader {
-        const HEADER: &'static str;
-
-        fn maybe_ip_from_headers(headers: &HeaderMap) -> Option<IpAddr> {
-            headers
-                .get(Self::HEADER)
-                .and_then(|hv| hv.to_str().ok())
-                .and_then(|s| s.parse::<IpAddr>().ok())
-        }
-
-        fn ip_from_headers(headers: &HeaderMap) -> Result<IpAddr, StringRejection> {
-            Self::maybe_ip_from_headers(headers).ok_or_else(|| Self::rejection())
-        }
-
-        fn rejection() -> StringRejection {
-            format!("No `{}` header, or the IP is invalid", Self::HEADER).into()
-        }
-    }
+    pub struct Forwarded;
 
     pub(crate) trait MultiIpHeader {
         const HEADER: &'static str;
@@ -126,22 +198,29 @@ mod rudimental {
                 .next()
         }
 
-        fn leftmost_ip(headers: &HeaderMap) -> Result<IpAddr, StringRejection> {
-            Self::maybe_leftmost_ip(headers).ok_or_else(|| Self::rejection())
+        /// strips given amount of (trusted) IPs from the right and takes the next one,
+        /// or the leftmost IP if the list is shorter
+        fn maybe_ip_before_hops(headers: &HeaderMap, hops: usize) -> Option<IpAddr> {
+            let ips = Self::ips_from_headers(headers);
+            if ips.len() > hops {
+                Some(ips[ips.len() - 1 - hops])
+            } else {
+                ips.first().copied()
+            }
         }
 
-        fn maybe_rightmost_ip(headers: &HeaderMap) -> Option<IpAddr> {
-            headers
-                .get_all(Self::HEADER)
-                .iter()
-                .filter_map(|hv| hv.to_str().ok())
-                .flat_map(Self::ips_from_header_value)
+        /// walks the list from the right, skipping the IPs of the trusted networks.
+        /// The leftmost IP is taken if the whole list is trusted
+        fn maybe_rightmost_untrusted_ip(
+            headers: &HeaderMap,
+            trusted: &[IpNetwork],
+        ) -> Option<IpAddr> {
+            let ips = Self::ips_from_headers(headers);
+            ips.iter()
                 .rev()
-                .next()
-        }
-
-        fn rightmost_ip(headers: &HeaderMap) -> Result<IpAddr, StringRejection> {
-            Self::maybe_rightmost_ip(headers).ok_or_else(|| Self::rejection())
+                .find(|ip| !trusted.iter().any(|net| net.contains(**ip)))
+                .or_else(|| ips.first())
+                .copied()
         }
 
         fn rejection() -> StringRejection {
@@ -149,34 +228,6 @@ mod rudimental {
         }
     }
 
-    macro_rules! impl_single_header {
-        ($type:ty, $header:literal) => {
-            impl SingleIpHeader for $type {
-                const HEADER: &'static str = $header;
-            }
-
-            #[async_trait]
-            impl<S> FromRequestParts<S> for $type
-            where
-                S: Sync,
-            {
-                type Rejection = StringRejection;
-
-                async fn from_request_parts(
-                    parts: &mut Parts,
-                    _state: &S,
-                ) -> Result<Self, Self::Rejection> {
-                    Ok(Self(
-                        Self::maybe_ip_from_headers(&parts.headers).ok_or_else(Self::rejection)?,
-                    ))
-                }
-            }
-        };
-    }
-
-    impl_single_header!(XRealIp, "X-Real-Ip");
-    impl_single_header!(CfConnectingIp, "CF-Connecting-IP");
-
     impl MultiIpHeader for XForwardedFor {
         const HEADER: &'static str = "X-Forwarded-For";
 
@@ -194,7 +245,9 @@ mod rudimental {
         fn ips_from_header_value(header_value: &str) -> Vec<IpAddr> {
             use forwarded_header_value::{ForwardedHeaderValue, Identifier};
 
-            let Ok(fv) = ForwardedHeaderValue::from_forwarded(header_value) else {return Vec::new()};
+            let Ok(fv) = ForwardedHeaderValue::from_forwarded(header_value) else {
+                return Vec::new();
+            };
             fv.iter()
                 .filter_map(|fs| fs.forwarded_for.as_ref())
                 .filter_map(|ff| match ff {
@@ -207,22 +260,7 @@ mod rudimental {
     }
 
     #[async_trait]
-    impl<S> FromRequestParts<S> for XForwardedFor
-    where
-        S: Sync,
-    {
-        type Rejection = InfallibleRejection;
-
-        async fn from_request_parts(
-            parts: &mut Parts,
-            _state: &S,
-        ) -> Result<Self, Self::Rejection> {
-            Ok(Self(Self::ips_from_headers(&parts.headers)))
-        }
-    }
-
-    #[async_trait]
-    impl<S> FromRequestParts<S> for LeftmostXForwardedFor
+    impl<S> FromRequestParts<S> for RightmostUntrustedXForwardedFor
     where
         S: Sync,
     {
@@ -232,79 +270,17 @@ mod rudimental {
             parts: &mut Parts,
             _state: &S,
         ) -> Result<Self, Self::Rejection> {
+            let trusted = parts
+                .extensions
+                .get::<super::ClientIpConfig>()
+                .map(|config| config.trusted_proxies.clone())
+                .unwrap_or_default();
             Ok(Self(
-                XForwardedFor::maybe_leftmost_ip(&parts.headers)
+                XForwardedFor::maybe_rightmost_untrusted_ip(&parts.headers, &trusted)
                     .ok_or_else(XForwardedFor::rejection)?,
             ))
         }
     }
-
-    #[async_trait]
-    impl<S> FromRequestParts<S> for RightmostXForwardedFor
-    where
-        S: Sync,
-    {
-        type Rejection = StringRejection;
-
-        async fn from_request_parts(
-            parts: &mut Parts,
-            _state: &S,
-        ) -> Result<Self, Self::Rejection> {
-            Ok(Self(
-                XForwardedFor::maybe_rightmost_ip(&parts.headers)
-                    .ok_or_else(XForwardedFor::rejection)?,
-            ))
-        }
-    }
-
-    #[async_trait]
-    impl<S> FromRequestParts<S> for Forwarded
-    where
-        S: Sync,
-    {
-        type Rejection = InfallibleRejection;
-
-        async fn from_request_parts(
-            parts: &mut Parts,
-            _state: &S,
-        ) -> Result<Self, Self::Rejection> {
-            Ok(Self(Self::ips_from_headers(&parts.headers)))
-        }
-    }
-
-    #[async_trait]
-    impl<S> FromRequestParts<S> for LeftmostForwarded
-    where
-        S: Sync,
-    {
-        type Rejection = StringRejection;
-
-        async fn from_request_parts(
-            parts: &mut Parts,
-            _state: &S,
-        ) -> Result<Self, Self::Rejection> {
-            Ok(Self(
-                Forwarded::maybe_leftmost_ip(&parts.headers).ok_or_else(Forwarded::rejection)?,
-            ))
-        }
-    }
-
-    #[async_trait]
-    impl<S> FromRequestParts<S> for RightmostForwarded
-    where
-        S: Sync,
-    {
-        type Rejection = StringRejection;
-
-        async fn from_request_parts(
-            parts: &mut Parts,
-            _state: &S,
-        ) -> Result<Self, Self::Rejection> {
-            Ok(Self(
-                Forwarded::maybe_rightmost_ip(&parts.headers).ok_or_else(Forwarded::rejection)?,
-            ))
-        }
-    }
 }
 
 #[async_trait]
@@ -315,10 +291,29 @@ where
     type Rejection = (StatusCode, &'static str);
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        CfConnectingIp::maybe_ip_from_headers(&parts.headers)
-            .or_else(|| XForwardedFor::maybe_leftmost_ip(&parts.headers))
-            .or_else(|| XRealIp::maybe_ip_from_headers(&parts.headers))
-            .or_else(|| maybe_connect_info(&parts.extensions))
+        let config = parts
+            .extensions
+            .get::<ClientIpConfig>()
+            .cloned()
+            .unwrap_or_default();
+        let peer = maybe_connect_info(&parts.extensions);
+        if !config.trusts(peer) {
+            return peer.map(Self).ok_or((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Can't extract `UnsecureClientIp`, provide `axum::extract::ConnectInfo`",
+            ));
+        }
+        let defaults;
+        let headers = if config.headers.is_empty() {
+            defaults = IpHeader::defaults();
+            &defaults
+        } else {
+            &config.headers
+        };
+        headers
+            .iter()
+            .find_map(|h| h.maybe_ip(&parts.headers, &config))
+            .or(peer)
             .map(Self)
             .ok_or((
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -333,3 +328,185 @@ fn maybe_connect_info(extensions: &Extensions) -> Option<IpAddr> {
         .get::<ConnectInfo<SocketAddr>>()
         .map(|ConnectInfo(addr)| addr.ip())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    async fn client_ip(xff: &str, config: ClientIpConfig) -> IpAddr {
+        let req = Request::get("/")
+            .header("X-Forwarded-For", xff)
+            .extension(config)
+            .body(())
+            .unwrap();
+        let (mut parts, _) = req.into_parts();
+        ClientIp::from_request_parts(&mut parts, &())
+            .await
+            .unwrap()
+            .0
+    }
+
+    fn hops(n: usize) -> ClientIpConfig {
+        ClientIpConfig {
+            xff_trusted_hops: Some(n),
+            ..Default::default()
+        }
+    }
+
+    async fn client_ip_from(peer: &str, headers: &[(&str, &str)]) -> IpAddr {
+        let config = ClientIpConfig {
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let mut req = Request::get("/");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let peer = SocketAddr::new(peer.parse().unwrap(), 40000);
+        let req = req
+            .extension(config)
+            .extension(ConnectInfo(peer))
+            .body(())
+            .unwrap();
+        let (mut parts, _) = req.into_parts();
+        ClientIp::from_request_parts(&mut parts, &())
+            .await
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn it_takes_leftmost_by_default() {
+        let ip = client_ip("1.1.1.1, 2.2.2.2, 3.3.3.3", ClientIpConfig::default()).await;
+        assert_eq!(ip.to_string(), "1.1.1.1");
+    }
+
+    #[tokio::test]
+    async fn it_strips_trusted_hops() {
+        let chain = "1.1.1.1, 2.2.2.2, 3.3.3.3";
+        assert_eq!(client_ip(chain, hops(0)).await.to_string(), "3.3.3.3");
+        assert_eq!(client_ip(chain, hops(1)).await.to_string(), "2.2.2.2");
+        assert_eq!(client_ip(chain, hops(2)).await.to_string(), "1.1.1.1");
+        // chain is shorter than the number of trusted hops
+        assert_eq!(client_ip(chain, hops(5)).await.to_string(), "1.1.1.1");
+        assert_eq!(client_ip("4.4.4.4", hops(1)).await.to_string(), "4.4.4.4");
+    }
+
+    #[tokio::test]
+    async fn it_honors_headers_from_trusted_proxies_only() {
+        let xff = [("X-Forwarded-For", "1.1.1.1")];
+        assert_eq!(
+            client_ip_from("10.1.2.3", &xff).await.to_string(),
+            "1.1.1.1"
+        );
+        assert_eq!(client_ip_from("fd00::1", &xff).await.to_string(), "1.1.1.1");
+        // no forwarding headers from the trusted proxy
+        assert_eq!(
+            client_ip_from("10.1.2.3", &[]).await.to_string(),
+            "10.1.2.3"
+        );
+    }
+
+    #[tokio::test]
+    async fn it_ignores_spoofed_headers_from_untrusted_peers() {
+        let peer = "6.6.6.6";
+        for header in ["X-Forwarded-For", "X-Real-Ip", "CF-Connecting-IP"] {
+            let ip = client_ip_from(peer, &[(header, "1.1.1.1")]).await;
+            assert_eq!(ip.to_string(), peer, "{} is spoofed", header);
+        }
+    }
+
+    async fn client_ip_with(headers: &[&str], sent: &[(&str, &str)]) -> IpAddr {
+        let config = ClientIpConfig {
+            headers: headers.iter().map(|h| h.parse().unwrap()).collect(),
+            ..Default::default()
+        };
+        let mut req = Request::get("/");
+        for (name, value) in sent {
+            req = req.header(*name, *value);
+        }
+        let peer = SocketAddr::new([10, 0, 0, 1].into(), 40000);
+        let req = req
+            .extension(config)
+            .extension(ConnectInfo(peer))
+            .body(())
+            .unwrap();
+        let (mut parts, _) = req.into_parts();
+        ClientIp::from_request_parts(&mut parts, &())
+            .await
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn it_follows_configured_headers_order() {
+        let sent = [
+            ("CF-Connecting-IP", "1.1.1.1"),
+            ("X-Forwarded-For", "2.2.2.2, 10.0.0.2"),
+            ("True-Client-IP", "3.3.3.3"),
+            ("Forwarded", "for=4.4.4.4"),
+        ];
+        assert_eq!(client_ip_with(&[], &sent).await.to_string(), "1.1.1.1");
+        let order = ["True-Client-IP", "X-Forwarded-For"];
+        assert_eq!(client_ip_with(&order, &sent).await.to_string(), "3.3.3.3");
+        let order = ["X-Forwarded-For", "True-Client-IP"];
+        assert_eq!(client_ip_with(&order, &sent).await.to_string(), "2.2.2.2");
+        let order = ["forwarded"];
+        assert_eq!(client_ip_with(&order, &sent).await.to_string(), "4.4.4.4");
+        // headers out of the list are ignored, the socket peer is the fallback
+        let order = ["True-Client-IP"];
+        assert_eq!(
+            client_ip_with(&order, &sent[..2]).await.to_string(),
+            "10.0.0.1"
+        );
+        assert!("bad header".parse::<IpHeader>().is_err());
+    }
+
+    async fn rightmost_untrusted(xff: &str) -> IpAddr {
+        let config = ClientIpConfig {
+            xff_strategy: XffStrategy::RightmostUntrusted,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let peer = SocketAddr::new([10, 0, 0, 1].into(), 40000);
+        let req = Request::get("/")
+            .header("X-Forwarded-For", xff)
+            .extension(config)
+            .extension(ConnectInfo(peer))
+            .body(())
+            .unwrap();
+        let (mut parts, _) = req.into_parts();
+        let ip = ClientIp::from_request_parts(&mut parts, &())
+            .await
+            .unwrap()
+            .0;
+        let extracted = RightmostUntrustedXForwardedFor::from_request_parts(&mut parts, &())
+            .await
+            .ok()
+            .unwrap();
+        assert_eq!(ip, extracted.0);
+        ip
+    }
+
+    #[tokio::test]
+    async fn it_takes_rightmost_untrusted() {
+        let chain = "6.6.6.6, 1.1.1.1, 10.0.0.2";
+        assert_eq!(rightmost_untrusted(chain).await.to_string(), "1.1.1.1");
+        // untrusted hop between the trusted ones ends the walk
+        let chain = "6.6.6.6, 1.1.1.1, 10.0.0.3, 2.2.2.2, fd00::1, 10.0.0.2";
+        assert_eq!(rightmost_untrusted(chain).await.to_string(), "2.2.2.2");
+        // trusted IP spoofed on the left doesn't hide the client
+        let chain = "10.9.9.9, 1.1.1.1, 10.0.0.2";
+        assert_eq!(rightmost_untrusted(chain).await.to_string(), "1.1.1.1");
+        // the whole chain is trusted
+        let chain = "10.0.0.3, 10.0.0.2";
+        assert_eq!(rightmost_untrusted(chain).await.to_string(), "10.0.0.3");
+    }
+
+    #[tokio::test]
+    async fn it_ignores_spoofed_leftmost_with_hops() {
+        let chain = "6.6.6.6, 1.1.1.1, 10.0.0.1";
+        assert_eq!(client_ip(chain, hops(1)).await.to_string(), "1.1.1.1");
+    }
+}
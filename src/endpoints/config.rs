@@ -0,0 +1,70 @@
+use super::*;
+use crate::cli::ServerOpts;
+use crate::endpoints::auth::Authorized;
+
+#[derive(Serialize)]
+pub struct EffectiveConfig<'a> {
+    storage_backend: &'a str,
+    storage_path: Option<&'a str>,
+    #[serde(flatten)]
+    server: &'a ServerOpts,
+}
+
+/// config
+#[utoipa::path(
+    get,
+    path = "/config",
+    responses(
+        (status = 200, description = "effective runtime settings of the server, secrets are redacted", content_type = "application/json"),
+        (status = 401, description = "secret token is missing or invalid"),
+    ),
+)]
+pub async fn handle<MM>(
+    _: Authorized,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
+) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let state = state.read().unwrap();
+    let config = EffectiveConfig {
+        storage_backend: state.svc.store.name(),
+        storage_path: state.svc.store.local_path(),
+        server: &state.opts,
+    };
+    Json(serde_json::to_value(config).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::SecurityGroupService;
+    use crate::visitor::Visit;
+    use axum::body::HttpBody;
+    use clap::Parser;
+    use std::net::IpAddr;
+
+    struct NoGeo;
+
+    impl IntoVisitor for NoGeo {
+        fn visit(&self, _ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit::no_ip(uri))
+        }
+    }
+
+    #[tokio::test]
+    async fn it_reports_config_without_secrets() {
+        let store = crate::store::FileStore::new("/var/lib/guard");
+        let svc = SecurityGroupService::with_store(Box::new(store));
+        let opts = ServerOpts::parse_from(["server", "--secret-token", "s3cr3t"]);
+        let state = Arc::new(RwLock::new(AppState::new(svc, NoGeo, opts)));
+        let res = handle(Authorized, Extension(state)).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        let config: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(config["storage_backend"], "file");
+        assert_eq!(config["storage_path"], "/var/lib/guard");
+        assert_eq!(config["secret_token"], "<redacted>");
+        assert!(!String::from_utf8_lossy(&body).contains("s3cr3t"));
+    }
+}
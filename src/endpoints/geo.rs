@@ -0,0 +1,136 @@
+use super::*;
+use std::time::{Duration, SystemTime};
+
+/// checks the MaxMind databases every given period and swaps the reader in the shared state
+/// once their files are changed. Failed reload keeps the previous databases in use
+pub fn spawn<MM>(
+    maxmind_path: &str,
+    every: Duration,
+    state: Arc<RwLock<AppState<MM>>>,
+    open: fn(&str) -> anyhow::Result<MM>,
+) where
+    MM: IntoVisitor + Send + Sync + 'static,
+{
+    let path = maxmind_path.to_string();
+    tokio::spawn(async move {
+        let mut loaded = crate::visitor::maxmind_modified(&path);
+        let mut interval = tokio::time::interval(every);
+        interval.tick().await; // the first tick is immediate
+        loop {
+            interval.tick().await;
+            loaded = reload_if_changed(&path, loaded, &state, open).await;
+        }
+    });
+    info!(
+        "checking {} for MaxMind updates every {:?}",
+        maxmind_path, every
+    );
+}
+
+// reopens the databases if they are modified after the given time, returns time of the loaded files
+async fn reload_if_changed<MM>(
+    path: &str,
+    loaded: Option<SystemTime>,
+    state: &Arc<RwLock<AppState<MM>>>,
+    open: fn(&str) -> anyhow::Result<MM>,
+) -> Option<SystemTime>
+where
+    MM: IntoVisitor + Send + Sync + 'static,
+{
+    let modified = crate::visitor::maxmind_modified(path);
+    if modified.is_none() || modified == loaded {
+        return loaded;
+    }
+    // reading the database takes a while, the state is locked only to swap it
+    let owned = path.to_string();
+    match tokio::task::spawn_blocking(move || open(&owned)).await {
+        Ok(Ok(mm)) => {
+            state.write().unwrap().mm = mm;
+            metrics::MAXMIND_RELOADED.set(chrono::Utc::now().timestamp());
+            info!("reloaded MaxMind databases from {}", path);
+            modified
+        }
+        Ok(Err(e)) => {
+            warn!(
+                "cannot reload MaxMind databases, keeping the old ones: {:?}",
+                e
+            );
+            loaded
+        }
+        Err(e) => {
+            warn!("MaxMind reload task failed: {:?}", e);
+            loaded
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::SecurityGroupService;
+    use crate::visitor::Visit;
+    use clap::Parser;
+    use std::net::IpAddr;
+
+    // visitor source that reports the content of the database file as the country
+    struct FakeGeo(String);
+
+    impl IntoVisitor for FakeGeo {
+        fn visit(&self, _ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit {
+                country: Some(self.0.clone()),
+                ..Visit::no_ip(uri)
+            })
+        }
+    }
+
+    fn open(path: &str) -> anyhow::Result<FakeGeo> {
+        let db = std::fs::read_to_string(format!("{}/GeoLite2-City.mmdb", path))?;
+        if db.is_empty() {
+            anyhow::bail!("broken database");
+        }
+        Ok(FakeGeo(db))
+    }
+
+    fn country(state: &Arc<RwLock<AppState<FakeGeo>>>) -> Option<String> {
+        let ip = IpAddr::from([8, 8, 8, 8]);
+        state.read().unwrap().mm.visit(ip, "/").unwrap().country
+    }
+
+    #[tokio::test]
+    async fn it_reloads_changed_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let db = dir.path().join("GeoLite2-City.mmdb");
+        std::fs::write(&db, "US").unwrap();
+        let opts = crate::cli::ServerOpts::parse_from(["server"]);
+        let mm = open(path).unwrap();
+        let svc = SecurityGroupService::default();
+        let state = Arc::new(RwLock::new(AppState::new(svc, mm, opts)));
+        let loaded = crate::visitor::maxmind_modified(path);
+
+        // nothing changed
+        assert_eq!(reload_if_changed(path, loaded, &state, open).await, loaded);
+
+        let set_modified = |secs| {
+            let file = std::fs::File::options().write(true).open(&db).unwrap();
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            file.set_modified(time).unwrap();
+        };
+        std::fs::write(&db, "").unwrap();
+        set_modified(1_000);
+        // broken update keeps the old database
+        assert_eq!(reload_if_changed(path, loaded, &state, open).await, loaded);
+        assert_eq!(country(&state).as_deref(), Some("US"));
+
+        std::fs::write(&db, "CA").unwrap();
+        set_modified(2_000);
+        let reloaded = reload_if_changed(path, loaded, &state, open).await;
+        assert_ne!(reloaded, loaded);
+        assert_eq!(country(&state).as_deref(), Some("CA"));
+        assert_eq!(
+            reload_if_changed(path, reloaded, &state, open).await,
+            reloaded
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use super::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Flag of the server being ready to guard: rules are loaded and the geo database is opened.
+/// Probes read it without locking the state, so they don't wait for the rules reload
+#[derive(Debug, Clone, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn set_ready(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// healthz
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    responses(
+        (status = 200, description = "liveness probe, the server is running", content_type = "text/plain"),
+    ),
+)]
+pub async fn handle_liveness() -> impl IntoResponse {
+    "OK"
+}
+
+/// readyz
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "readiness probe, security groups are loaded and the geo database is opened", content_type = "text/plain"),
+        (status = 503, description = "server is still starting", content_type = "text/plain"),
+    ),
+)]
+pub async fn handle_readiness(Extension(ready): Extension<Readiness>) -> impl IntoResponse {
+    if ready.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
@@ -1,12 +1,107 @@
 use super::*;
+use axum::http::Request;
+use axum::middleware::Next;
 use lazy_static::lazy_static;
-use prometheus::{opts, register_int_gauge};
+use prometheus::{
+    histogram_opts, opts, register_histogram, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec,
+};
 #[allow(unused_imports)]
-use prometheus::{Encoder, Gauge, IntGauge, Opts, Registry, TextEncoder};
+use prometheus::{
+    Encoder, Gauge, Histogram, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
 
 lazy_static! {
     pub static ref UP: IntGauge =
         register_int_gauge!(opts!("up", "Whether the server is running")).unwrap();
+    pub static ref REQUESTS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "guard_requests_total",
+        "Total number of HTTP requests received"
+    ))
+    .unwrap();
+    pub static ref ACTIVE_REQUESTS: IntGauge = register_int_gauge!(opts!(
+        "guard_active_requests",
+        "Number of HTTP requests being processed"
+    ))
+    .unwrap();
+    pub static ref ALERTS_SENT: IntCounter = register_int_counter!(opts!(
+        "guard_alerts_sent_total",
+        "Number of block events posted to the alert webhook"
+    ))
+    .unwrap();
+    pub static ref ALERTS_DROPPED: IntCounter = register_int_counter!(opts!(
+        "guard_alerts_dropped_total",
+        "Number of block events dropped on the alert queue overflow"
+    ))
+    .unwrap();
+    pub static ref RATE_LIMITED: IntCounter = register_int_counter!(opts!(
+        "guard_rate_limited_total",
+        "Number of requests rejected by the rate-limit rules"
+    ))
+    .unwrap();
+    pub static ref REACTIONS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "guard_reactions_total",
+            "Number of guard reactions by security group and HTTP status"
+        ),
+        &["nsg", "status"]
+    )
+    .unwrap();
+    // only the reactions are counted, requests passed by allow rules are not
+    pub static ref BLOCKS_BY_COUNTRY: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "guard_blocks_by_country_total",
+            "Number of non-200 guard reactions by visitor country, ZZ for unknown"
+        ),
+        &["country"]
+    )
+    .unwrap();
+    pub static ref MAXMIND_RELOADED: IntGauge = register_int_gauge!(opts!(
+        "guard_maxmind_reload_timestamp_seconds",
+        "Unix time of the last successful reload of the MaxMind databases"
+    ))
+    .unwrap();
+    pub static ref RATE_LIMIT_BUCKETS: IntGauge = register_int_gauge!(opts!(
+        "guard_rate_limit_buckets",
+        "Number of client IPs tracked by the rate-limit rules"
+    ))
+    .unwrap();
+    pub static ref RULES: IntGaugeVec = register_int_gauge_vec!(
+        opts!("guard_rules", "Number of rules loaded in the security group"),
+        &["nsg"]
+    )
+    .unwrap();
+    // lookups in memory are expected to take microseconds, slow buckets are for the lock contention
+    pub static ref EVAL_SECONDS: Histogram = register_histogram!(histogram_opts!(
+        "guard_eval_seconds",
+        "Time spent on the MaxMind lookup and the rules evaluation per guard request",
+        vec![0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5]
+    ))
+    .unwrap();
+}
+
+// decrements active requests, even if the request future was dropped
+struct ActiveRequest;
+
+impl ActiveRequest {
+    fn start() -> Self {
+        REQUESTS_TOTAL.inc();
+        ACTIVE_REQUESTS.inc();
+        Self
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        ACTIVE_REQUESTS.dec();
+    }
+}
+
+/// middleware to measure the raw load of the server
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> Response {
+    let _active = ActiveRequest::start();
+    next.run(req).await
 }
 
 #[instrument]
@@ -16,6 +111,17 @@ pub fn to_string() -> String {
     // let sr = Registry::new_custom(Some("api".to_string()), Some(labels)).unwrap();
     let sr = Registry::new();
     sr.register(Box::new(UP.clone())).unwrap();
+    sr.register(Box::new(REQUESTS_TOTAL.clone())).unwrap();
+    sr.register(Box::new(ACTIVE_REQUESTS.clone())).unwrap();
+    sr.register(Box::new(ALERTS_SENT.clone())).unwrap();
+    sr.register(Box::new(ALERTS_DROPPED.clone())).unwrap();
+    sr.register(Box::new(REACTIONS_TOTAL.clone())).unwrap();
+    sr.register(Box::new(BLOCKS_BY_COUNTRY.clone())).unwrap();
+    sr.register(Box::new(RATE_LIMITED.clone())).unwrap();
+    sr.register(Box::new(RATE_LIMIT_BUCKETS.clone())).unwrap();
+    sr.register(Box::new(MAXMIND_RELOADED.clone())).unwrap();
+    sr.register(Box::new(EVAL_SECONDS.clone())).unwrap();
+    sr.register(Box::new(RULES.clone())).unwrap();
     UP.set(1i64);
 
     let mut buffer = Vec::<u8>::new();
@@ -23,6 +129,89 @@ pub fn to_string() -> String {
     String::from_utf8(buffer.clone()).unwrap()
 }
 
+// counts the reaction of the guard. Security group name comes from the request path,
+// so names of unknown groups are not used as labels
+pub fn count_reaction(nsg: &str, known: bool, status: u16) {
+    let nsg = if known { nsg } else { "-" };
+    REACTIONS_TOTAL
+        .with_label_values(&[nsg, &status.to_string()])
+        .inc();
+}
+
+// sets the amount of rules for each of the groups. Gauges of the removed groups are dropped,
+// so the labels are limited to the existing group names
+pub fn set_rules_count<'a>(groups: impl Iterator<Item = (&'a str, usize)>) {
+    RULES.reset();
+    for (nsg, count) in groups {
+        RULES.with_label_values(&[nsg]).set(count as i64);
+    }
+}
+
+// counts the non-200 reaction by the visitor country,
+// anything but the ISO country code goes to "ZZ" to keep the amount of labels limited
+pub fn count_block(country: Option<&str>) {
+    let country = match country {
+        Some(c) if c.len() == 2 && c.chars().all(|c| c.is_ascii_uppercase()) => c,
+        _ => "ZZ",
+    };
+    BLOCKS_BY_COUNTRY.with_label_values(&[country]).inc();
+}
+
 pub async fn handle() -> impl IntoResponse {
     metrics::to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn it_tracks_active_requests() {
+        let release = Arc::new(Notify::new());
+        let wait = release.clone();
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(move || async move {
+                    wait.notified().await;
+                    "done"
+                }),
+            )
+            .layer(axum::middleware::from_fn(track));
+
+        let total = REQUESTS_TOTAL.get();
+        let req = Request::get("/slow").body(Body::empty()).unwrap();
+        let pending = tokio::spawn(app.oneshot(req));
+        for _ in 0..100 {
+            if ACTIVE_REQUESTS.get() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(ACTIVE_REQUESTS.get(), 1);
+        assert_eq!(REQUESTS_TOTAL.get(), total + 1);
+
+        release.notify_one();
+        let res = pending.await.unwrap().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(ACTIVE_REQUESTS.get(), 0);
+        assert!(to_string().contains("guard_requests_total"));
+    }
+
+    #[test]
+    fn it_buckets_unknown_countries() {
+        let unknown = BLOCKS_BY_COUNTRY.with_label_values(&["ZZ"]);
+        let before = unknown.get();
+        count_block(None);
+        count_block(Some("Slovakia"));
+        assert!(unknown.get() >= before + 2);
+        let sk = BLOCKS_BY_COUNTRY.with_label_values(&["SK"]);
+        let before = sk.get();
+        count_block(Some("SK"));
+        assert!(sk.get() > before);
+    }
+}
@@ -1,18 +1,46 @@
 use super::prelude::*;
 use crate::endpoints as management;
+use crate::endpoints::config;
+use crate::endpoints::health;
 use crate::endpoints::react;
+use crate::endpoints::status;
 use utoipa::OpenApi;
 
 #[derive(OpenApi)]
 #[openapi(
     paths(
+        management::handle_reload,
+        management::handle_groups_list,
+        management::handle_group_rm,
         management::handle_rules_list,
         management::handle_rules_add,
         management::handle_rules_update,
         management::handle_rules_rm,
+        management::handle_rule_update,
+        management::handle_rule_rm,
+        management::handle_rules_toggle,
+        management::handle_rules_count,
+        management::handle_tags_list,
+        management::handle_tag_rename,
+        management::handle_lookup,
+        management::handle_rule_test,
         react::handle_visitor,
+        config::handle,
+        status::handle,
+        health::handle_liveness,
+        health::handle_readiness,
     ),
-    components(schemas(HttpErrMessage,))
+    components(schemas(
+        HttpErrMessage,
+        management::RulesCount,
+        management::CreatedRules,
+        management::GroupInfo,
+        management::TagInfo,
+        management::ReloadReport,
+        management::GeoLookup,
+        management::RuleTest,
+        status::ServerStatus
+    ))
 )]
 pub struct ApiDoc;
 
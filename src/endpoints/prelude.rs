@@ -3,7 +3,7 @@ pub use axum::extract::*;
 pub use axum::http::StatusCode;
 pub use axum::response::*;
 pub use serde::{Deserialize, Serialize};
-pub use std::sync::{Arc, Mutex};
+pub use std::sync::{Arc, Mutex, RwLock};
 pub use tracing::*;
 pub use utoipa::{IntoParams, ToSchema};
 
@@ -25,6 +25,18 @@ pub fn err400(message: &str) -> impl IntoResponse {
         .into_response()
 }
 
+#[instrument(level = "warn")]
+pub fn err404(message: &str) -> impl IntoResponse {
+    (
+        StatusCode::NOT_FOUND,
+        Json(HttpErrMessage {
+            error: "Not Found".to_string(),
+            message: message.to_string(),
+        }),
+    )
+        .into_response()
+}
+
 #[instrument(level = "warn")]
 pub fn err500(message: &str) -> impl IntoResponse {
     (
@@ -37,6 +49,17 @@ pub fn err500(message: &str) -> impl IntoResponse {
         .into_response()
 }
 
+// maps errors of the service layer: invalid input is the client's fault, the rest is ours
+pub fn err_service(e: &anyhow::Error) -> Response {
+    match e.downcast_ref::<crate::error::GuardError>() {
+        Some(crate::error::GuardError::Parse { .. }) => err400(&e.to_string()).into_response(),
+        Some(crate::error::GuardError::NotFound(_)) => err404(&e.to_string()).into_response(),
+        Some(crate::error::GuardError::MissingStorage(_)) | None => {
+            err500(&e.to_string()).into_response()
+        }
+    }
+}
+
 #[instrument(level = "warn")]
 pub fn not_implemented() -> impl IntoResponse {
     (
@@ -0,0 +1,238 @@
+use super::metrics::{RATE_LIMITED, RATE_LIMIT_BUCKETS};
+use std::collections::BTreeMap as Map;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// minimal delay between the sweeps of the stale buckets
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// tokens available to the client, refilled continuously up to the burst size
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    per_sec: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    // full bucket is the same as no bucket at all
+    fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.per_sec >= self.capacity
+    }
+}
+
+// state of the client bucket, sent in the X-RateLimit-* headers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quota {
+    // size of the bucket, the requests allowed at once
+    pub limit: u32,
+    // requests left in the bucket
+    pub remaining: u32,
+    // seconds until the bucket is full again
+    pub reset: u64,
+}
+
+// bucket of the client for one rate-limit rule of the group,
+// so the rules with different limits don't drain or resize each other's buckets
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BucketKey {
+    nsg: String,
+    rule: String,
+    ip: IpAddr,
+}
+
+impl BucketKey {
+    pub fn new(nsg: &str, rule: &str, ip: IpAddr) -> Self {
+        Self {
+            nsg: nsg.to_string(),
+            rule: rule.to_string(),
+            ip,
+        }
+    }
+}
+
+// token bucket per client IP and rate-limit rule
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Map<BucketKey, Bucket>,
+    swept: Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self {
+            buckets: Map::new(),
+            swept: Instant::now(),
+        }
+    }
+}
+
+impl RateLimiter {
+    // takes a token from the client bucket, returns false if the client is over the limit
+    pub fn allow(&mut self, key: &BucketKey, rpm: u32, burst: u32) -> bool {
+        let allowed = self.allow_at(key, rpm, burst, Instant::now());
+        if !allowed {
+            RATE_LIMITED.inc();
+        }
+        allowed
+    }
+
+    fn allow_at(&mut self, key: &BucketKey, rpm: u32, burst: u32, now: Instant) -> bool {
+        if now.saturating_duration_since(self.swept) >= SWEEP_INTERVAL {
+            self.sweep(now);
+        }
+        let capacity = burst.max(1) as f64;
+        let bucket = self.buckets.entry(key.clone()).or_insert_with(|| Bucket {
+            tokens: capacity,
+            capacity,
+            per_sec: rpm as f64 / 60.0,
+            updated: now,
+        });
+        bucket.refill(now);
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        RATE_LIMIT_BUCKETS.set(self.buckets.len() as i64);
+        allowed
+    }
+
+    // seconds until the client bucket has a token again, rounded up.
+    // None if the client has no bucket or it is never refilled
+    pub fn retry_after(&self, key: &BucketKey) -> Option<u64> {
+        self.retry_after_at(key, Instant::now())
+    }
+
+    fn retry_after_at(&self, key: &BucketKey, now: Instant) -> Option<u64> {
+        let bucket = self.buckets.get(key).filter(|b| b.per_sec > 0.0)?;
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        let missing = (1.0 - bucket.tokens - elapsed * bucket.per_sec).max(0.0);
+        Some((missing / bucket.per_sec).ceil().max(1.0) as u64)
+    }
+
+    // current state of the client bucket.
+    // None if the client has no bucket or it is never refilled
+    pub fn quota(&self, key: &BucketKey) -> Option<Quota> {
+        self.quota_at(key, Instant::now())
+    }
+
+    fn quota_at(&self, key: &BucketKey, now: Instant) -> Option<Quota> {
+        let bucket = self.buckets.get(key).filter(|b| b.per_sec > 0.0)?;
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        let tokens = (bucket.tokens + elapsed * bucket.per_sec).min(bucket.capacity);
+        Some(Quota {
+            limit: bucket.capacity as u32,
+            remaining: tokens.floor() as u32,
+            reset: ((bucket.capacity - tokens) / bucket.per_sec).ceil() as u64,
+        })
+    }
+
+    // drops the buckets that were refilled completely
+    fn sweep(&mut self, now: Instant) {
+        self.buckets.retain(|_, b| !b.is_full_at(now));
+        self.swept = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+
+    fn key(ip: IpAddr) -> BucketKey {
+        BucketKey::new("default", "ratelimit:60:3", ip)
+    }
+
+    #[test]
+    fn test_bucket_allows_burst_and_refills() {
+        let mut limiter = RateLimiter::default();
+        let now = limiter.swept;
+        for _ in 0..3 {
+            assert!(limiter.allow_at(&key(CLIENT), 60, 3, now));
+        }
+        assert!(!limiter.allow_at(&key(CLIENT), 60, 3, now));
+        // other clients have their own buckets
+        assert!(limiter.allow_at(&key(OTHER), 60, 3, now));
+        // one token per second at 60 rpm
+        let later = now + Duration::from_millis(1100);
+        assert!(limiter.allow_at(&key(CLIENT), 60, 3, later));
+        assert!(!limiter.allow_at(&key(CLIENT), 60, 3, later));
+    }
+
+    #[test]
+    fn test_retry_after_refill() {
+        let mut limiter = RateLimiter::default();
+        let now = limiter.swept;
+        assert!(limiter.allow_at(&key(CLIENT), 6, 1, now));
+        assert!(!limiter.allow_at(&key(CLIENT), 6, 1, now));
+        // one token per 10 seconds at 6 rpm
+        assert_eq!(limiter.retry_after_at(&key(CLIENT), now), Some(10));
+        let later = now + Duration::from_millis(7500);
+        assert_eq!(limiter.retry_after_at(&key(CLIENT), later), Some(3));
+        assert_eq!(limiter.retry_after_at(&key(OTHER), now), None);
+        assert!(limiter.allow_at(&key(OTHER), 0, 1, now));
+        assert_eq!(limiter.retry_after_at(&key(OTHER), now), None);
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut limiter = RateLimiter::default();
+        let now = limiter.swept;
+        assert_eq!(limiter.quota_at(&key(CLIENT), now), None);
+        assert!(limiter.allow_at(&key(CLIENT), 6, 3, now));
+        assert!(limiter.allow_at(&key(CLIENT), 6, 3, now));
+        let quota = |at| limiter.quota_at(&key(CLIENT), at).unwrap();
+        // one token per 10 seconds at 6 rpm
+        let expected = Quota {
+            limit: 3,
+            remaining: 1,
+            reset: 20,
+        };
+        assert_eq!(quota(now), expected);
+        let later = now + Duration::from_secs(15);
+        assert_eq!(quota(later).remaining, 2);
+        assert_eq!(quota(later).reset, 5);
+        assert_eq!(quota(now + Duration::from_secs(60)).reset, 0);
+    }
+
+    #[test]
+    fn test_stale_buckets_are_evicted() {
+        let mut limiter = RateLimiter::default();
+        let now = limiter.swept;
+        assert!(limiter.allow_at(&key(CLIENT), 60, 1, now));
+        assert!(limiter.allow_at(&key(OTHER), 1, 100, now));
+        assert_eq!(limiter.buckets.len(), 2);
+        // the client bucket is full again, while the other one still refills
+        let later = now + SWEEP_INTERVAL;
+        assert!(limiter.allow_at(&key(OTHER), 1, 100, later));
+        assert_eq!(limiter.buckets.len(), 1);
+    }
+
+    #[test]
+    fn test_rules_have_own_buckets() {
+        let mut limiter = RateLimiter::default();
+        let now = limiter.swept;
+        let loose = BucketKey::new("web", "ratelimit:600:100", CLIENT);
+        let strict = BucketKey::new("api", "ratelimit:6:1", CLIENT);
+        for _ in 0..50 {
+            assert!(limiter.allow_at(&loose, 600, 100, now));
+        }
+        assert!(limiter.allow_at(&strict, 6, 1, now));
+        assert!(!limiter.allow_at(&strict, 6, 1, now));
+        // the strict rule doesn't resize the loose bucket
+        assert_eq!(limiter.quota_at(&loose, now).unwrap().limit, 100);
+        assert_eq!(limiter.quota_at(&loose, now).unwrap().remaining, 50);
+        assert_eq!(limiter.quota_at(&strict, now).unwrap().limit, 1);
+    }
+}
@@ -1,10 +1,17 @@
 use super::*;
+use crate::cli::{AccessLogFormat, RedirectBalance, ServerOpts};
 use crate::diacritics::*;
+use crate::endpoints::alerts::AlertEvent;
 use crate::endpoints::client_ip::ClientIp;
 use crate::proto::Reaction;
+use crate::state::MatchInfo;
 use crate::visitor::IntoVisitor;
-use axum::http::header::{HeaderMap, HeaderValue};
-use std::net::{IpAddr, Ipv4Addr};
+use axum::http::header::{HeaderMap, HeaderName, HeaderValue};
+use axum::http::response::Builder;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tracing::*;
 
 fn get_traefik_auth_root(headers: &HeaderMap) -> Option<String> {
@@ -18,7 +25,91 @@ fn get_traefik_auth_root(headers: &HeaderMap) -> Option<String> {
     Some(format!("{}://{}", proto, host))
 }
 
-fn get_location_header(to: &str, headers: &HeaderMap) -> HeaderValue {
+fn forwarded_header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|x| x.to_str().ok())
+}
+
+// target of the guard called directly, without the forward-auth headers:
+// the request path after `/guard/{nsg}`, with its query
+fn direct_uri(nsg: &str, uri: &axum::http::Uri) -> String {
+    let prefix = format!("/guard/{}", nsg);
+    let path = match uri.path().strip_prefix(&prefix) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => uri.path(),
+    };
+    match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    }
+}
+
+// the rest of the path is only read from the request URI
+#[derive(Deserialize)]
+pub struct GuardPath {
+    nsg: String,
+}
+
+// URI of the request as resolved for the visitor, with its query,
+// either forwarded or taken from the direct call of the guard
+fn request_uri(visitor: &impl Visitor) -> String {
+    match visitor.query() {
+        Some(query) => format!("{}?{}", visitor.uri(), query),
+        None => visitor.uri(),
+    }
+}
+
+// expands placeholders of the redirect location from the forwarded request:
+// `{host}`, `{host-PREFIX}` (host without the given prefix, e.g. `{host-www.}`) and `{uri}`
+fn expand_location(to: &str, headers: &HeaderMap, visitor: &impl Visitor) -> String {
+    let host = forwarded_header(headers, "x-forwarded-host").unwrap_or("");
+    let uri = &request_uri(visitor);
+    let mut out = String::new();
+    let mut rest = to;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match &rest[start + 1..end] {
+            "host" => out.push_str(host),
+            "uri" => out.push_str(uri),
+            var => match var.strip_prefix("host-") {
+                Some(prefix) => out.push_str(host.strip_prefix(prefix).unwrap_or(host)),
+                None => out.push_str(&rest[start..=end]),
+            },
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// picks one of the comma-separated redirect locations, either in turns or by the client IP
+fn pick_location<'a>(
+    to: &'a str,
+    balance: &RedirectBalance,
+    turn: &AtomicUsize,
+    ip: IpAddr,
+) -> &'a str {
+    let locations: Vec<&str> = to.split(',').map(str::trim).collect();
+    if locations.len() < 2 {
+        return to;
+    }
+    let n = match balance {
+        RedirectBalance::RoundRobin => turn.fetch_add(1, Ordering::Relaxed),
+        RedirectBalance::IpHash => {
+            let mut hasher = DefaultHasher::new();
+            ip.hash(&mut hasher);
+            hasher.finish() as usize
+        }
+    };
+    locations[n % locations.len()]
+}
+
+fn get_location_header(to: &str, headers: &HeaderMap, visitor: &impl Visitor) -> HeaderValue {
+    let to = &expand_location(to, headers, visitor);
     if to.contains("://") {
         // if it is already a full URL, just return it
         return HeaderValue::from_str(to).unwrap();
@@ -31,16 +122,73 @@ fn get_location_header(to: &str, headers: &HeaderMap) -> HeaderValue {
     HeaderValue::from_str(&to).unwrap()
 }
 
-#[instrument(skip(headers), level = "TRACE")]
-pub fn apache_log(code: u16, access_log: &str, headers: &HeaderMap, real_ip: Ipv4Addr) {
+// one line of the access log, including the trailing newline
+fn access_log_line(
+    code: u16,
+    format: &AccessLogFormat,
+    nsg: &str,
+    headers: &HeaderMap,
+    visitor: &impl Visitor,
+    now: chrono::DateTime<chrono::Local>,
+) -> String {
+    let uri = request_uri(visitor);
+    let method = forwarded_header(headers, "x-forwarded-method").unwrap_or("GET");
+    let ua = headers
+        .get("user-agent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("(no agent)");
+
+    match format {
+        AccessLogFormat::Apache => format!(
+            "- - - [{}] \"{} {} HTTP/1.1\" {} 0 \"-\" \"{}\" \"{}\"\n",
+            // Common Log Format time, as expected by the log analyzers
+            now.format("%d/%b/%Y:%H:%M:%S %z"),
+            method,
+            uri,
+            code,
+            ua,
+            visitor.ip()
+        ),
+        AccessLogFormat::Json => {
+            let line = serde_json::json!({
+                "ts": now.to_rfc3339(),
+                "ip": visitor.ip().to_string(),
+                "country": visitor.country(),
+                "city": visitor.city(),
+                "method": method,
+                "uri": uri,
+                "status": code,
+                "nsg": nsg,
+                "user_agent": ua,
+            });
+            format!("{}\n", line)
+        }
+    }
+}
+
+#[instrument(skip_all, level = "TRACE")]
+pub fn write_access_log(
+    code: u16,
+    opts: &ServerOpts,
+    nsg: &str,
+    headers: &HeaderMap,
+    visitor: &impl Visitor,
+) {
     use std::io::prelude::Write;
 
-    if access_log.len() == 0 || code == 200 {
+    if opts.access_log_path.is_empty() || (code == 200 && !opts.access_log_allowed) {
         // skip if not configured or if guard is not reacting
         return;
     }
     let now = chrono::Local::now();
-    let filename = format!("{}/guard.{}.log", access_log, now.format("%Y-%m-%d"));
+    let filename = format!(
+        "{}/guard.{}.log",
+        opts.access_log_path,
+        now.format("%Y-%m-%d")
+    );
+    if let Some(max_bytes) = opts.access_log_max_bytes {
+        super::access_log::roll_if_full(&filename, max_bytes);
+    }
 
     let mut file = match std::fs::OpenOptions::new()
         .create(true)
@@ -54,40 +202,8 @@ pub fn apache_log(code: u16, access_log: &str, headers: &HeaderMap, real_ip: Ipv
         }
     };
 
-    let default_uri_str = "/";
-    let default_uri = HeaderValue::from_static(default_uri_str);
-    let uri = headers
-        .get("x-forwarded-uri")
-        .unwrap_or(&default_uri)
-        .to_str()
-        .unwrap_or(default_uri_str);
-
-    let default_method_str = "GET";
-    let default_method = HeaderValue::from_static(default_method_str);
-    let method = headers
-        .get("x-forwarded-method")
-        .unwrap_or(&default_method)
-        .to_str()
-        .unwrap_or(default_method_str);
-
-    let default_ua_str = "(no agent)";
-    let default_ua = HeaderValue::from_static(default_ua_str);
-    let ua = headers
-        .get("user-agent")
-        .unwrap_or(&default_ua)
-        .to_str()
-        .unwrap_or(default_ua_str);
-
-    let out = format!(
-        "- - - [{}] \"{} {} HTTP/1.1\" {} 0 \"-\" \"{}\" \"{}\"\n",
-        now.to_rfc2822(),
-        method,
-        uri,
-        code,
-        ua,
-        real_ip
-    );
-    match file.write_all(&out.as_bytes()) {
+    let out = access_log_line(code, &opts.access_log_format, nsg, headers, visitor, now);
+    match file.write_all(out.as_bytes()) {
         Ok(_) => {}
         Err(e) => {
             warn!("cannot write to access log file {} {:?}", filename, e);
@@ -95,6 +211,78 @@ pub fn apache_log(code: u16, access_log: &str, headers: &HeaderMap, real_ip: Ipv
     }
 }
 
+// geo location of the visitor is passed down with the reaction,
+// for the allowed ones only with --forward-geo-headers
+fn with_geo_headers(mut builder: Builder, visitor: &impl Visitor) -> Builder {
+    if let Some(country) = visitor.country() {
+        if !country.is_ascii() {
+            warn!("skipping non-ascii country name {:?}", country);
+        } else {
+            match HeaderValue::from_str(&country) {
+                Ok(country) => {
+                    builder = builder.header("x-country-code", country);
+                }
+                Err(e) => {
+                    warn!("cannot parse country code {:?} {:?}", country, e);
+                }
+            }
+        }
+    }
+    if let Some(city) = visitor.city() {
+        let city = remove_diacritics(&city);
+        if !city.is_ascii() {
+            warn!("skipping non-ascii city name {:?}", city);
+        } else {
+            match HeaderValue::from_str(&city) {
+                Ok(city) => {
+                    builder = builder.header("x-city-en-name", city);
+                }
+                Err(e) => {
+                    warn!("cannot parse city name {:?} {:?}", city, e);
+                }
+            }
+        }
+    }
+    if let Some(asn) = visitor.asn() {
+        builder = builder.header("x-asn", asn);
+    }
+    builder
+}
+
+// caching of the guard reaction by CDN: rules that react on the visitor source must not be shared
+fn with_cache_headers(
+    mut builder: Builder,
+    opts: &ServerOpts,
+    matched: Option<&MatchInfo>,
+) -> Builder {
+    let cache_control = match matched {
+        Some(m) => m.cache_control().or_else(|| {
+            if m.per_visitor {
+                Some("private, no-store".to_string())
+            } else {
+                opts.cache_control.clone()
+            }
+        }),
+        None => opts.cache_control.clone(),
+    };
+    for (name, value) in [
+        ("cache-control", cache_control),
+        ("vary", opts.vary.clone()),
+    ] {
+        if let Some(value) = value {
+            match HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    builder = builder.header(name, value);
+                }
+                Err(e) => {
+                    warn!("cannot set {} header {:?} {:?}", name, value, e);
+                }
+            }
+        }
+    }
+    builder
+}
+
 /// guard/{nsg}
 #[utoipa::path(
 get,
@@ -108,98 +296,218 @@ responses(
 )]
 #[instrument(skip(state, headers), level = "trace")]
 pub async fn handle_visitor<MM>(
-    Path(nsg): Path<String>,
-    Extension(state): Extension<Arc<Mutex<AppState<MM>>>>,
+    Path(GuardPath { nsg }): Path<GuardPath>,
+    Extension(state): Extension<Arc<RwLock<AppState<MM>>>>,
     ClientIp(ip): ClientIp,
+    OriginalUri(original_uri): OriginalUri,
     headers: HeaderMap,
 ) -> impl IntoResponse
 where
     MM: IntoVisitor,
 {
-    let default_uri_str = "/";
-    let default_uri = HeaderValue::from_static(default_uri_str);
-    let uri = headers
-        .get("x-forwarded-uri")
-        .unwrap_or(&default_uri)
-        .to_str()
-        .unwrap_or(default_uri_str);
+    let uri = match headers.get("x-forwarded-uri") {
+        Some(uri) => uri.to_str().unwrap_or("/").to_string(),
+        None => direct_uri(&nsg, &original_uri),
+    };
+    let uri = uri.as_str();
+    let ip = crate::visitor::canonical_ip(ip);
     let mut builder = Response::builder().header("x-uri", uri);
-    let ipv4: Ipv4Addr = match ip {
+    let is_local = match ip {
         IpAddr::V4(ip4) => {
-            if ip4.is_loopback() || ip4.is_private() || ip4.is_link_local() || ip4.is_unspecified()
-            {
-                builder = builder.header("x-local-ip", "1");
-            } else {
-                builder = builder.header("x-real-ip", ip.to_string());
-            }
-            ip4
+            ip4.is_loopback() || ip4.is_private() || ip4.is_link_local() || ip4.is_unspecified()
         }
-        _ => {
+        IpAddr::V6(ip6) => {
             builder = builder.header("x-ipv6", "1");
-            Ipv4Addr::new(127, 0, 0, 1)
+            ip6.is_loopback() || ip6.is_unspecified()
         }
     };
+    if is_local {
+        builder = builder.header("x-local-ip", "1");
+    } else {
+        builder = builder.header("x-real-ip", ip.to_string());
+    }
 
-    let state = state.lock().unwrap();
-    let visitor = match state.mm.visit(ipv4, uri) {
+    let state = state.read().unwrap();
+    // observed on drop for the requests that skip the rules
+    let eval_timer = metrics::EVAL_SECONDS.start_timer();
+    let mut visitor = match state.mm.visit(ip, uri) {
         Ok(v) => v,
         Err(_) => {
             builder = builder.header("x-maxmind-ipv4-error", "1");
-            crate::visitor::Visit::no_ip(uri)
+            crate::visitor::Visit {
+                ip,
+                ..crate::visitor::Visit::geo_unknown(uri)
+            }
         }
     };
+    visitor.host = forwarded_header(&headers, "x-forwarded-host").map(str::to_string);
+    visitor.scheme = forwarded_header(&headers, "x-forwarded-proto").map(str::to_string);
+    visitor.user_agent = forwarded_header(&headers, "user-agent").map(str::to_string);
+    let method = forwarded_header(&headers, "x-forwarded-method").unwrap_or("GET");
+    visitor.method = Some(method.to_string());
+    visitor.paths_ignore_case = state.opts.paths_ignore_case;
 
-    match state.svc.react(&nsg, &visitor) {
-        Ok(reaction) => {
-            if let Some(country) = visitor.country() {
-                if !country.is_ascii() {
-                    warn!("skipping non-ascii country name {:?}", country);
-                } else {
-                    match HeaderValue::from_str(&country) {
-                        Ok(country) => {
-                            builder = builder.header("x-country-code", country);
-                        }
-                        Err(e) => {
-                            warn!("cannot parse country code {:?} {:?}", country, e);
+    let enforced = &state.opts.enforce_methods;
+    if !enforced.is_empty() && !enforced.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+        // safe method by the global policy, rules are not evaluated
+        write_access_log(200, &state.opts, &nsg, &headers, &visitor);
+        metrics::count_reaction(&nsg, state.svc.groups.contains_key(&nsg), 200);
+        if state.opts.forward_geo_headers {
+            builder = with_geo_headers(builder, &visitor);
+        }
+        builder = builder.header("x-guard-status", 200);
+        return builder.body(Full::from("")).unwrap().into_response();
+    }
+
+    let explained = state.svc.react_explain(&nsg, &visitor);
+    eval_timer.observe_duration();
+    match explained {
+        Ok((reaction, matched)) => {
+            let mut retry_after = None;
+            let mut quota = None;
+            let mut limited = false;
+            let reaction = match reaction {
+                Reaction::RateLimit { rpm, burst } => {
+                    let rule = matched.as_ref().map_or("", |m| m.rule.as_str());
+                    let key = ratelimit::BucketKey::new(&nsg, rule, ip);
+                    let mut limiter = state.limiter.lock().unwrap();
+                    let allowed = limiter.allow(&key, rpm, burst);
+                    quota = limiter.quota(&key);
+                    match allowed {
+                        true => Reaction::HttpStatus(200),
+                        false => {
+                            retry_after = limiter.retry_after(&key);
+                            limited = true;
+                            Reaction::HttpStatus(state.opts.rate_limit_status)
                         }
                     }
                 }
+                reaction => reaction,
+            };
+            let retry_after = match (reaction.code(), state.opts.retry_after_secs) {
+                (429 | 503, secs) if secs > 0 => retry_after.or(Some(secs)),
+                _ => retry_after,
+            };
+            if reaction.code() != 200 {
+                builder = with_cache_headers(builder, &state.opts, matched.as_ref());
+            }
+            if reaction.code() >= 400 {
+                state.alerts.send(AlertEvent {
+                    nsg: nsg.clone(),
+                    ip: ip.to_string(),
+                    uri: uri.to_string(),
+                    code: reaction.code(),
+                    country: visitor.country(),
+                    tags: matched.as_ref().map(|m| m.tags.clone()).unwrap_or_default(),
+                });
             }
-            if let Some(city) = visitor.city() {
-                let city = remove_diacritics(&city);
-                if !city.is_ascii() {
-                    warn!("skipping non-ascii city name {:?}", city);
-                } else {
-                    match HeaderValue::from_str(&city) {
-                        Ok(city) => {
-                            builder = builder.header("x-city-en-name", city);
+            if state.opts.debug_headers {
+                if let Some(m) = &matched {
+                    if !m.tags.is_empty() {
+                        let tags = m.tags.join(",");
+                        match HeaderValue::from_str(&tags) {
+                            Ok(tags) => {
+                                builder = builder.header("x-guard-tags", tags);
+                            }
+                            Err(e) => {
+                                warn!("cannot pass rule tags {:?} {:?}", tags, e);
+                            }
+                        }
+                    }
+                    match HeaderValue::from_str(&m.rule) {
+                        Ok(rule) => {
+                            builder = builder.header("x-guard-rule", rule);
                         }
                         Err(e) => {
-                            warn!("cannot parse city name {:?} {:?}", city, e);
+                            warn!("cannot pass rule {:?} {:?}", m.rule, e);
                         }
                     }
+                    builder = builder.header("x-guard-lookup", m.lookup.as_str());
                 }
             }
+            if reaction.code() != 200 || state.opts.forward_geo_headers {
+                builder = with_geo_headers(builder, &visitor);
+            }
+            write_access_log(reaction.code(), &state.opts, &nsg, &headers, &visitor);
+            let known = state.svc.groups.contains_key(&nsg);
+            metrics::count_reaction(&nsg, known, reaction.code());
+            if reaction.code() != 200 {
+                metrics::count_block(visitor.country().as_deref());
+            }
+            let balance = &state.opts.redirect_balance;
+            let code = reaction.code();
+            builder = builder.header("x-guard-status", code);
             builder = match reaction {
-                Reaction::PermanentRedirect(to) => {
-                    apache_log(301, &state.access_log, &headers, ipv4);
+                Reaction::PermanentRedirect(to)
+                | Reaction::TemporaryRedirect(to)
+                | Reaction::TemporaryRedirectKeepMethod(to)
+                | Reaction::PermanentRedirectKeepMethod(to) => {
+                    let to = pick_location(&to, balance, &state.redirect_turn, ip);
                     builder
-                        .status(301)
-                        .header("Location", get_location_header(&to, &headers))
+                        .status(code)
+                        .header("Location", get_location_header(to, &headers, &visitor))
                 }
-                Reaction::TemporaryRedirect(to) => {
-                    apache_log(302, &state.access_log, &headers, ipv4);
-
-                    builder
-                        .status(302)
-                        .header("Location", get_location_header(&to, &headers))
+                Reaction::HttpStatus(code) => builder.status(code),
+                Reaction::RateLimit { .. } => {
+                    // resolved by the limiter above
+                    builder.status(429)
                 }
-                Reaction::HttpStatus(code) => {
-                    apache_log(code, &state.access_log, &headers, ipv4);
+                Reaction::HttpStatusWithHeaders(code, extra) => {
+                    for (name, value) in extra {
+                        match (
+                            HeaderName::from_bytes(name.as_bytes()),
+                            HeaderValue::from_str(&value),
+                        ) {
+                            (Ok(name), Ok(value)) => {
+                                builder = builder.header(name, value);
+                            }
+                            _ => {
+                                warn!("cannot set rule header {:?} {:?}", name, value);
+                            }
+                        }
+                    }
                     builder.status(code)
                 }
             };
-            builder.body(Full::from("")).unwrap().into_response()
+            // the header of the rule is kept
+            let has_retry_after = builder
+                .headers_ref()
+                .map(|h| h.contains_key(axum::http::header::RETRY_AFTER))
+                .unwrap_or(true);
+            if let Some(secs) = retry_after.filter(|_| !has_retry_after) {
+                builder = builder.header(axum::http::header::RETRY_AFTER, secs);
+            }
+            if let Some(quota) = quota.filter(|_| state.opts.rate_limit_headers) {
+                builder = builder
+                    .header("x-ratelimit-limit", quota.limit)
+                    .header("x-ratelimit-remaining", quota.remaining)
+                    .header("x-ratelimit-reset", quota.reset);
+            }
+            let always_200 = state.opts.always_200;
+            if always_200 {
+                builder = builder.status(200);
+            }
+            if let Some(body) = state.opts.rate_limit_body.as_ref() {
+                if limited && !always_200 {
+                    return builder
+                        .header("content-type", "text/plain; charset=utf-8")
+                        .body(Full::from(body.clone()))
+                        .unwrap()
+                        .into_response();
+                }
+            }
+            match state
+                .block_body
+                .as_ref()
+                .filter(|_| code >= 400 && !always_200)
+            {
+                Some(body) => builder
+                    .header("content-type", body.content_type)
+                    .body(Full::from(body.render(&visitor)))
+                    .unwrap()
+                    .into_response(),
+                None => builder.body(Full::from("")).unwrap().into_response(),
+            }
         }
         Err(e) => err500(&e.to_string()).into_response(),
     }
@@ -208,7 +516,709 @@ where
 #[cfg(test)]
 mod tests {
 
+    use super::*;
     use crate::diacritics::remove_diacritics;
+    use crate::state::SecurityGroupService;
+    use crate::visitor::Visit;
+    use clap::Parser;
+    use std::net::Ipv4Addr;
+
+    // visitor without any geo database
+    struct NoGeo;
+
+    impl IntoVisitor for NoGeo {
+        fn visit(&self, ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit {
+                ip,
+                ..Visit::no_ip(uri)
+            })
+        }
+    }
+
+    // visitor with failing geo database lookup
+    struct FailingGeo;
+
+    impl IntoVisitor for FailingGeo {
+        fn visit(&self, _ip: IpAddr, _uri: &str) -> anyhow::Result<Visit> {
+            anyhow::bail!("address not found")
+        }
+    }
+
+    // visitor at the given time, for the scheduled rules
+    struct AtTime(chrono::DateTime<chrono::Utc>);
+
+    impl IntoVisitor for AtTime {
+        fn visit(&self, ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit {
+                ip,
+                time: self.0,
+                ..Visit::no_ip(uri)
+            })
+        }
+    }
+
+    // visitor, always located in the same place
+    struct FixedGeo;
+
+    impl IntoVisitor for FixedGeo {
+        fn visit(&self, _ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit {
+                country: Some("SK".to_string()),
+                city: Some("Dunajská Streda".to_string()),
+                asn: Some(6855),
+                ..Visit::no_ip(uri)
+            })
+        }
+    }
+
+    fn app_state(rules: &str, args: &[&str]) -> Arc<RwLock<AppState<NoGeo>>> {
+        app_state_with(NoGeo, rules, args)
+    }
+
+    fn app_state_with<MM: IntoVisitor>(
+        mm: MM,
+        rules: &str,
+        args: &[&str],
+    ) -> Arc<RwLock<AppState<MM>>> {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", rules, false).unwrap();
+        let opts = ServerOpts::parse_from(std::iter::once("server").chain(args.iter().copied()));
+        Arc::new(RwLock::new(AppState::new(svc, mm, opts)))
+    }
+
+    async fn guard<MM: IntoVisitor>(state: Arc<RwLock<AppState<MM>>>, uri: &str) -> Response {
+        guard_url(state, "http", "localhost", uri).await
+    }
+
+    async fn guard_url<MM: IntoVisitor>(
+        state: Arc<RwLock<AppState<MM>>>,
+        proto: &str,
+        host: &str,
+        uri: &str,
+    ) -> Response {
+        guard_with(
+            state,
+            &[("x-forwarded-proto", proto), ("x-forwarded-host", host)],
+            uri,
+        )
+        .await
+    }
+
+    async fn guard_with<MM: IntoVisitor>(
+        state: Arc<RwLock<AppState<MM>>>,
+        extra: &[(&'static str, &str)],
+        uri: &str,
+    ) -> Response {
+        let mut headers = HeaderMap::new();
+        for (name, value) in extra {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers.insert("x-forwarded-uri", HeaderValue::from_str(uri).unwrap());
+        let ip = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+        handle_visitor(
+            Path(GuardPath {
+                nsg: "default".to_string(),
+            }),
+            Extension(state),
+            ClientIp(ip),
+            OriginalUri(axum::http::Uri::from_static("/guard/default")),
+            headers,
+        )
+        .await
+        .into_response()
+    }
+
+    #[tokio::test]
+    async fn it_passes_decided_status() {
+        let state = app_state("403|/admin", &[]);
+        let res = guard(state.clone(), "/admin").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["x-guard-status"], "403");
+        let res = guard(state, "/").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-guard-status"], "200");
+
+        let state = app_state("403|/admin", &["--always-200"]);
+        let res = guard(state, "/admin").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-guard-status"], "403");
+    }
+
+    #[tokio::test]
+    async fn it_echoes_matched_tags() {
+        let state = app_state("403|/admin#blacklist,geo", &["--debug-headers"]);
+        let res = guard(state.clone(), "/admin").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["x-guard-tags"], "blacklist,geo");
+
+        let res = guard(state, "/public").await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("x-guard-tags").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_echoes_matched_rule() {
+        let rules = "403|/admin#blacklist\n401|^/private";
+        let state = app_state(rules, &["--debug-headers"]);
+        let res = guard(state.clone(), "/admin").await;
+        assert_eq!(res.headers()["x-guard-rule"], "403|/admin#blacklist");
+        assert_eq!(res.headers()["x-guard-lookup"], "indexed");
+
+        let res = guard(state.clone(), "/private/a").await;
+        assert_eq!(res.status(), 401);
+        assert_eq!(res.headers()["x-guard-rule"], "401|^/private");
+        assert_eq!(res.headers()["x-guard-lookup"], "scan");
+
+        let res = guard(state, "/public").await;
+        assert!(res.headers().get("x-guard-rule").is_none());
+
+        let res = guard(app_state(rules, &[]), "/admin").await;
+        assert!(res.headers().get("x-guard-rule").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_forwards_geo_headers_on_allow() {
+        let state = app_state_with(FixedGeo, "403|/admin", &["--forward-geo-headers"]);
+        let res = guard(state.clone(), "/").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-country-code"], "SK");
+        assert_eq!(res.headers()["x-city-en-name"], "Dunajska Streda");
+        assert_eq!(res.headers()["x-asn"], "6855");
+
+        // methods outside of the enforced ones are allowed without the rules
+        let args = ["--forward-geo-headers", "--enforce-methods", "POST"];
+        let state = app_state_with(FixedGeo, "403|/admin", &args);
+        let res = guard(state, "/admin").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-asn"], "6855");
+    }
+
+    #[tokio::test]
+    async fn it_keeps_geo_headers_for_blocked_only_by_default() {
+        let state = app_state_with(FixedGeo, "403|/admin", &[]);
+        let res = guard(state.clone(), "/").await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("x-country-code").is_none());
+        assert!(res.headers().get("x-asn").is_none());
+
+        let res = guard(state, "/admin").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["x-country-code"], "SK");
+        assert_eq!(res.headers()["x-city-en-name"], "Dunajska Streda");
+        assert_eq!(res.headers()["x-asn"], "6855");
+    }
+
+    #[tokio::test]
+    async fn it_reacts_on_unknown_geo() {
+        let rules = "403|geo-unknown";
+        let res = guard(app_state_with(FailingGeo, rules, &[]), "/").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["x-maxmind-ipv4-error"], "1");
+
+        let res = guard(app_state_with(FixedGeo, rules, &[]), "/").await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn it_hides_tags_without_debug_headers() {
+        let state = app_state("403|/admin#blacklist,geo", &[]);
+        let res = guard(state, "/admin").await;
+        assert_eq!(res.status(), 403);
+        assert!(res.headers().get("x-guard-tags").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_redirects_www_to_apex() {
+        let state = app_state("301|//www.example.com|https://{host-www.}{uri}", &[]);
+        let res = guard_url(state.clone(), "https", "www.example.com", "/a?b=1").await;
+        assert_eq!(res.status(), 301);
+        assert_eq!(res.headers()["location"], "https://example.com/a?b=1");
+
+        let res = guard_url(state, "https", "example.com", "/a").await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn it_redirects_keeping_method() {
+        let rules = "307|/upload|/v2/upload\n308|^/api/v1|https://api.example.com/v2";
+        let state = app_state(rules, &[]);
+        let res = guard(state.clone(), "/upload").await;
+        assert_eq!(res.status(), 307);
+        assert_eq!(res.headers()["location"], "http://localhost/v2/upload");
+        let res = guard(state, "/api/v1/users").await;
+        assert_eq!(res.status(), 308);
+        assert_eq!(res.headers()["location"], "https://api.example.com/v2");
+    }
+
+    #[tokio::test]
+    async fn it_redirects_http_to_https() {
+        let state = app_state("301|http:|https://{host}{uri}", &[]);
+        let res = guard_url(state.clone(), "http", "example.com", "/login").await;
+        assert_eq!(res.status(), 301);
+        assert_eq!(res.headers()["location"], "https://example.com/login");
+
+        let res = guard_url(state, "https", "example.com", "/login").await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn it_expands_uri_of_direct_requests() {
+        // guard called directly, the URI is the path after /guard/{nsg}
+        let state = app_state("301|/old|https://example.com{uri}", &[]);
+        let res = handle_visitor(
+            Path(GuardPath {
+                nsg: "default".to_string(),
+            }),
+            Extension(state),
+            ClientIp(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))),
+            OriginalUri(axum::http::Uri::from_static("/guard/default/old?a=1")),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), 301);
+        assert_eq!(res.headers()["location"], "https://example.com/old?a=1");
+    }
+
+    #[tokio::test]
+    async fn it_requires_both_host_and_scheme() {
+        let state = app_state("301|http:,//www.example.com|https://example.com{uri}", &[]);
+        let res = guard_url(state.clone(), "http", "www.example.com", "/").await;
+        assert_eq!(res.status(), 301);
+        assert_eq!(res.headers()["location"], "https://example.com/");
+        let res = guard_url(state.clone(), "https", "www.example.com", "/").await;
+        assert_eq!(res.status(), 200);
+        let res = guard_url(state, "http", "example.com", "/").await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn it_sets_cache_headers_on_blocks() {
+        let rules = "403|/admin\n403|/private#cache:no-cache,cache:max-age=0";
+        let args = [
+            "--cache-control",
+            "public, max-age=60",
+            "--vary",
+            "x-forwarded-host",
+        ];
+        let state = app_state(rules, &args);
+        let res = guard(state.clone(), "/admin").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["cache-control"], "public, max-age=60");
+        assert_eq!(res.headers()["vary"], "x-forwarded-host");
+
+        let res = guard(state.clone(), "/private").await;
+        assert_eq!(res.headers()["cache-control"], "no-cache, max-age=0");
+
+        // IP-based block
+        let res = guard(app_state("403|8.8.8.8", &args), "/").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["cache-control"], "private, no-store");
+
+        let res = guard(app_state("403|/admin", &args), "/public").await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("cache-control").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_blocks_by_user_agent() {
+        let state = app_state("403|ua:~(?i)scrapy", &[]);
+        let res = guard_with(state.clone(), &[("user-agent", "Scrapy/2.11")], "/").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = guard_with(state, &[("user-agent", "Mozilla/5.0")], "/").await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_renders_block_body() {
+        use axum::body::HttpBody;
+        let state = app_state("403|/admin\n302|/old|/new", &[]);
+        let template = "<h1>{ip} is blocked</h1>".to_string();
+        let body = crate::endpoints::block_body::BlockBody::new(template, "blocked.html");
+        state.write().unwrap().block_body = Some(body);
+        let res = guard(state.clone(), "/admin").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert_eq!(body, "<h1>8.8.8.8 is blocked</h1>");
+        // redirects and allowed requests stay empty
+        let res = guard(state.clone(), "/old").await;
+        assert!(res.into_body().data().await.is_none());
+        let res = guard(state, "/").await;
+        assert!(res.into_body().data().await.is_none());
+    }
+
+    #[test]
+    fn it_guards_in_parallel() {
+        // every thread keeps reading the state until all of them are in,
+        // and guards in the meantime, which would deadlock with an exclusive lock
+        let state = app_state("403|/admin", &[]);
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (state, barrier) = (state.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    let rt = tokio::runtime::Builder::new_current_thread()
+                        .build()
+                        .unwrap();
+                    let _reading = state.read().unwrap();
+                    barrier.wait();
+                    rt.block_on(guard(state.clone(), "/admin")).status()
+                })
+            })
+            .collect();
+        for t in threads {
+            assert_eq!(t.join().unwrap(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_alerts_on_block() {
+        let (url, mut rx) = crate::endpoints::alerts::tests::mock_webhook().await;
+        let state = app_state("403|/admin#blacklist", &["--alert-webhook", &url]);
+        assert_eq!(guard(state.clone(), "/").await.status(), 200);
+        assert_eq!(guard(state, "/admin").await.status(), 403);
+        let body = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["nsg"], "default");
+        assert_eq!(body[0]["ip"], "8.8.8.8");
+        assert_eq!(body[0]["uri"], "/admin");
+        assert_eq!(body[0]["code"], 403);
+        assert_eq!(body[0]["tags"][0], "blacklist");
+    }
+
+    #[tokio::test]
+    async fn it_blocks_by_method() {
+        let state = app_state("403|POST,/admin", &[]);
+        let post = [("x-forwarded-method", "POST")];
+        assert_eq!(
+            guard_with(state.clone(), &post, "/admin").await.status(),
+            403
+        );
+        assert_eq!(guard(state, "/admin").await.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn it_adds_rule_headers() {
+        let state = app_state("403|/admin|X-Reason:blocked;X-Team:sec", &[]);
+        let res = guard(state, "/admin").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["x-reason"], "blocked");
+        assert_eq!(res.headers()["x-team"], "sec");
+    }
+
+    #[tokio::test]
+    async fn it_enforces_only_mutating_methods() {
+        let state = app_state("403|*", &["--enforce-methods", "POST,PUT,DELETE,PATCH"]);
+        let res = guard_with(state.clone(), &[("x-forwarded-method", "GET")], "/").await;
+        assert_eq!(res.status(), 200);
+        let res = guard_with(state.clone(), &[("x-forwarded-method", "post")], "/").await;
+        assert_eq!(res.status(), 403);
+
+        let res = guard_with(
+            app_state("403|*", &[]),
+            &[("x-forwarded-method", "GET")],
+            "/",
+        )
+        .await;
+        assert_eq!(res.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn it_rotates_redirect_locations() {
+        let state = app_state("302|/old|https://a/,https://b/", &[]);
+        let mut locations = vec![];
+        for _ in 0..3 {
+            let res = guard(state.clone(), "/old").await;
+            assert_eq!(res.status(), 302);
+            locations.push(res.headers()["location"].to_str().unwrap().to_string());
+        }
+        assert_eq!(locations, vec!["https://a/", "https://b/", "https://a/"]);
+    }
+
+    #[tokio::test]
+    async fn it_sticks_redirect_location_to_ip() {
+        let rules = "301|/old|https://a/,https://b/,https://c/";
+        let state = app_state(rules, &["--redirect-balance", "ip-hash"]);
+        let first = guard(state.clone(), "/old").await.headers()["location"].clone();
+        for _ in 0..3 {
+            let res = guard(state.clone(), "/old").await;
+            assert_eq!(res.headers()["location"], first);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_serves_maintenance_except_ops() {
+        use crate::endpoints::block_body::BlockBody;
+        use axum::body::HttpBody;
+        // scheduled 503 with the page and Retry-After to everyone, except the ops network
+        let maintenance = |time: &str, rules: &str| {
+            let state = app_state_with(AtTime(time.parse().unwrap()), rules, &[]);
+            let template = "<h1>back soon, {ip}</h1>".to_string();
+            state.write().unwrap().block_body = Some(BlockBody::new(template, "maintenance.html"));
+            state
+        };
+        let rules = "503|*,@02:00-03:00,-10.0.0.0/8|Retry-After:3600";
+        let res = guard(maintenance("2024-01-01T02:30:00Z", rules), "/").await;
+        assert_eq!(res.status(), 503);
+        let values: Vec<_> = res.headers().get_all("retry-after").iter().collect();
+        assert_eq!(values, ["3600"]);
+        assert_eq!(res.headers()["content-type"], "text/html; charset=utf-8");
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert_eq!(body, "<h1>back soon, 8.8.8.8</h1>");
+
+        let res = guard(maintenance("2024-01-01T04:00:00Z", rules), "/").await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("retry-after").is_none());
+        assert!(res.into_body().data().await.is_none());
+
+        let rules = "503|*,@02:00-03:00,-8.8.8.0/24|Retry-After:3600";
+        let res = guard(maintenance("2024-01-01T02:30:00Z", rules), "/").await;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get("retry-after").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_sends_retry_after() {
+        let res = guard(app_state("503|/maintenance", &[]), "/maintenance").await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers()["retry-after"], "60");
+        let args = ["--retry-after-secs", "300"];
+        let res = guard(app_state("429|/api", &args), "/api").await;
+        assert_eq!(res.headers()["retry-after"], "300");
+        let res = guard(app_state("503|/|Retry-After:120", &args), "/").await;
+        let values: Vec<_> = res.headers().get_all("retry-after").iter().collect();
+        assert_eq!(values, ["120"]);
+        let args = ["--retry-after-secs", "0"];
+        let res = guard(app_state("503|/maintenance", &args), "/maintenance").await;
+        assert!(res.headers().get("retry-after").is_none());
+        let res = guard(app_state("403|/admin", &[]), "/admin").await;
+        assert!(res.headers().get("retry-after").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_configures_rate_limit_response() {
+        use axum::body::HttpBody;
+        let args = [
+            "--rate-limit-status",
+            "503",
+            "--rate-limit-body",
+            "slow down",
+            "--rate-limit-headers",
+        ];
+        let state = app_state("429|*|ratelimit:60:2", &args);
+        let res = guard(state.clone(), "/").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-ratelimit-limit"], "2");
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "1");
+        assert_eq!(res.headers()["x-ratelimit-reset"], "1");
+        let res = guard(state.clone(), "/").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+        let res = guard(state, "/").await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.headers()["x-guard-status"], "503");
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+        assert_eq!(res.headers()["x-ratelimit-reset"], "2");
+        assert_eq!(res.headers()["retry-after"], "1");
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert_eq!(body, "slow down");
+
+        // headers are left out by default, and only the rate-limit rules send them
+        let state = app_state("429|*|ratelimit:60:1", &[]);
+        let res = guard(state.clone(), "/").await;
+        assert!(res.headers().get("x-ratelimit-limit").is_none());
+        let res = guard(state, "/").await;
+        assert_eq!(res.status(), 429);
+        assert!(res.into_body().data().await.is_none());
+        let state = app_state("403|/admin", &["--rate-limit-headers"]);
+        let res = guard(state, "/admin").await;
+        assert!(res.headers().get("x-ratelimit-limit").is_none());
+    }
+
+    #[tokio::test]
+    async fn it_rate_limits_per_ip() {
+        let state = app_state("429|*|ratelimit:60:2", &[]);
+        assert_eq!(guard(state.clone(), "/").await.status(), 200);
+        assert_eq!(guard(state.clone(), "/").await.status(), 200);
+        let res = guard(state.clone(), "/").await;
+        assert_eq!(res.status(), 429);
+        assert_eq!(res.headers()["cache-control"], "private, no-store");
+        assert_eq!(res.headers()["retry-after"], "1");
+        // another client has its own bucket
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-uri", HeaderValue::from_static("/"));
+        let other = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        let res = handle_visitor(
+            Path(GuardPath {
+                nsg: "default".to_string(),
+            }),
+            Extension(state),
+            ClientIp(other),
+            OriginalUri(axum::http::Uri::from_static("/guard/default")),
+            headers,
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), 200);
+    }
+
+    #[test]
+    fn it_formats_access_log_lines() {
+        // the URI is the one resolved for the visitor, not necessarily forwarded
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-method", HeaderValue::from_static("POST"));
+        headers.insert("user-agent", HeaderValue::from_static("curl/8.0"));
+        let visitor = Visit {
+            ip: IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+            country: Some("SK".to_string()),
+            city: Some("Dunajska Streda".to_string()),
+            ..Visit::no_ip("/admin?id=1")
+        };
+        let now = chrono::Local::now();
+
+        let line = access_log_line(
+            403,
+            &AccessLogFormat::Apache,
+            "default",
+            &headers,
+            &visitor,
+            now,
+        );
+        assert!(
+            line.ends_with("\"POST /admin?id=1 HTTP/1.1\" 403 0 \"-\" \"curl/8.0\" \"8.8.8.8\"\n")
+        );
+        let clf = regex::Regex::new(
+            r#"^\S+ \S+ \S+ \[\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4}\] "[A-Z]+ \S+ HTTP/1\.1" \d{3} \d+ "[^"]*" "[^"]*""#,
+        )
+        .unwrap();
+        assert!(clf.is_match(&line), "not a CLF line: {}", line);
+
+        let line = access_log_line(
+            403,
+            &AccessLogFormat::Json,
+            "default",
+            &headers,
+            &visitor,
+            now,
+        );
+        let entry: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(entry["ts"], now.to_rfc3339());
+        assert_eq!(entry["ip"], "8.8.8.8");
+        assert_eq!(entry["country"], "SK");
+        assert_eq!(entry["city"], "Dunajska Streda");
+        assert_eq!(entry["method"], "POST");
+        assert_eq!(entry["uri"], "/admin?id=1");
+        assert_eq!(entry["status"], 403);
+        assert_eq!(entry["nsg"], "default");
+        assert_eq!(entry["user_agent"], "curl/8.0");
+    }
+
+    #[tokio::test]
+    async fn it_logs_allowed_requests_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_dir = dir.path().to_str().unwrap();
+        let read_log = || {
+            let today = chrono::Local::now().format("%Y-%m-%d");
+            let file = dir.path().join(format!("guard.{}.log", today));
+            std::fs::read_to_string(file).unwrap_or_default()
+        };
+
+        let state = app_state("403|/admin", &["--access-log-path", log_dir]);
+        guard(state.clone(), "/").await;
+        assert_eq!(read_log(), "");
+        guard(state, "/admin").await;
+        assert_eq!(read_log().lines().count(), 1);
+
+        let args = ["--access-log-path", log_dir, "--access-log-allowed"];
+        let state = app_state("403|/admin", &args);
+        guard(state, "/").await;
+        let log = read_log();
+        assert_eq!(log.lines().count(), 2);
+        assert!(log
+            .lines()
+            .last()
+            .unwrap()
+            .contains("\"GET / HTTP/1.1\" 200 "));
+    }
+
+    #[tokio::test]
+    async fn it_counts_reactions() {
+        use crate::endpoints::metrics::REACTIONS_TOTAL;
+        let blocked = REACTIONS_TOTAL.with_label_values(&["default", "403"]);
+        let allowed = REACTIONS_TOTAL.with_label_values(&["default", "200"]);
+        let (blocked_before, allowed_before) = (blocked.get(), allowed.get());
+        let state = app_state("403|/admin", &[]);
+        guard(state.clone(), "/admin").await;
+        guard(state.clone(), "/admin").await;
+        guard(state, "/").await;
+        // tests run in parallel, so the counters could only grow more
+        assert!(blocked.get() >= blocked_before + 2);
+        assert!(allowed.get() > allowed_before);
+    }
+
+    #[tokio::test]
+    async fn it_times_evaluation() {
+        use crate::endpoints::metrics::EVAL_SECONDS;
+        let before = EVAL_SECONDS.get_sample_count();
+        let state = app_state("403|/admin", &[]);
+        guard(state.clone(), "/admin").await;
+        guard(state, "/").await;
+        assert!(EVAL_SECONDS.get_sample_count() >= before + 2);
+    }
+
+    #[tokio::test]
+    async fn it_evaluates_direct_requests() {
+        let state = app_state("403|/admin", &[]);
+        let direct = |uri: &'static str| {
+            handle_visitor(
+                Path(GuardPath {
+                    nsg: "default".to_string(),
+                }),
+                Extension(state.clone()),
+                ClientIp(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))),
+                OriginalUri(axum::http::Uri::from_static(uri)),
+                HeaderMap::new(),
+            )
+        };
+        let res = direct("/guard/default/admin?a=1").await.into_response();
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["x-uri"], "/admin?a=1");
+        let res = direct("/guard/default").await.into_response();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["x-uri"], "/");
+        // forwarded URI is preferred
+        let res = guard(state.clone(), "/").await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn it_evaluates_ipv4_mapped_clients_as_ipv4() {
+        let state = app_state("403|8.8.8.8", &[]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-uri", HeaderValue::from_static("/"));
+        let res = handle_visitor(
+            Path(GuardPath {
+                nsg: "default".to_string(),
+            }),
+            Extension(state),
+            ClientIp("::ffff:8.8.8.8".parse().unwrap()),
+            OriginalUri(axum::http::Uri::from_static("/guard/default")),
+            headers,
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.headers()["x-real-ip"], "8.8.8.8");
+        assert!(res.headers().get("x-ipv6").is_none());
+    }
+
     #[test]
     pub fn it_converts() {
         let input = "Dunajská Streda";
@@ -1,52 +1,145 @@
+use crate::cli::ServerOpts;
 use crate::endpoints;
-use crate::visitor::MmKeepInMemory as MR;
+use crate::endpoints::auth::SecretToken;
+use crate::endpoints::client_ip::ClientIpConfig;
+use crate::endpoints::prelude::err404;
+use crate::store::RuleStore;
+use crate::visitor::{IntoVisitor, MmKeepInMemory as MR};
 use anyhow::Context;
 use axum::{
-    extract::{DefaultBodyLimit, Extension},
+    extract::{DefaultBodyLimit, Extension, Path, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
     routing::*,
     Router, Server,
 };
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use tower_http::cors::{Any, CorsLayer};
+use std::sync::{Arc, RwLock};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, Any, CorsLayer};
 use tower_http::limit::*;
+use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::*;
 use tracing::*;
 
 #[allow(unused_imports)]
 use axum::ServiceExt;
 
-pub async fn run(
-    socket_addr: SocketAddr,
-    _secret_token: &str,
-    maxmind_path: &str,
-    storage_path: &str,
-    access_log_path: &str,
-) -> anyhow::Result<()> {
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-
-    let svc = crate::state::SecurityGroupService::from_local_path(storage_path)
-        .context("security group load")?;
-    let shared_state = Arc::new(Mutex::new(endpoints::AppState {
-        svc,
-        mm: MR::new(maxmind_path)?,
-        access_log: access_log_path.to_string(),
-    }));
-    let app = Router::new()
-        .route("/openapi.json", get(endpoints::openapi::handle))
-        .route("/metrics", get(endpoints::metrics::handle))
-        .route("/nsg/:nsg/rules", get(endpoints::handle_rules_list::<MR>))
-        .route("/nsg/:nsg/rules", post(endpoints::handle_rules_add::<MR>))
-        .route("/nsg/:nsg/rules", put(endpoints::handle_rules_update::<MR>))
-        .route("/nsg/:nsg/rules", delete(endpoints::handle_rules_rm::<MR>))
-        .route("/guard/:nsg", get(endpoints::react::handle_visitor::<MR>))
+// when the server is started for one group, other groups are not found
+async fn only_group<B>(
+    State(only): State<Option<String>>,
+    params: Option<Path<HashMap<String, String>>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let nsg = params.as_ref().and_then(|Path(p)| p.get("nsg"));
+    match (only, nsg) {
+        (Some(only), Some(nsg)) if *nsg != only => {
+            err404(&format!("security group {} is not served", nsg)).into_response()
+        }
+        _ => next.run(req).await,
+    }
+}
+
+// builds CORS layer from the options, any origin is allowed if no origins are given.
+// Credentials are invalid with any origin, browsers would reject such responses
+pub fn cors_layer(opts: &ServerOpts) -> anyhow::Result<CorsLayer> {
+    if opts.cors_origin.is_empty() || opts.cors_origin.iter().any(|o| o == "*") {
+        if opts.cors_allow_credentials {
+            anyhow::bail!("CORS credentials cannot be allowed for any origin");
+        }
+        return Ok(CorsLayer::new()
+            .allow_origin(Any)
+            .allow_methods(Any)
+            .allow_headers(Any));
+    }
+    let origins = opts
+        .cors_origin
+        .iter()
+        .map(|o| {
+            o.parse()
+                .with_context(|| format!("invalid CORS origin {}", o))
+        })
+        .collect::<anyhow::Result<Vec<axum::http::HeaderValue>>>()?;
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request())
+        .allow_credentials(opts.cors_allow_credentials))
+}
+
+// forward-auth requests have no body, anything larger is not a guard request
+const GUARD_MAX_BODY_BYTES: usize = 1024;
+
+// limits the time of handling a request, answering 408 when it expires
+fn timeout_layer(opts: &ServerOpts) -> TimeoutLayer {
+    TimeoutLayer::new(std::time::Duration::from_millis(opts.request_timeout_ms))
+}
+
+// builds all routes of the HTTP server
+pub fn router<MM>(opts: &ServerOpts, shared_state: Arc<RwLock<endpoints::AppState<MM>>>) -> Router
+where
+    MM: IntoVisitor + Send + Sync + 'static,
+{
+    let cors = cors_layer(opts).expect("CORS options are checked at startup");
+    let ready = shared_state.read().unwrap().ready.clone();
+    Router::new()
+        .route(&opts.openapi_path, get(endpoints::openapi::handle))
+        .route(&opts.metrics_path, get(endpoints::metrics::handle))
+        .route("/config", get(endpoints::config::handle::<MM>))
+        .route("/status", get(endpoints::status::handle::<MM>))
+        .route("/reload", post(endpoints::handle_reload::<MM>))
+        .route("/nsg", get(endpoints::handle_groups_list::<MM>))
+        .route("/nsg/:nsg", delete(endpoints::handle_group_rm::<MM>))
+        .route("/nsg/:nsg/rules", get(endpoints::handle_rules_list::<MM>))
+        .route("/nsg/:nsg/rules", post(endpoints::handle_rules_add::<MM>))
+        .route("/nsg/:nsg/rules", put(endpoints::handle_rules_update::<MM>))
+        .route("/nsg/:nsg/rules", delete(endpoints::handle_rules_rm::<MM>))
+        .route(
+            "/nsg/:nsg/rules/:index",
+            put(endpoints::handle_rule_update::<MM>).delete(endpoints::handle_rule_rm::<MM>),
+        )
+        .route(
+            "/nsg/:nsg/rules/:index/toggle",
+            post(endpoints::handle_rules_toggle::<MM>),
+        )
+        .route("/nsg/:nsg/count", get(endpoints::handle_rules_count::<MM>))
+        .route("/nsg/:nsg/tags", get(endpoints::handle_tags_list::<MM>))
+        .route("/nsg/:nsg/test", post(endpoints::handle_rule_test::<MM>))
+        .route(
+            "/nsg/:nsg/tags/:tag/rename",
+            post(endpoints::handle_tag_rename::<MM>),
+        )
+        .route("/lookup/:ip", get(endpoints::handle_lookup::<MM>))
+        .route(
+            "/guard/:nsg",
+            get(endpoints::react::handle_visitor::<MM>)
+                .layer(RequestBodyLimitLayer::new(GUARD_MAX_BODY_BYTES)),
+        )
+        // direct calls without forward-auth, the rest of the path is the target
+        .route(
+            "/guard/:nsg/*path",
+            get(endpoints::react::handle_visitor::<MM>)
+                .layer(RequestBodyLimitLayer::new(GUARD_MAX_BODY_BYTES)),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            opts.nsg.clone(),
+            only_group,
+        ))
         .layer(cors)
+        .layer(timeout_layer(opts))
         .layer(DefaultBodyLimit::disable())
-        .layer(RequestBodyLimitLayer::new(100 * 1024 * 1024)) // reason for 429
+        .layer(RequestBodyLimitLayer::new(opts.max_body_bytes))
         .layer(Extension(shared_state))
+        .layer(Extension(SecretToken(opts.secret_token.clone())))
+        .layer(Extension(ClientIpConfig {
+            xff_trusted_hops: opts.xff_trusted_hops,
+            xff_strategy: opts.xff_strategy,
+            trusted_proxies: opts.trusted_proxies.clone(),
+            headers: opts.client_ip_headers.clone(),
+        }))
+        .layer(axum::middleware::from_fn(endpoints::metrics::track))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(
@@ -61,11 +154,606 @@ pub async fn run(
                         .include_headers(true),
                 ),
         )
-        .route("/", get(|| async { "# Traefik Guard API, v1" }));
+        .route("/", get(|| async { "# Traefik Guard API, v1" }))
+        // probes are kept out of the tracing and metrics layers, they are polled too often
+        .route("/healthz", get(endpoints::health::handle_liveness))
+        .route(
+            "/readyz",
+            get(endpoints::health::handle_readiness).layer(Extension(ready)),
+        )
+}
+
+// resolves on SIGINT or SIGTERM, returning the name of the signal
+async fn shutdown_signal() -> &'static str {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
+    tokio::select! {
+        _ = ctrl_c => "SIGINT",
+        _ = terminate => "SIGTERM",
+    }
+}
+
+pub async fn run(opts: &ServerOpts, store: Box<dyn RuleStore>) -> anyhow::Result<()> {
+    let socket_addr: SocketAddr = opts.listen.parse().expect("invalid network port bind");
+    // invalid CORS options fail the startup, not the router
+    let _ = cors_layer(opts).context("CORS options")?;
+    // only the rules files on the local disk can be watched
+    let watched_path = match (opts.watch, store.local_path()) {
+        (true, Some(path)) => Some(path.to_string()),
+        (true, None) => anyhow::bail!("--watch requires the file storage backend"),
+        (false, _) => None,
+    };
+    let mut svc =
+        crate::state::SecurityGroupService::from_store(store).context("security group load")?;
+    svc.set_reaction_cache(opts.reaction_cache_size, opts.reaction_cache_shards);
+    if let Some(rules) = &opts.rules_inline {
+        let nsg = opts.nsg.as_deref().unwrap_or("default");
+        let count = svc.seed_group(nsg, rules);
+        info!("group {}: {} inline rules", nsg, count);
+    }
+    let mm = match opts.require_maxmind {
+        true => MR::new(&opts.maxmind_path)?,
+        false => MR::new_or_no_geo(&opts.maxmind_path),
+    };
+    let mut state = endpoints::AppState::new(svc, mm, opts.clone());
+    if let Some(path) = &opts.block_body_file {
+        state.block_body = Some(endpoints::block_body::BlockBody::load(path)?);
+    }
+    let shared_state = Arc::new(RwLock::new(state));
+    // watcher is kept until the server stops
+    let _watcher = match &watched_path {
+        Some(path) => Some(endpoints::watch::spawn(path, shared_state.clone())?),
+        None => None,
+    };
+    if let Some(days) = opts.access_log_retention_days {
+        if !opts.access_log_path.is_empty() {
+            endpoints::access_log::spawn_retention(&opts.access_log_path, days);
+        }
+    }
+    if let Some(secs) = opts.maxmind_reload_secs.filter(|secs| *secs > 0) {
+        let every = std::time::Duration::from_secs(secs);
+        endpoints::geo::spawn(&opts.maxmind_path, every, shared_state.clone(), MR::new);
+    }
+    let app = router(opts, shared_state.clone());
+    let listener = std::net::TcpListener::bind(socket_addr)
+        .with_context(|| format!("bind {}", socket_addr))?;
     info!("Listening on {}", socket_addr);
-    Server::bind(&socket_addr)
+    serve(listener, app, shared_state, shutdown_signal()).await
+}
+
+// serves the app on the bound listener until the shutdown future resolves with the reason,
+// then the rules changed through the API are saved.
+// The server is ready from here: the rules and the geo database are loaded before
+async fn serve<MM>(
+    listener: std::net::TcpListener,
+    app: Router,
+    state: Arc<RwLock<endpoints::AppState<MM>>>,
+    shutdown: impl std::future::Future<Output = &'static str>,
+) -> anyhow::Result<()>
+where
+    MM: IntoVisitor + Send + Sync + 'static,
+{
+    let server = Server::from_tcp(listener)?;
+    state.read().unwrap().ready.set_ready();
+    server
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async {
+            let signal = shutdown.await;
+            info!("{} received, shutting down", signal);
+        })
         .await?;
+    // in-flight requests are completed, flushing the changed rules before exit
+    state.write().unwrap().svc.save();
+    info!("rules saved, bye");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::SecurityGroupService;
+    use crate::visitor::Visit;
+    use axum::body::{Body, HttpBody};
+    use axum::http::{Request, StatusCode};
+    use clap::Parser;
+    use std::net::IpAddr;
+    use tower::ServiceExt;
+
+    struct NoGeo;
+
+    impl IntoVisitor for NoGeo {
+        fn visit(&self, _ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit::no_ip(uri))
+        }
+    }
+
+    fn app(args: &[&str]) -> Router {
+        let opts = ServerOpts::parse_from(std::iter::once("server").chain(args.iter().copied()));
+        let state = endpoints::AppState::new(SecurityGroupService::default(), NoGeo, opts.clone());
+        router(&opts, Arc::new(RwLock::new(state)))
+    }
+
+    async fn status(app: &Router, uri: &str) -> StatusCode {
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    async fn post(app: &Router, uri: &str, bearer: Option<&str>) -> StatusCode {
+        let mut req = Request::post(uri);
+        if let Some(token) = bearer {
+            req = req.header("authorization", format!("Bearer {}", token));
+        }
+        let req = req.body(Body::from("403|US")).unwrap();
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn it_relocates_metrics() {
+        let app = app(&["--metrics-path", "/guard-metrics"]);
+        assert_eq!(status(&app, "/guard-metrics").await, StatusCode::OK);
+        assert_eq!(status(&app, "/metrics").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&app, "/openapi.json").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_requires_token_to_manage_rules() {
+        let app = app(&["--secret-token", "s3cr3t"]);
+        let rules = "/nsg/default/rules";
+        assert_eq!(post(&app, rules, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            post(&app, rules, Some("wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(post(&app, rules, Some("s3cr3t")).await, StatusCode::OK);
+        let with_query = "/nsg/default/rules?token=s3cr3t";
+        assert_eq!(post(&app, with_query, None).await, StatusCode::OK);
+        let toggle = "/nsg/default/rules/0/toggle";
+        assert_eq!(post(&app, toggle, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(post(&app, "/reload", None).await, StatusCode::UNAUTHORIZED);
+        let rename = "/nsg/default/tags/a/rename?to=b";
+        assert_eq!(post(&app, rename, None).await, StatusCode::UNAUTHORIZED);
+        // reading the rules and guarding stay open
+        assert_eq!(status(&app, rules).await, StatusCode::OK);
+        let req = Request::get("/guard/default")
+            .header("x-forwarded-for", "8.8.8.8")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(req).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_rules_open_without_token() {
+        let app = app(&[]);
+        assert_eq!(post(&app, "/nsg/default/rules", None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_lists_groups() {
+        let app = app(&[]);
+        assert_eq!(post(&app, "/nsg/web/rules", None).await, StatusCode::OK);
+        assert_eq!(post(&app, "/nsg/api/rules", None).await, StatusCode::OK);
+        assert_eq!(post(&app, "/nsg/api/rules", None).await, StatusCode::OK);
+
+        let req = Request::get("/nsg").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert_eq!(body, "api\nweb\n");
+
+        let req = Request::get("/nsg?json=true").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let body = res.into_body().data().await.unwrap().unwrap();
+        let groups: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            groups,
+            serde_json::json!([{"name": "api", "rules": 2}, {"name": "web", "rules": 1}])
+        );
+    }
+
+    #[tokio::test]
+    async fn it_lists_rules_as_json() {
+        let app = app(&[]);
+        assert_eq!(post(&app, "/nsg/default/rules", None).await, StatusCode::OK);
+        let expected = vec![crate::proto::Rule::parse("403|US").unwrap()];
+        for req in [
+            Request::get("/nsg/default/rules?format=json"),
+            Request::get("/nsg/default/rules").header("accept", "application/json"),
+        ] {
+            let res = app.clone().oneshot(req.body(Body::empty()).unwrap()).await;
+            let body = res.unwrap().into_body().data().await.unwrap().unwrap();
+            let rules: Vec<crate::proto::Rule> = serde_json::from_slice(&body).unwrap();
+            assert_eq!(rules, expected);
+        }
+        let req = Request::get("/nsg/default/rules").header("accept", "text/plain");
+        let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.into_body().data().await.unwrap().unwrap(), "403|US\n");
+    }
+
+    #[tokio::test]
+    async fn it_guards_direct_requests() {
+        let app = app(&[]);
+        let req = Request::post("/nsg/default/rules")
+            .body(Body::from("403|/admin"))
+            .unwrap();
+        assert_eq!(
+            app.clone().oneshot(req).await.unwrap().status(),
+            StatusCode::OK
+        );
+        for (uri, expected) in [
+            ("/guard/default/admin", StatusCode::FORBIDDEN),
+            ("/guard/default/public", StatusCode::OK),
+        ] {
+            let req = Request::get(uri)
+                .header("x-forwarded-for", "8.8.8.8")
+                .body(Body::empty())
+                .unwrap();
+            assert_eq!(app.clone().oneshot(req).await.unwrap().status(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_limits_request_body() {
+        let limited = app(&["--max-body-bytes", "10"]);
+        let req = Request::post("/nsg/default/rules")
+            .body(Body::from("403|US\n403|CN\n"))
+            .unwrap();
+        let res = limited.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            post(&limited, "/nsg/default/rules", None).await,
+            StatusCode::OK
+        );
+
+        let app = app(&[]);
+        let req = Request::get("/guard/default")
+            .header("x-forwarded-for", "8.8.8.8")
+            .header("content-length", "2048")
+            .body(Body::from(vec![b' '; 2048]))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn it_deletes_group() {
+        let app = app(&[]);
+        assert_eq!(post(&app, "/nsg/web/rules", None).await, StatusCode::OK);
+        let req = Request::delete("/nsg/web").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let req = Request::delete("/nsg/web").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_unknown_groups() {
+        let app = app(&[]);
+        let rules = "/nsg/typo/rules";
+        assert_eq!(status(&app, rules).await, StatusCode::NOT_FOUND);
+        let req = Request::put(rules).body(Body::from("403|US")).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let req = Request::delete(rules).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        // guarding stays open for the groups that are not created yet
+        let req = Request::get("/guard/typo")
+            .header("x-forwarded-for", "8.8.8.8")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(status(&app, "/nsg/typo/tags").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&app, "/nsg/typo/count").await, StatusCode::NOT_FOUND);
+        let rename = "/nsg/typo/tags/temp/rename?to=temporary";
+        assert_eq!(post(&app, rename, None).await, StatusCode::NOT_FOUND);
+        assert_eq!(post(&app, rules, None).await, StatusCode::OK);
+        assert_eq!(status(&app, rules).await, StatusCode::OK);
+        assert_eq!(status(&app, "/nsg/typo/tags").await, StatusCode::OK);
+        assert_eq!(status(&app, "/nsg/typo/count").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_manages_rule_by_index() {
+        let app = app(&[]);
+        let req = Request::post("/nsg/web/rules")
+            .body(Body::from("403|US\n403|CN"))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert_eq!(body, r#"{"indexes":[0,1],"skipped":0}"#);
+        let req = Request::put("/nsg/web/rules/1")
+            .body(Body::from("401|CN"))
+            .unwrap();
+        assert_eq!(
+            app.clone().oneshot(req).await.unwrap().status(),
+            StatusCode::OK
+        );
+        let req = Request::put("/nsg/web/rules/5")
+            .body(Body::from("401|CN"))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let req = Request::delete("/nsg/web/rules/0")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            app.clone().oneshot(req).await.unwrap().status(),
+            StatusCode::OK
+        );
+        let req = Request::delete("/nsg/web/rules/1")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(
+            post(&app, "/nsg/web/rules/5/toggle", None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            post(&app, "/nsg/typo/rules/0/toggle", None).await,
+            StatusCode::NOT_FOUND
+        );
+
+        let req = Request::get("/nsg/web/rules").body(Body::empty()).unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        let body = res.into_body().data().await.unwrap().unwrap();
+        assert_eq!(body, "401|CN\n");
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_rules() {
+        let app = app(&[]);
+        let req = Request::post("/nsg/web/rules")
+            .body(Body::from("403|US\nforbidden|US"))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        let err: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(err["message"].as_str().unwrap().contains("forbidden|US"));
+        // nothing is added from the rejected request
+        let req = Request::get("/nsg").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert!(res.into_body().data().await.is_none());
+
+        assert_eq!(post(&app, "/nsg/web/rules", None).await, StatusCode::OK);
+        let req = Request::put("/nsg/web/rules")
+            .body(Body::from("forbidden|US"))
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn it_looks_up_ip() {
+        let app = app(&[]);
+        let req = Request::get("/lookup/::ffff:8.8.8.8")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        let geo: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(geo["ip"], "8.8.8.8");
+        assert_eq!(geo["country"], serde_json::Value::Null);
+        assert_eq!(geo["geo_unknown"], false);
+        assert_eq!(
+            status(&app, "/lookup/not-an-ip").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn it_tests_rules_without_saving() {
+        let app = app(&[]);
+        let test = |uri: &str, rule: &str| {
+            let req = Request::post(uri)
+                .body(Body::from(rule.to_string()))
+                .unwrap();
+            app.clone().oneshot(req)
+        };
+        let res = test("/nsg/default/test?ip=8.8.8.8&uri=/admin", "401|/admin\n")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(out["rule"], "401|/admin");
+        assert_eq!(out["matched"], true);
+        assert_eq!(out["status"], 401);
+
+        let res = test("/nsg/default/test?ip=8.8.8.8", "301|/admin|/login")
+            .await
+            .unwrap();
+        let body = res.into_body().data().await.unwrap().unwrap();
+        let out: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(out["uri"], "/");
+        assert_eq!(out["matched"], false);
+        assert_eq!(out["status"], serde_json::Value::Null);
+
+        let res = test("/nsg/default/test?ip=8.8.8.8", "403|~[")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = test("/nsg/default/test?ip=nope", "403|US").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            status(&app, "/nsg/default/rules").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn it_keeps_inline_rules_on_reload() {
+        let opts = ServerOpts::parse_from(["server", "--rules-inline", "403|/admin"]);
+        let mut svc = SecurityGroupService::default();
+        svc.seed_group("default", opts.rules_inline.as_deref().unwrap());
+        let state = endpoints::AppState::new(svc, NoGeo, opts.clone());
+        let app = router(&opts, Arc::new(RwLock::new(state)));
+        let req = Request::post("/reload").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let req = Request::get("/guard/default/admin")
+            .header("x-forwarded-for", "8.8.8.8")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            app.oneshot(req).await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn it_keeps_rules_files_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = crate::store::FileStore::new(dir.path().to_str().unwrap());
+        // written back, the file would lose the trailing space
+        let file = dir.path().join("default.rules.txt");
+        std::fs::write(&file, "403|US \n").unwrap();
+        let mut svc = SecurityGroupService::from_store(Box::new(store)).unwrap();
+        svc.seed_group("default", "403|CN");
+        let opts = ServerOpts::parse_from(["server"]);
+        let state = endpoints::AppState::new(svc, NoGeo, opts.clone());
+        let state = Arc::new(RwLock::new(state));
+        let app = router(&opts, state.clone());
+        assert_eq!(post(&app, "/nsg/default/rules", None).await, StatusCode::OK);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        serve(listener, app, state.clone(), async { "SIGTERM" })
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "403|US \n");
+        assert_eq!(state.read().unwrap().svc.count_rules("default").total, 2);
+    }
+
+    #[tokio::test]
+    async fn it_answers_probes() {
+        let opts = ServerOpts::parse_from(["server", "--secret-token", "s3cr3t"]);
+        let state = endpoints::AppState::new(SecurityGroupService::default(), NoGeo, opts.clone());
+        let state = Arc::new(RwLock::new(state));
+        let app = router(&opts, state.clone());
+        assert_eq!(status(&app, "/healthz").await, StatusCode::OK);
+        assert_eq!(
+            status(&app, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        state.read().unwrap().ready.set_ready();
+        assert_eq!(status(&app, "/readyz").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_gets_ready_once_serving() {
+        let opts = ServerOpts::parse_from(["server"]);
+        let state = endpoints::AppState::new(SecurityGroupService::default(), NoGeo, opts.clone());
+        let state = Arc::new(RwLock::new(state));
+        let app = router(&opts, state.clone());
+        assert_eq!(
+            status(&app, "/readyz").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = tokio::spawn(serve(listener, app.clone(), state, async {
+            let _ = stopped.await;
+            "SIGTERM"
+        }));
+        let ready = async {
+            while status(&app, "/readyz").await != StatusCode::OK {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), ready)
+            .await
+            .expect("ready while serving");
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_serves_only_selected_group() {
+        let opts = ServerOpts {
+            nsg: Some("web".to_string()),
+            ..ServerOpts::parse_from(["server"])
+        };
+        let state = endpoints::AppState::new(SecurityGroupService::default(), NoGeo, opts.clone());
+        let app = router(&opts, Arc::new(RwLock::new(state)));
+        assert_eq!(post(&app, "/nsg/web/rules", None).await, StatusCode::OK);
+        assert_eq!(
+            post(&app, "/nsg/api/rules", None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(&app, "/nsg/api/count").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&app, "/guard/api").await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&app, "/nsg").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_times_out_slow_requests() {
+        let opts = ServerOpts::parse_from(["server", "--request-timeout-ms", "50"]);
+        let slow = || async {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            "OK"
+        };
+        let app = Router::new()
+            .route("/slow", get(slow))
+            .route("/fast", get(|| async { "OK" }))
+            .layer(timeout_layer(&opts));
+        assert_eq!(status(&app, "/slow").await, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(status(&app, "/fast").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_restricts_cors_origins() {
+        let app = app(&["--cors-origin", "https://admin.example.com"]);
+        let preflight = |origin: &str| {
+            Request::options("/nsg")
+                .header("origin", origin)
+                .header("access-control-request-method", "GET")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let req = preflight("https://admin.example.com");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let allowed = res.headers().get("access-control-allow-origin").unwrap();
+        assert_eq!(allowed, "https://admin.example.com");
+        let res = app.oneshot(preflight("https://evil.com")).await.unwrap();
+        assert!(res.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[test]
+    fn it_rejects_credentials_for_any_origin() {
+        assert!(ServerOpts::try_parse_from(["server", "--cors-allow-credentials"]).is_err());
+        let opts =
+            ServerOpts::parse_from(["server", "--cors-origin", "*", "--cors-allow-credentials"]);
+        assert!(cors_layer(&opts).is_err());
+        let opts = ServerOpts::parse_from([
+            "server",
+            "--cors-origin",
+            "https://admin.example.com",
+            "--cors-allow-credentials",
+        ]);
+        assert!(cors_layer(&opts).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_relative_route_paths() {
+        assert!(ServerOpts::try_parse_from(["server", "--openapi-path", "openapi.json"]).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use super::*;
+
+#[derive(Serialize, ToSchema)]
+pub struct ServerStatus {
+    /// version of the running build
+    #[schema(example = "0.1.0")]
+    version: String,
+    /// security groups with their amount of rules
+    groups: Vec<GroupInfo>,
+    /// time of the last successful load of the rules, RFC 3339
+    #[schema(example = "2023-03-01T12:00:00+00:00")]
+    rules_loaded_at: Option<String>,
+    /// path to the MaxMind databases
+    #[schema(example = "./")]
+    maxmind_path: String,
+    /// time of the latest change of the MaxMind databases on disk, RFC 3339
+    #[schema(example = "2023-02-28T06:00:00+00:00")]
+    maxmind_modified_at: Option<String>,
+}
+
+/// status
+#[utoipa::path(
+    get,
+    path = "/status",
+    responses(
+        (status = 200, description = "running configuration of the server, to compare it with the files on disk", body = ServerStatus),
+    ),
+)]
+pub async fn handle<MM>(Extension(state): Extension<Arc<RwLock<AppState<MM>>>>) -> impl IntoResponse
+where
+    MM: IntoVisitor,
+{
+    let state = state.read().unwrap();
+    let maxmind_path = state.opts.maxmind_path.clone();
+    let maxmind_modified_at = crate::visitor::maxmind_modified(&maxmind_path)
+        .map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339());
+    Json(ServerStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        groups: state.svc.list_groups(),
+        rules_loaded_at: state.svc.loaded_at.map(|t| t.to_rfc3339()),
+        maxmind_path,
+        maxmind_modified_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::SecurityGroupService;
+    use crate::store::FileStore;
+    use crate::visitor::Visit;
+    use axum::body::HttpBody;
+    use clap::Parser;
+    use std::net::IpAddr;
+
+    struct NoGeo;
+
+    impl IntoVisitor for NoGeo {
+        fn visit(&self, _ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit::no_ip(uri))
+        }
+    }
+
+    #[tokio::test]
+    async fn it_reports_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        std::fs::write(dir.path().join("default.rules.txt"), "403|US\n403|CN\n").unwrap();
+        std::fs::write(dir.path().join("GeoLite2-City.mmdb"), "").unwrap();
+        let svc = SecurityGroupService::from_store(Box::new(FileStore::new(path))).unwrap();
+        let opts = crate::cli::ServerOpts::parse_from(["server", "--maxmind-path", path]);
+        let state = Arc::new(RwLock::new(AppState::new(svc, NoGeo, opts)));
+
+        let res = handle(Extension(state)).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().data().await.unwrap().unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            status["groups"],
+            serde_json::json!([{"name": "default", "rules": 2}])
+        );
+        assert!(status["rules_loaded_at"].is_string());
+        assert_eq!(status["maxmind_path"], path);
+        assert!(status["maxmind_modified_at"].is_string());
+    }
+}
@@ -0,0 +1,97 @@
+use super::*;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// time to wait for more changes after the first one, as editors write files in several steps
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// watches the storage path and reloads the changed security groups into the shared state.
+/// Watching stops when the returned watcher is dropped
+pub fn spawn<MM>(
+    storage_path: &str,
+    state: Arc<RwLock<AppState<MM>>>,
+) -> anyhow::Result<RecommendedWatcher>
+where
+    MM: IntoVisitor + Send + Sync + 'static,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+        Ok(event) => {
+            for path in event.paths {
+                let _ = tx.send(path);
+            }
+        }
+        Err(e) => warn!("rules watcher error {:?}", e),
+    })?;
+    watcher.watch(
+        std::path::Path::new(storage_path),
+        RecursiveMode::NonRecursive,
+    )?;
+    tokio::spawn(reload(rx, state));
+    info!("watching {} for rules changes", storage_path);
+    Ok(watcher)
+}
+
+async fn reload<MM>(mut rx: mpsc::UnboundedReceiver<PathBuf>, state: Arc<RwLock<AppState<MM>>>)
+where
+    MM: IntoVisitor,
+{
+    while let Some(path) = rx.recv().await {
+        let mut changed = BTreeSet::from([path]);
+        tokio::time::sleep(DEBOUNCE).await;
+        while let Ok(path) = rx.try_recv() {
+            changed.insert(path);
+        }
+        let mut state = state.write().unwrap();
+        for path in changed {
+            if let Some((name, count)) = state.svc.reload_file(&path.to_string_lossy()) {
+                info!("reloaded group {}, {} rules", name, count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::SecurityGroupService;
+    use crate::store::FileStore;
+    use crate::visitor::Visit;
+    use clap::Parser;
+    use std::net::IpAddr;
+
+    struct NoGeo;
+
+    impl IntoVisitor for NoGeo {
+        fn visit(&self, _ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+            Ok(Visit::no_ip(uri))
+        }
+    }
+
+    #[tokio::test]
+    async fn it_reloads_changed_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = dir.path().to_str().unwrap();
+        let file = dir.path().join("default.rules.txt");
+        std::fs::write(&file, "403|US\n").unwrap();
+
+        let svc = SecurityGroupService::from_store(Box::new(FileStore::new(storage))).unwrap();
+        let opts = crate::cli::ServerOpts::parse_from(["server", "--watch"]);
+        let state = Arc::new(RwLock::new(AppState::new(svc, NoGeo, opts)));
+        let _watcher = spawn(storage, state.clone()).unwrap();
+
+        std::fs::write(&file, "403|US\n401|GB\n403|/admin\n").unwrap();
+        let mut count = 0;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            count = state.read().unwrap().svc.count_rules("default").total;
+            if count == 3 {
+                break;
+            }
+        }
+        assert_eq!(count, 3);
+    }
+}
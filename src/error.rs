@@ -0,0 +1,36 @@
+/// errors of the service layer that should be told apart from IO failures,
+/// carried inside `anyhow::Error` and recovered with `downcast_ref`
+#[derive(Debug, Clone, PartialEq)]
+pub enum GuardError {
+    /// the rule line provided by the client cannot be parsed
+    Parse { line: String, message: String },
+    /// the group or the rule addressed by the client doesn't exist
+    NotFound(String),
+    /// the directory of the rules files doesn't exist, e.g. on the first run with a fresh volume
+    MissingStorage(String),
+}
+
+impl GuardError {
+    pub fn parse(line: &str, err: anyhow::Error) -> Self {
+        Self::Parse {
+            line: line.to_string(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for GuardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse { line, message } => write!(f, "invalid rule {:?}: {}", line, message),
+            Self::NotFound(what) => write!(f, "{} not found", what),
+            Self::MissingStorage(path) => write!(
+                f,
+                "storage path {:?} doesn't exist, create it or run with --create-storage",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GuardError {}
@@ -0,0 +1,15 @@
+pub mod batch;
+pub mod cache;
+pub mod cli;
+pub mod compact;
+pub mod countries;
+pub mod diacritics;
+pub mod endpoints;
+pub mod error;
+pub mod logging;
+pub mod networks;
+pub mod proto;
+pub mod state;
+pub mod store;
+pub mod tags;
+pub mod visitor;
@@ -1,18 +1,8 @@
-mod cli;
-mod diacritics;
-mod endpoints;
-mod logging;
-mod proto;
-mod state;
-mod tags;
-mod visitor;
-
-// NSG: actually check secret token to use it. Token could be in query param request too
+use traefik_guard::{batch, cli, endpoints, logging, state, tags, visitor};
 
 use anyhow::Context;
 use clap::Parser;
 use state::*;
-use std::net::SocketAddr;
 use tracing::*;
 use visitor::*;
 
@@ -23,21 +13,26 @@ pub async fn main() -> anyhow::Result<()> {
 
     let args = cli::Opts::parse();
     debug!("{args:?}");
+    let nsg = args.nsg.clone().unwrap_or_else(|| "default".to_string());
+    let store = args.rule_store();
     match args.action {
-        cli::Action::Add { rule } => {
+        cli::Action::Add { rule, dedupe } => {
             info!("Add {}", rule);
-            let mut svc = state::SecurityGroupService::from_local_path(&args.storage_path)
-                .context("security group load")?;
-            svc.create_rule(&args.nsg, &rule)?;
+            let mut svc = SecurityGroupService::from_store(store).context("security group load")?;
+            let created = svc.create_rule(&nsg, &rule, dedupe)?;
+            println!(
+                "{} rules added, {} duplicates skipped",
+                created.indexes.len(),
+                created.skipped
+            );
         }
         cli::Action::List { tags } => {
-            let svc = state::SecurityGroupService::from_local_path(&args.storage_path)
-                .context("security group load")?;
+            let svc = SecurityGroupService::from_store(store).context("security group load")?;
             let tm = match tags {
                 Some(t) => tags::TagMap::from_query(&t),
                 None => tags::TagMap::new(),
             };
-            println!("{}", svc.list_rules_as_str(&args.nsg, &tm)?);
+            println!("{}", svc.list_rules_as_str(&nsg, &tm, None)?);
         }
         cli::Action::Update {
             ref_type,
@@ -49,9 +44,27 @@ pub async fn main() -> anyhow::Result<()> {
                 cli::RuleRefType::Index => RulesRef::Index(reference.parse().unwrap()),
                 cli::RuleRefType::Tag => RulesRef::Tag(tags::TagMap::from_query(&reference)),
             };
-            let mut svc = SecurityGroupService::from_local_path(&args.storage_path)
-                .context("security group load")?;
-            svc.update_rule(&args.nsg, &r, &rule)?;
+            let mut svc = SecurityGroupService::from_store(store).context("security group load")?;
+            svc.update_rule(&nsg, &r, &rule)?;
+        }
+        cli::Action::Lint => {
+            let svc = SecurityGroupService::from_store(store).context("security group load")?;
+            let group = svc
+                .groups
+                .get(&nsg)
+                .ok_or_else(|| anyhow::anyhow!("group {} not found", nsg))?;
+            let conflicts = group.lint();
+            for conflict in &conflicts {
+                println!("{}", conflict);
+            }
+            if !conflicts.is_empty() {
+                anyhow::bail!("{} conflicts in group {}", conflicts.len(), nsg);
+            }
+        }
+        cli::Action::RenameTag { from, to } => {
+            let mut svc = SecurityGroupService::from_store(store).context("security group load")?;
+            let count = svc.rename_tag(&nsg, &from, &to)?;
+            println!("{} rules updated", count);
         }
         cli::Action::Rm {
             ref_type,
@@ -62,9 +75,8 @@ pub async fn main() -> anyhow::Result<()> {
                 cli::RuleRefType::Index => RulesRef::Index(reference.parse().unwrap()),
                 cli::RuleRefType::Tag => RulesRef::Tag(tags::TagMap::from_query(&reference)),
             };
-            let mut svc = SecurityGroupService::from_local_path(&args.storage_path)
-                .context("security group load")?;
-            svc.delete_rule(&args.nsg, &r)?;
+            let mut svc = SecurityGroupService::from_store(store).context("security group load")?;
+            svc.delete_rule(&nsg, &r)?;
         }
 
         cli::Action::Check {
@@ -72,29 +84,35 @@ pub async fn main() -> anyhow::Result<()> {
             uri,
             maxmind_path,
         } => {
-            let svc = state::SecurityGroupService::from_local_path(&args.storage_path)
-                .context("security group load")?;
-            let ipv4 = ip.parse().unwrap();
-            let v = MmFromDiskReader::new(&maxmind_path)?.visit(ipv4, &uri)?;
+            let svc = SecurityGroupService::from_store(store).context("security group load")?;
+            let ip = ip.parse().context("invalid IP address")?;
+            let v = MmFromDiskReader::new(&maxmind_path)?.visit(ip, &uri)?;
             println!("{:?}", v);
-            println!("{:?}", svc.react(&args.nsg, &v)?);
+            println!("{:?}", svc.react(&nsg, &v)?);
+        }
+        cli::Action::CheckBatch { maxmind_path } => {
+            let svc = SecurityGroupService::from_store(store).context("security group load")?;
+            let mm = MmKeepInMemory::new(&maxmind_path)?;
+            let stdin = std::io::stdin();
+            batch::check_batch(&svc, &nsg, &mm, stdin.lock(), &mut std::io::stdout())?;
         }
 
-        cli::Action::Server {
-            listen,
-            maxmind_path,
-            secret_token,
-            access_log_path,
-        } => {
-            let socket_addr: SocketAddr = listen.parse().expect("invalid network port bind");
-            endpoints::server::run(
-                socket_addr,
-                &secret_token,
-                &maxmind_path,
-                &args.storage_path,
-                &access_log_path,
-            )
-            .await?;
+        cli::Action::Export => {
+            let svc = SecurityGroupService::from_store(store).context("security group load")?;
+            println!("{}", serde_json::to_string_pretty(&svc.export_json())?);
+        }
+
+        cli::Action::Import => {
+            let mut svc = SecurityGroupService::from_store(store).context("security group load")?;
+            let value: serde_json::Value =
+                serde_json::from_reader(std::io::stdin().lock()).context("invalid JSON")?;
+            let count = svc.import_json(value)?;
+            info!("Imported {} groups", count);
+        }
+
+        cli::Action::Server(mut opts) => {
+            opts.nsg = args.nsg;
+            endpoints::server::run(&opts, store).await?;
         }
     }
     Ok(())
@@ -0,0 +1,94 @@
+use ipnetwork::IpNetwork;
+use std::collections::BTreeMap as Map;
+use std::net::IpAddr;
+
+/// Quick lookup of the indexed rules by the networks of their sources.
+/// Networks are grouped by the prefix length, so the visitor IP is masked once
+/// per prefix length of the group, instead of checking every network rule
+#[derive(Clone, Default)]
+pub struct NetworkIndex {
+    // network address by the prefix length, pointing to the position in list_indexed
+    v4: Map<u8, Map<u32, usize>>,
+    v6: Map<u8, Map<u128, usize>>,
+}
+
+impl NetworkIndex {
+    pub fn len(&self) -> usize {
+        let v4: usize = self.v4.values().map(Map::len).sum();
+        let v6: usize = self.v6.values().map(Map::len).sum();
+        v4 + v6
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the earlier rule keeps the network, as it is checked first
+    pub fn insert(&mut self, net: IpNetwork, position: usize) {
+        match net {
+            IpNetwork::V4(net) => {
+                let prefix = net.prefix();
+                let address = u32::from(net.ip()) & mask_v4(prefix);
+                let networks = self.v4.entry(prefix).or_default();
+                networks.entry(address).or_insert(position);
+            }
+            IpNetwork::V6(net) => {
+                let prefix = net.prefix();
+                let address = u128::from(net.ip()) & mask_v6(prefix);
+                let networks = self.v6.entry(prefix).or_default();
+                networks.entry(address).or_insert(position);
+            }
+        }
+    }
+
+    /// position of the earliest rule with a network containing the IP
+    pub fn get(&self, ip: IpAddr) -> Option<usize> {
+        match ip {
+            IpAddr::V4(ip) => {
+                let ip = u32::from(ip);
+                self.v4
+                    .iter()
+                    .filter_map(|(prefix, networks)| networks.get(&(ip & mask_v4(*prefix))))
+                    .min()
+                    .copied()
+            }
+            IpAddr::V6(ip) => {
+                let ip = u128::from(ip);
+                self.v6
+                    .iter()
+                    .filter_map(|(prefix, networks)| networks.get(&(ip & mask_v6(*prefix))))
+                    .min()
+                    .copied()
+            }
+        }
+    }
+}
+
+fn mask_v4(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask_v6(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_index() {
+        let mut index = NetworkIndex::default();
+        index.insert("10.0.0.0/8".parse().unwrap(), 3);
+        index.insert("10.1.2.0/24".parse().unwrap(), 1);
+        index.insert("10.1.2.7/24".parse().unwrap(), 5);
+        index.insert("0.0.0.0/0".parse().unwrap(), 9);
+        index.insert("2001:db8::/32".parse().unwrap(), 2);
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.get("10.1.2.3".parse().unwrap()), Some(1));
+        assert_eq!(index.get("10.1.3.3".parse().unwrap()), Some(3));
+        assert_eq!(index.get("11.0.0.1".parse().unwrap()), Some(9));
+        assert_eq!(index.get("2001:db8::1".parse().unwrap()), Some(2));
+        assert_eq!(index.get("2001:db9::1".parse().unwrap()), None);
+    }
+}
@@ -1,18 +1,41 @@
+use crate::compact::CompactDenyList;
+use crate::networks::NetworkIndex;
 use anyhow::{bail, Context};
-use ipnetwork::Ipv4Network;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap as Map;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use tracing::*;
 
 // abstraction to wrap properties of HTTP request
 pub trait Visitor {
     fn country(&self) -> Option<String>;
     fn city(&self) -> Option<String>;
-    fn ip(&self) -> Ipv4Addr;
+    // ISO 3166-2 code of the region, e.g. "US-CA"
+    fn subdivision(&self) -> Option<String>;
+    // postal code, in the format of the country
+    fn postal(&self) -> Option<String>;
+    fn asn(&self) -> Option<u32>;
+    fn ip(&self) -> IpAddr;
     fn uri(&self) -> String;
+    // HTTP method of the original request, as forwarded by the proxy
+    fn method(&self) -> Option<String>;
+    // host and scheme of the original request, as forwarded by the proxy
+    fn host(&self) -> Option<String>;
+    fn scheme(&self) -> Option<String>;
+    // raw query string of the request URI
+    fn query(&self) -> Option<String>;
+    // whether geo location of the visitor could not be resolved
+    fn geo_unknown(&self) -> bool;
+    // time of the visit, for the scheduled rules
+    fn time(&self) -> DateTime<Utc>;
+    // whether paths of the rules are compared regardless of the letter case
+    fn paths_ignore_case(&self) -> bool;
+    // User-Agent header of the request
+    fn user_agent(&self) -> Option<String>;
 }
 
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -23,37 +46,119 @@ pub enum Source {
     FromIpv4(Ipv4Addr),
     #[serde(rename = "net")]
     FromIpv4Network(Ipv4Network),
+    #[serde(rename = "ipv6")]
+    FromIpv6(Ipv6Addr),
+    #[serde(rename = "net6")]
+    FromIpv6Network(Ipv6Network),
     #[serde(rename = "country")]
     FromCountry(String),
     #[serde(rename = "city")]
     FromCity(String),
+    #[serde(rename = "subdivision")]
+    FromSubdivision(String),
+    // postal code, compared as is since the formats vary by country
+    #[serde(rename = "postal")]
+    FromPostal(String),
+    #[serde(rename = "asn")]
+    FromAsn(u32),
+    #[serde(rename = "geo-unknown")]
+    GeoUnknown,
+    // substring of the User-Agent header, regardless of the letter case
+    #[serde(rename = "user-agent")]
+    FromUserAgent(String),
+    #[serde(rename = "user-agent-regex")]
+    FromUserAgentRegex(Pattern),
 }
 
-impl Source {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Source::Any => "*".to_string(),
-            Source::FromIpv4(ip) => ip.to_string(),
-            Source::FromIpv4Network(net) => net.to_string(),
-            Source::FromCountry(country) => country.to_string(),
-            Source::FromCity(city) => city.to_string(),
+            Source::Any => write!(f, "*"),
+            Source::FromIpv4(ip) => write!(f, "{}", ip),
+            Source::FromIpv4Network(net) => write!(f, "{}", net),
+            Source::FromIpv6(ip) => write!(f, "{}", ip),
+            Source::FromIpv6Network(net) => write!(f, "{}", net),
+            // explicit prefix is written only when the value alone would be read as something else
+            Source::FromCountry(country) if country.len() != 2 => write!(f, "country:{}", country),
+            Source::FromCountry(country) => write!(f, "{}", country),
+            Source::FromCity(city) => match Source::parse(city) {
+                Ok(Source::FromCity(_)) => write!(f, "{}", city),
+                _ => write!(f, "city:{}", city),
+            },
+            Source::FromSubdivision(code) => write!(f, "{}", code),
+            Source::FromPostal(code) => write!(f, "zip:{}", code),
+            Source::FromAsn(asn) => write!(f, "AS{}", asn),
+            Source::GeoUnknown => write!(f, "geo-unknown"),
+            Source::FromUserAgent(ua) => write!(f, "ua:{}", ua),
+            Source::FromUserAgentRegex(re) => write!(f, "ua:~{}", re.0.as_str()),
         }
     }
+}
 
-    pub fn parse(input: &str) -> Self {
-        if input == "" || input == "*" {
+impl Source {
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        if let Some(ua) = input.strip_prefix("ua:") {
+            if let Some(pattern) = ua.strip_prefix('~') {
+                let re = regex::Regex::new(pattern)
+                    .with_context(|| format!("invalid user agent regex {:?}", pattern))?;
+                return Ok(Source::FromUserAgentRegex(Pattern(re)));
+            }
+            if ua.is_empty() {
+                bail!("empty user agent in {:?}", input);
+            }
+            return Ok(Source::FromUserAgent(ua.to_string()));
+        }
+        if let Some(country) = input.strip_prefix("country:") {
+            if country.is_empty() {
+                bail!("empty country in {:?}", input);
+            }
+            return Ok(Source::FromCountry(country.to_string()));
+        }
+        if let Some(code) = input.strip_prefix("zip:") {
+            if code.is_empty() {
+                bail!("empty postal code in {:?}", input);
+            }
+            return Ok(Source::FromPostal(code.to_string()));
+        }
+        if let Some(city) = input.strip_prefix("city:") {
+            if city.is_empty() {
+                bail!("empty city in {:?}", input);
+            }
+            return Ok(Source::FromCity(city.to_string()));
+        }
+        Ok(if input.is_empty() || input == "*" {
             Source::Any
+        } else if input == "geo-unknown" {
+            Source::GeoUnknown
         } else if input.len() == 2 {
             // 2 rule character set will be treated as a country
             Source::FromCountry(input.to_string())
+        } else if is_subdivision(input) {
+            Source::FromSubdivision(input.to_string())
+        } else if let Some(asn) = input.strip_prefix("AS").and_then(|n| n.parse::<u32>().ok()) {
+            Source::FromAsn(asn)
         } else if let Ok(ip) = input.parse::<Ipv4Addr>() {
             Source::FromIpv4(ip)
         } else if let Ok(net) = input.parse::<Ipv4Network>() {
             Source::FromIpv4Network(net)
+        } else if let Ok(ip) = input.parse::<Ipv6Addr>() {
+            Source::FromIpv6(ip)
+        } else if let Ok(net) = input.parse::<Ipv6Network>() {
+            Source::FromIpv6Network(net)
         } else {
             // we've filtered out empty results already
             // so the unclassified string would be treated like a city
             Source::FromCity(input.to_string())
+        })
+    }
+
+    // whether the user agent of the visitor matches, for the user agent sources only
+    fn matches_user_agent<V: Visitor>(&self, v: &V) -> bool {
+        let ua = v.user_agent().unwrap_or_default();
+        match self {
+            Source::FromUserAgent(s) => ua.to_lowercase().contains(&s.to_lowercase()),
+            Source::FromUserAgentRegex(re) => re.0.is_match(&ua),
+            _ => false,
         }
     }
 }
@@ -66,29 +171,383 @@ pub enum Target {
     Path(String),
     #[serde(rename = "path-prefix")]
     PathPrefix(String),
+    #[serde(rename = "path-regex")]
+    Regex(Pattern),
+    #[serde(rename = "path-glob")]
+    Glob(String),
+    #[serde(rename = "query")]
+    Query(String, String),
+    #[serde(rename = "query-present")]
+    QueryPresent(String),
+    #[serde(rename = "host")]
+    Host(String),
+    #[serde(rename = "scheme")]
+    Scheme(String),
+    #[serde(rename = "excluding")]
+    Excluding(Box<Target>),
 }
 
-impl Target {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Target::Any => "".to_string(),
-            Target::Path(path) => path.to_string(),
-            Target::PathPrefix(path) => format!("^{}", path),
+            Target::Any => Ok(()),
+            Target::Path(path) => write!(f, "{}", path),
+            Target::PathPrefix(path) => write!(f, "^{}", path),
+            Target::Regex(pattern) => write!(f, "~{}", pattern.0.as_str()),
+            Target::Glob(glob) => write!(f, "{}", glob),
+            Target::Query(key, value) => write!(f, "?{}={}", key, value),
+            Target::QueryPresent(key) => write!(f, "?{}", key),
+            Target::Host(host) => write!(f, "//{}", host),
+            Target::Scheme(scheme) => write!(f, "{}:", scheme),
+            Target::Excluding(target) => write!(f, "-{}", target),
+        }
+    }
+}
+
+// compiled regular expression of the path, compared by its source
+#[derive(Debug, Clone)]
+pub struct Pattern(pub regex::Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let src = String::deserialize(d)?;
+        regex::Regex::new(&src)
+            .map(Pattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// subdivision is ISO 3166-2 code: country, dash and up to 3 letters or digits, e.g. "US-CA"
+fn is_subdivision(input: &str) -> bool {
+    match input.split_once('-') {
+        Some((country, region)) => {
+            country.len() == 2
+                && country.chars().all(|c| c.is_ascii_uppercase())
+                && (1..=3).contains(&region.len())
+                && region
+                    .chars()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+// scheme target is a word with a trailing colon, e.g. "http:", unlike IPv6 addresses
+fn is_scheme(input: &str) -> bool {
+    match input.strip_suffix(':') {
+        Some(scheme) => !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphabetic()),
+        None => false,
+    }
+}
+
+// matches the path against the glob: `?` is any character and `*` is any sequence of characters
+// within one path segment, `**` also spans across the segments
+fn glob_match(glob: &[char], path: &[char]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            let segment = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+            (0..=segment).any(|i| glob_match(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != '/' && glob_match(rest, tail)),
+        [g, rest @ ..] => matches!(path, [c, tail @ ..] if c == g && glob_match(rest, tail)),
+    }
+}
+
+// matches the forwarded host, `*.example.com` matches any subdomain but not the domain itself
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('*') {
+        Some(suffix) => {
+            host.len() > suffix.len()
+                && host.is_char_boundary(host.len() - suffix.len())
+                && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
         }
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+// path, query, host and scheme parts of the rule are targets, `-` excludes the path or query
+fn is_target(part: &str) -> bool {
+    if let Some(excluded) = part.strip_prefix('-') {
+        return excluded.starts_with(['/', '^', '?', '~']);
     }
+    part.starts_with(['/', '^', '?', '~'])
+        || part.starts_with("host:")
+        || (is_scheme(part) && !["ua:", "country:", "city:", "zip:"].contains(&part))
+}
 
-    pub fn parse(input: &str) -> Self {
-        if input == "" {
-            return Self::Any;
+impl Target {
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        if input.is_empty() {
+            return Ok(Self::Any);
+        }
+        if let Some(excluded) = input.strip_prefix('-') {
+            return Ok(Self::Excluding(Box::new(Self::parse(excluded)?)));
+        }
+        if let Some(pattern) = input.strip_prefix('~') {
+            let re = regex::Regex::new(pattern)
+                .with_context(|| format!("invalid path regex {:?}", pattern))?;
+            return Ok(Self::Regex(Pattern(re)));
+        }
+        if let Some(host) = input.strip_prefix("//").or(input.strip_prefix("host:")) {
+            if host.is_empty() {
+                bail!("empty host in {:?}", input);
+            }
+            return Ok(Self::Host(host.to_lowercase()));
+        }
+        if let Some(query) = input.strip_prefix('?') {
+            return Ok(match query.split_once('=') {
+                Some((key, value)) => Self::Query(key.to_string(), value.to_string()),
+                None => Self::QueryPresent(query.to_string()),
+            });
+        }
+        if is_scheme(input) {
+            return Ok(Self::Scheme(input.trim_end_matches(':').to_lowercase()));
         }
         let start = input.chars().next().unwrap();
-        if start == '/' {
+        Ok(if start == '/' && input.contains(['*', '?']) {
+            // path with wildcards is always a glob, literal `*` or `?` can be matched with regex
+            Self::Glob(input.to_string())
+        } else if start == '/' {
             Self::Path(input.to_string())
         } else if start == '^' {
             Self::PathPrefix(input.chars().skip(1).collect())
         } else {
             Self::Any
+        })
+    }
+
+    // host and scheme are conditions on the request itself, they are checked in addition to the path
+    fn is_request_condition(&self) -> bool {
+        matches!(self, Target::Host(_) | Target::Scheme(_))
+    }
+
+    fn is_excluding(&self) -> bool {
+        matches!(self, Target::Excluding(_))
+    }
+
+    // checks the path or query condition of the target against the visitor
+    fn matches_path<V: Visitor>(&self, v: &V) -> bool {
+        match self {
+            Target::Path(path) if v.paths_ignore_case() => {
+                let uri = v.uri().to_lowercase();
+                let path = path.to_lowercase();
+                uri == path || uri == format!("{}/", path)
+            }
+            Target::Path(path) => v.uri() == *path || v.uri() == format!("{}/", *path),
+            Target::PathPrefix(prefix) if v.paths_ignore_case() => {
+                v.uri().to_lowercase().starts_with(&prefix.to_lowercase())
+            }
+            Target::PathPrefix(prefix) => v.uri().starts_with(prefix),
+            Target::Regex(pattern) => pattern.0.is_match(&v.uri()),
+            Target::Glob(glob) => {
+                let (glob, uri) = match v.paths_ignore_case() {
+                    true => (glob.to_lowercase(), v.uri().to_lowercase()),
+                    false => (glob.to_string(), v.uri()),
+                };
+                let glob: Vec<char> = glob.chars().collect();
+                let uri: Vec<char> = uri.chars().collect();
+                // trailing slash is optional, like for the exact paths
+                glob_match(&glob, &uri)
+                    || matches!(uri.as_slice(), [head @ .., '/'] if glob_match(&glob, head))
+            }
+            Target::Query(key, value) => query_pairs(v)
+                .iter()
+                .any(|(k, val)| k == key && val == value),
+            Target::QueryPresent(key) => query_pairs(v).iter().any(|(k, _)| k == key),
+            Target::Any => true,
+            Target::Excluding(target) => target.matches_path(v),
+            Target::Host(_) | Target::Scheme(_) => false,
+        }
+    }
+}
+
+// HTTP method condition of the rule
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Connect,
+    Trace,
+}
+
+impl Method {
+    const ALL: [Method; 9] = [
+        Method::Get,
+        Method::Head,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Patch,
+        Method::Options,
+        Method::Connect,
+        Method::Trace,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+        }
+    }
+
+    // only uppercase tokens are methods, to be distinguished from the city names
+    pub fn parse(input: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.as_str() == input)
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// UTC time window of the rule, e.g. `@02:00-03:00` or `@22:00-06:00/mon-fri`.
+// Window with the end before the start goes past midnight, the weekdays are the days it starts on
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Schedule {
+    // minutes since midnight
+    start: u16,
+    end: u16,
+    // bit mask of the weekdays, monday is the lowest bit
+    days: u8,
+}
+
+impl Schedule {
+    const ALL_DAYS: u8 = 0b111_1111;
+
+    fn parse_time(input: &str) -> anyhow::Result<u16> {
+        let (h, m) = input.split_once(':').context("time expected as HH:MM")?;
+        let (h, m) = (h.parse::<u16>()?, m.parse::<u16>()?);
+        if h > 24 || m > 59 || (h == 24 && m > 0) {
+            bail!("invalid time {:?}", input);
+        }
+        Ok(h * 60 + m)
+    }
+
+    fn parse_day(input: &str) -> anyhow::Result<u32> {
+        match WEEKDAYS.iter().position(|d| d.eq_ignore_ascii_case(input)) {
+            Some(day) => Ok(day as u32),
+            None => bail!("invalid weekday {:?}", input),
+        }
+    }
+
+    // parses `mon-fri`, `sat+sun` or their combination
+    fn parse_days(input: &str) -> anyhow::Result<u8> {
+        let mut days = 0u8;
+        for item in input.split('+') {
+            let (from, to) = match item.split_once('-') {
+                Some((from, to)) => (Self::parse_day(from)?, Self::parse_day(to)?),
+                None => (Self::parse_day(item)?, Self::parse_day(item)?),
+            };
+            let mut day = from;
+            loop {
+                days |= 1 << day;
+                if day == to {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+        }
+        Ok(days)
+    }
+
+    // parses the window without the leading `@`
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        let (window, days) = match input.split_once('/') {
+            Some((window, days)) => (window, Self::parse_days(days)?),
+            None => (input, Self::ALL_DAYS),
+        };
+        let (start, end) = window
+            .split_once('-')
+            .context("time window expected as @HH:MM-HH:MM")?;
+        let (start, end) = (Self::parse_time(start)?, Self::parse_time(end)?);
+        if start == end {
+            bail!("empty time window {:?}", input);
+        }
+        Ok(Self { start, end, days })
+    }
+
+    fn has_day(&self, day: u32) -> bool {
+        self.days & (1 << day) != 0
+    }
+
+    pub fn contains(&self, t: DateTime<Utc>) -> bool {
+        let minute = (t.hour() * 60 + t.minute()) as u16;
+        let day = t.weekday().num_days_from_monday();
+        if self.start < self.end {
+            self.has_day(day) && self.start <= minute && minute < self.end
+        } else if minute >= self.start {
+            self.has_day(day)
+        } else {
+            // continuation of the window started the day before
+            minute < self.end && self.has_day((day + 6) % 7)
+        }
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hhmm = |m: u16| format!("{:02}:{:02}", m / 60, m % 60);
+        write!(f, "@{}-{}", hhmm(self.start), hhmm(self.end))?;
+        if self.days == Self::ALL_DAYS {
+            return Ok(());
         }
+        // consecutive days are written as ranges
+        let mut items = vec![];
+        let mut day = 0;
+        while day < 7 {
+            if !self.has_day(day) {
+                day += 1;
+                continue;
+            }
+            let from = day;
+            while day + 1 < 7 && self.has_day(day + 1) {
+                day += 1;
+            }
+            match day - from {
+                0 => items.push(WEEKDAYS[from as usize].to_string()),
+                1 => items.push(format!(
+                    "{}+{}",
+                    WEEKDAYS[from as usize], WEEKDAYS[day as usize]
+                )),
+                _ => items.push(format!(
+                    "{}-{}",
+                    WEEKDAYS[from as usize], WEEKDAYS[day as usize]
+                )),
+            }
+            day += 1;
+        }
+        write!(f, "/{}", items.join("+"))
     }
 }
 
@@ -100,20 +559,22 @@ pub enum Access {
     Excluding(Source),
 }
 
-impl Access {
-    pub fn to_string(&self) -> String {
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Access::From(source) => source.to_string(),
-            Access::Excluding(source) => format!("-{}", source.to_string()),
+            Access::From(source) => write!(f, "{}", source),
+            Access::Excluding(source) => write!(f, "-{}", source),
         }
     }
+}
 
-    pub fn parse(input: &str) -> Self {
-        if input.len() > 1 && input.chars().next().unwrap() == '-' {
+impl Access {
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        if input.len() > 1 && input.starts_with('-') {
             let next = &input[1..];
-            return Access::Excluding(Source::parse(next));
+            return Ok(Access::Excluding(Source::parse(next)?));
         }
-        Access::From(Source::parse(input))
+        Ok(Access::From(Source::parse(input)?))
     }
 }
 
@@ -123,9 +584,20 @@ pub enum Reaction {
     PermanentRedirect(String),
     #[serde(rename = "302")]
     TemporaryRedirect(String),
+    // method-preserving redirects, clients repeat POST with the same body at the new location
+    #[serde(rename = "307")]
+    TemporaryRedirectKeepMethod(String),
+    #[serde(rename = "308")]
+    PermanentRedirectKeepMethod(String),
     #[serde(rename = "code")]
     HttpStatus(u16),
-    // TODO: reaction with additional header (key=value)
+    // status with additional response headers, e.g. `403|US|X-Reason:blocked;X-Team:sec`
+    #[serde(rename = "code-with-headers")]
+    HttpStatusWithHeaders(u16, Vec<(String, String)>),
+    // allows the traffic, but responds with 429 once the client IP exceeds the limit,
+    // e.g. `429|US|ratelimit:60:10` for 60 requests per minute with bursts of 10
+    #[serde(rename = "ratelimit")]
+    RateLimit { rpm: u32, burst: u32 },
 }
 
 impl Reaction {
@@ -134,8 +606,58 @@ impl Reaction {
         match self {
             Reaction::PermanentRedirect(_) => 301,
             Reaction::TemporaryRedirect(_) => 302,
+            Reaction::TemporaryRedirectKeepMethod(_) => 307,
+            Reaction::PermanentRedirectKeepMethod(_) => 308,
             Reaction::HttpStatus(code) => *code,
+            Reaction::HttpStatusWithHeaders(code, _) => *code,
+            Reaction::RateLimit { .. } => 429,
+        }
+    }
+
+    // returns additional response headers, if any
+    pub fn headers(&self) -> &[(String, String)] {
+        match self {
+            Reaction::HttpStatusWithHeaders(_, headers) => headers,
+            _ => &[],
+        }
+    }
+
+    // parses `ratelimit:RPM:BURST`, burst defaults to the requests per minute
+    fn parse_rate_limit(input: &str) -> anyhow::Result<Reaction> {
+        let mut parts = input.split(':').skip(1);
+        let rpm = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<u32>()
+            .context("invalid requests per minute")?;
+        let burst = match parts.next() {
+            Some(burst) => burst.parse::<u32>().context("invalid burst")?,
+            None => rpm,
+        };
+        if rpm == 0 || burst == 0 || parts.next().is_some() {
+            bail!(
+                "rate limit expected as ratelimit:RPM:BURST, got {:?}",
+                input
+            );
         }
+        Ok(Reaction::RateLimit { rpm, burst })
+    }
+
+    // parses `Name:value;Name:value` list of the headers
+    fn parse_headers(input: &str) -> anyhow::Result<Vec<(String, String)>> {
+        let mut out = vec![];
+        for header in input.split(';').filter(|h| !h.trim().is_empty()) {
+            match header.split_once(':') {
+                Some((name, value)) if !name.trim().is_empty() => {
+                    out.push((name.trim().to_string(), value.trim().to_string()))
+                }
+                _ => bail!("header expected as Name:value, got {:?}", header),
+            }
+        }
+        if out.is_empty() {
+            bail!("no headers provided");
+        }
+        Ok(out)
     }
 
     // returns redirect location, if any
@@ -143,26 +665,40 @@ impl Reaction {
         match self {
             Reaction::PermanentRedirect(loc) => Some(loc.to_string()),
             Reaction::TemporaryRedirect(loc) => Some(loc.to_string()),
+            Reaction::TemporaryRedirectKeepMethod(loc) => Some(loc.to_string()),
+            Reaction::PermanentRedirectKeepMethod(loc) => Some(loc.to_string()),
             Reaction::HttpStatus(_) => None,
+            Reaction::HttpStatusWithHeaders(_, _) => None,
+            Reaction::RateLimit { .. } => None,
         }
     }
 
     pub fn extract(input: &str) -> anyhow::Result<(String, Reaction)> {
         let parts: Vec<&str> = input.split("|").collect();
-        // if there are 2 parts in the rule, we expect the description of the redirect
+        // if there are 3 parts in the rule, we expect the description of the redirect
+        // or the list of the headers for other statuses
         let (remaining, out) = if parts.len() == 3 {
-            // case for redirect
             let part1 = parts[0];
-            // we are expecting redirect, acceptable statuses
-            if part1 != "301" && part1 != "302" {
-                bail!("redirect HTTP status expected 301 or 302");
-            }
-            let redirect = parts[2];
-            if part1 == "301" {
-                (parts[1], Reaction::PermanentRedirect(redirect.to_owned()))
-            } else {
-                (parts[1], Reaction::TemporaryRedirect(redirect.to_owned()))
+            if parts[2].starts_with("ratelimit:") {
+                if part1 != "429" {
+                    bail!("rate limit rule should have 429 status, got {:?}", part1);
+                }
+                return Ok((parts[1].to_string(), Self::parse_rate_limit(parts[2])?));
             }
+            // case for redirect
+            let redirect = parts[2].to_owned();
+            let reaction = match part1 {
+                "301" => Reaction::PermanentRedirect(redirect),
+                "302" => Reaction::TemporaryRedirect(redirect),
+                "307" => Reaction::TemporaryRedirectKeepMethod(redirect),
+                "308" => Reaction::PermanentRedirectKeepMethod(redirect),
+                _ => {
+                    let status = part1.parse::<u16>().context("invalid HTTP status")?;
+                    let headers = Self::parse_headers(parts[2])?;
+                    Reaction::HttpStatusWithHeaders(status, headers)
+                }
+            };
+            (parts[1], reaction)
         } else if parts.len() == 1 {
             (parts[0], Reaction::HttpStatus(200))
         } else {
@@ -174,59 +710,246 @@ impl Reaction {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+// in JSON, each part of the rule is a named field, the optional ones can be omitted
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Rule {
-    #[serde(flatten)]
     pub access: Vec<Access>,
-    #[serde(flatten)]
     pub target: Vec<Target>,
-    #[serde(flatten)]
     pub reaction: Reaction,
-    #[serde(flatten)]
+    #[serde(default)]
     pub tags: Vec<String>,
+    // HTTP methods the rule is limited to, any method if empty
+    #[serde(default)]
+    pub methods: Vec<Method>,
+    // UTC time window when the rule is active, always active if not set
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    // rule is kept in the group, but never reacts
+    #[serde(default)]
+    pub disabled: bool,
+    // free text explaining the rule, after `;;` at the end of the line
+    #[serde(default)]
+    pub note: Option<String>,
+    // where the rule was loaded from, for diagnostics only
+    #[serde(skip)]
+    pub origin: Option<Origin>,
+    // comment and blank lines preceding the rule in the rules file, written back on save
+    #[serde(skip)]
+    pub comments: Vec<String>,
+}
+
+// origin is a diagnostic metadata, the same rule from different lines is still the same rule
+impl PartialEq for Rule {
+    fn eq(&self, other: &Self) -> bool {
+        self.access == other.access
+            && self.target == other.target
+            && self.reaction == other.reaction
+            && self.tags == other.tags
+            && self.methods == other.methods
+            && self.schedule == other.schedule
+            && self.disabled == other.disabled
+            && self.note == other.note
+            && self.comments == other.comments
+    }
+}
+
+// rules of the group combined in a confusing way, reported by the lint
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conflict {
+    // rule comes after another rule matching every request
+    Unreachable { rule: String, by: String },
+    // another rule with the same conditions and different reaction is checked first
+    Shadowed { rule: String, by: String },
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unreachable { rule, by } => write!(f, "{} is never reached after {}", rule, by),
+            Self::Shadowed { rule, by } => {
+                write!(
+                    f,
+                    "{} is never reached, {} has the same conditions",
+                    rule, by
+                )
+            }
+        }
+    }
+}
+
+// location of the rule in the rules file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Origin {
+    pub path: String,
+    pub line: usize,
+}
+
+impl Origin {
+    pub fn new(path: &str, line: usize) -> Self {
+        Self {
+            path: path.to_string(),
+            line,
+        }
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "line {}", self.line)
+        } else {
+            write!(f, "{}:{}", self.path, self.line)
+        }
+    }
+}
+
+// empty rule is the ALLOW-ALL rule
+impl Default for Rule {
+    fn default() -> Self {
+        Self {
+            access: vec![Access::From(Source::Any)],
+            target: vec![Target::Any],
+            reaction: Reaction::HttpStatus(200),
+            tags: vec![],
+            methods: vec![],
+            schedule: None,
+            disabled: false,
+            note: None,
+            origin: None,
+            comments: vec![],
+        }
+    }
 }
 
 impl Rule {
     // function to check if the rule has any access conditions
     // it is typical for redirects not to have any access conditions
-    fn has_access_conditions(&self) -> bool {
-        if self.access.len() == 0 || self.access.len() == 1 {
-            if let Access::From(Source::Any) = self.access[0] {
-                return false;
-            }
-        }
-        true
+    // empty access list is treated as "any source"
+    pub fn has_access_conditions(&self) -> bool {
+        !matches!(self.access.as_slice(), [] | [Access::From(Source::Any)])
     }
 
     // function to check if the rule has any target conditions
     // it is typical for IP-based rules not to have any target URL conditions
+    // empty target list is treated as "any target"
     fn has_target_conditions(&self) -> bool {
-        if self.target.len() == 0 || self.target.len() == 1 {
-            if let Target::Any = self.target[0] {
-                return false;
-            }
+        !matches!(self.target.as_slice(), [] | [Target::Any])
+    }
+
+    // country codes of the rule that are not ISO 3166-1 alpha-2, e.g. UK instead of GB.
+    // Such a rule is still valid, but it never matches
+    pub fn unknown_countries(&self) -> Vec<&str> {
+        self.access
+            .iter()
+            .filter_map(|a| match a {
+                Access::From(Source::FromCountry(c))
+                | Access::Excluding(Source::FromCountry(c)) => Some(c.as_str()),
+                _ => None,
+            })
+            .filter(|c| !crate::countries::is_country_code(c))
+            .collect()
+    }
+
+    // rule that matches every request, regardless of the source or the target
+    fn is_catch_all(&self) -> bool {
+        !self.disabled
+            && !self.has_access_conditions()
+            && !self.has_target_conditions()
+            && self.methods.is_empty()
+            && self.schedule.is_none()
+    }
+
+    // rule that denies every request, regardless of the source or the target
+    fn is_broad_deny(&self) -> bool {
+        self.is_catch_all() && self.reaction.redirect().is_none() && self.reaction.code() != 200
+    }
+
+    // whether the rules match the same requests, their reactions aside
+    fn same_conditions(&self, other: &Rule) -> bool {
+        self.access == other.access
+            && self.target == other.target
+            && self.methods == other.methods
+            && self.schedule == other.schedule
+    }
+
+    fn is_redirect(&self) -> bool {
+        !self.disabled && self.reaction.redirect().is_some()
+    }
+
+    // rule with its location in the file, for the diagnostic messages
+    fn describe(&self) -> String {
+        match &self.origin {
+            Some(origin) => format!("'{}' ({})", self, origin),
+            None => format!("'{}'", self),
         }
-        true
+    }
+
+    // described rule with its kind, for the diagnostic messages
+    fn label(&self) -> String {
+        let kind = if self.is_redirect() {
+            "redirect"
+        } else if self.is_broad_deny() {
+            "broad deny"
+        } else if self.is_catch_all() {
+            "catch-all"
+        } else {
+            "rule"
+        };
+        format!("{} {}", kind, self.describe())
     }
 
     // returns the list of index keys for the rule
     fn index_keys(&self) -> Vec<String> {
         let mut v = vec![];
+        if !self.methods.is_empty()
+            || self.schedule.is_some()
+            || self
+                .target
+                .iter()
+                .any(|t| t.is_request_condition() || t.is_excluding())
+        {
+            // index lookup skips the method, time, host, scheme and excluded path checks
+            return v;
+        }
+        let by_user_agent = |a: &Access| {
+            matches!(
+                a,
+                Access::From(Source::FromUserAgent(_) | Source::FromUserAgentRegex(_))
+                    | Access::Excluding(Source::FromUserAgent(_) | Source::FromUserAgentRegex(_))
+            )
+        };
+        if self.access.iter().any(by_user_agent) {
+            // user agent is not a part of the lookup keys, the rule must be scanned
+            return v;
+        }
         if !self.has_access_conditions() {
+            if !self.target.iter().all(|t| matches!(t, Target::Path(_))) {
+                // rule is found by any of its targets, only the exact paths could be looked up
+                return v;
+            }
+            let mixed_case =
+                |t: &Target| matches!(t, Target::Path(x) if x.chars().any(char::is_uppercase));
+            if self.target.iter().any(mixed_case) {
+                // lookup by the lowercased path wouldn't find it when the case is ignored
+                return v;
+            }
             for t in &self.target {
                 if let Target::Path(x) = t {
-                    if let Some(last_char) = x.chars().last() {
-                        if last_char != '/' {
-                            v.push(format!("{}/", x));
-                        }
-                    }
                     v.push(x.to_string());
+                    if !x.is_empty() && !x.ends_with('/') {
+                        let mut with_slash = String::with_capacity(x.len() + 1);
+                        with_slash.push_str(x);
+                        with_slash.push('/');
+                        v.push(with_slash);
+                    }
                 }
             }
-        } else if !self.has_target_conditions() {
+        } else if self.indexed_by_source() {
             for a in &self.access {
                 if let Access::From(Source::FromIpv4(ip)) = a {
                     v.push(ip.to_string());
+                } else if let Access::From(Source::FromIpv6(ip)) = a {
+                    v.push(ip.to_string());
                 } else if let Access::From(Source::FromCountry(country)) = a {
                     v.push(country.to_string());
                 }
@@ -235,6 +958,48 @@ impl Rule {
         v
     }
 
+    // returns the networks of the sources to look the rule up by the visitor IP
+    fn index_networks(&self) -> Vec<IpNetwork> {
+        if !self.indexed_by_source() {
+            return vec![];
+        }
+        self.access
+            .iter()
+            .filter_map(|a| match a {
+                Access::From(Source::FromIpv4Network(net)) => Some(IpNetwork::V4(*net)),
+                Access::From(Source::FromIpv6Network(net)) => Some(IpNetwork::V6(*net)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // whether the rule is found by the index keys or the networks of the visitor,
+    // and not scanned in the non-indexed list
+    fn is_indexed(&self) -> bool {
+        !self.index_keys().is_empty() || !self.index_networks().is_empty()
+    }
+
+    // rule of IP, network and country sources only, for any target.
+    // Every source must be indexed, as the lookup is the only check of the rule
+    fn indexed_by_source(&self) -> bool {
+        self.methods.is_empty()
+            && self.schedule.is_none()
+            && self.has_access_conditions()
+            && !self.has_target_conditions()
+            && self.access.iter().all(|a| {
+                matches!(
+                    a,
+                    Access::From(
+                        Source::FromIpv4(_)
+                            | Source::FromIpv6(_)
+                            | Source::FromCountry(_)
+                            | Source::FromIpv4Network(_)
+                            | Source::FromIpv6Network(_)
+                    )
+                )
+            })
+    }
+
     /// function to parse the rule from one line string
     /// rule consists of optional reaction, separated by |, access list and target list
     /// to match the rule, any of the source in the access list should be matched
@@ -243,13 +1008,56 @@ impl Rule {
     /// if target list is not specified, it matches any target. Empty rule matches everything.
     ///
     /// Examples of rules:
-    /// ```
+    /// ```text
     /// 200|US,CA,/path/to/resource
     /// 200|US,CA,/path/to/resource#blacklist,recent
     /// 301|-GB,^/path/to/resource|/not-found
+    /// 308|^/api/v1|/api/v2
     /// 403|-US
+    /// !403|-US
+    /// 301|http:|https://{host}{uri}
+    /// 301|//www.example.com|https://{host-www.}{uri}
+    /// 403|host:admin.example.com,/admin
+    /// 403|//*.example.com,^/debug
+    /// 403|?__cf_chl,?utm_source=spam
+    /// 403|~^/user/[0-9]+/settings$
+    /// 403|POST,PUT,/admin
+    /// 403|US,-/public
+    /// 403|/api/*/internal,/static/**.map
+    /// 302|*,@02:00-03:00|/maintenance
+    /// 403|/admin,@18:00-08:00/mon-fri
+    /// 403|ua:bot,ua:~^curl/
+    /// 403|country:GB,city:Ub
+    /// 403|zip:10001,zip:SW1A
+    /// 403|US#legal ;; blocked per legal ticket #42
     /// ```
+    /// Text after `;;` is a note of the rule, it is kept with the rule and never matched.
+    /// Two-letter source is a country and any other unclassified one is a city,
+    /// `country:` and `city:` prefixes make it explicit, e.g. for the city with a two-letter name.
+    /// User agent sources (`ua:` substring regardless of the case, `ua:~` regex) are a part of
+    /// the access list, so `US,ua:bot` matches visitors from US or with "bot" in the User-Agent.
+    /// Uppercase HTTP methods limit the rule to the requests with these methods.
+    /// Time window (`@HH:MM-HH:MM`, UTC, with optional `/mon-fri` or `/sat+sun` weekdays) limits it in time.
+    /// Path regex (`~`) cannot contain `,`, `|`, `#` and `;;`, as they separate parts of the rule.
+    /// Host (`//host` or `host:host`, `*.` prefix for any subdomain) and scheme (`http:`) targets
+    /// must match in addition to any of the path targets.
+    /// Path with `*` or `?` is a glob: `?` is any character, `*` is any part of one path segment,
+    /// `**` spans across segments. Use regex to match literal `*` or `?` in the path.
+    /// Path or query target prefixed with `-` excludes the matching requests from the rule.
+    /// Paths are case-sensitive, unless the server runs with `--paths-ignore-case`;
+    /// a single regex target can be made case-insensitive with `(?i)`, e.g. `~(?i)^/admin`.
+    /// Rule starting with `!` is disabled: it is kept in the group, but never reacts
     pub fn parse(src: &str) -> anyhow::Result<Rule> {
+        let (disabled, src) = match src.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, src),
+        };
+        // note is taken first, so it can contain `#` and `,`
+        let (src, note) = match src.split_once(";;") {
+            Some((src, note)) => (src.trim_end(), Some(note.trim().to_string())),
+            None => (src, None),
+        };
+        let note = note.filter(|n| !n.is_empty());
         let mut tags = vec![];
         let with_tags: Vec<&str> = src.split("#").collect();
         let remains = if with_tags.len() > 1 {
@@ -261,18 +1069,27 @@ impl Rule {
         let (input, reaction) = Reaction::extract(remains)?;
         let mut access = vec![];
         let mut target = vec![];
+        let mut methods = vec![];
+        let mut schedule = None;
         for part in input.split(",") {
-            if part.starts_with("/") || part.starts_with("^") {
-                target.push(Target::parse(part));
+            if let Some(method) = Method::parse(part) {
+                methods.push(method);
+            } else if let Some(window) = part.strip_prefix('@') {
+                if schedule.is_some() {
+                    bail!("only one time window is allowed");
+                }
+                schedule = Some(Schedule::parse(window)?);
+            } else if is_target(part) {
+                target.push(Target::parse(part)?);
             } else {
-                access.push(Access::parse(part));
+                access.push(Access::parse(part)?);
             }
         }
         // empty strings turn it into the ALLOW-ALL rule
-        if access.len() == 0 {
+        if access.is_empty() {
             access.push(Access::From(Source::Any));
         }
-        if target.len() == 0 {
+        if target.is_empty() {
             target.push(Target::Any);
         }
         Ok(Self {
@@ -280,95 +1097,89 @@ impl Rule {
             target,
             reaction,
             tags,
+            methods,
+            schedule,
+            disabled,
+            note,
+            origin: None,
+            comments: vec![],
         })
     }
 
-    // function to convert rule to string representation
-    pub fn to_string(&self) -> String {
-        let mut out = Vec::<String>::new();
-        if self.reaction.code() != 200 {
-            out.push(self.reaction.code().to_string());
-        };
-        let mut parts = Vec::<String>::new();
-        if self.has_access_conditions() {
-            for access in &self.access {
-                let a = access.to_string();
-                if a.len() > 0 {
-                    parts.push(a);
-                }
-            }
+    // function to validate the Rule against Visitor
+    pub fn react<V: Visitor>(&self, v: &V) -> Option<Reaction> {
+        if self.disabled {
+            return None;
         }
-        for target in &self.target {
-            let t = target.to_string();
-            if t.len() > 0 {
-                parts.push(t);
+        if let Some(schedule) = &self.schedule {
+            if !schedule.contains(v.time()) {
+                return None;
             }
         }
-        // if parts.len() > 0 {
-        out.push(parts.join(","));
-        // }
-        if let Some(redirect) = self.reaction.redirect() {
-            out.push(redirect);
+        if !self.methods.is_empty() {
+            let method = v.method().unwrap_or_default();
+            if !self
+                .methods
+                .iter()
+                .any(|m| m.as_str().eq_ignore_ascii_case(&method))
+            {
+                return None;
+            }
         }
-        let mut out_str = out.join("|");
-        if self.tags.len() > 0 {
-            out_str.push_str("#");
-            out_str.push_str(&self.tags.join(","));
+        let mut out = None;
+
+        for t in self.target.iter().filter(|t| t.is_request_condition()) {
+            let matched = match t {
+                Target::Host(host) => v.host().map(|h| host_matches(host, &h)),
+                Target::Scheme(scheme) => v.scheme().map(|s| s.eq_ignore_ascii_case(scheme)),
+                _ => None,
+            };
+            if matched != Some(true) {
+                return None;
+            }
         }
-        // let index_keys = self.index_keys();
-        // if index_keys.len() > 0 {
-        //     out_str.push_str("---");
-        //     out_str.push_str(&index_keys.join(","));
-        // }
-        out_str
-    }
 
-    // function to validate the Rule against Visitor
-    pub fn react<V: Visitor>(&self, v: &V) -> Option<Reaction> {
-        let mut out = None;
-
-        let mut match_target = false;
-        if self.target.len() > 0 {
-            // if rule is target-specific, we should check each URL, otherwise continue
-            for t in &self.target {
-                match t {
-                    Target::Path(path) => {
-                        if v.uri() == *path || v.uri() == format!("{}/", *path) {
-                            match_target = true;
-                            break;
-                        }
-                    }
-                    Target::PathPrefix(prefix) => {
-                        if v.uri().starts_with(prefix) {
-                            match_target = true;
-                            break;
-                        }
-                    }
-                    Target::Any => {
-                        match_target = true;
-                        break;
-                    }
-                }
-                if match_target {
-                    continue;
-                }
-            }
-        } else {
-            match_target = true; // no target rules - we have match
-        }
-        if !match_target {
-            return None;
-        }
+        // excluded path turns the whole rule off
+        if self
+            .target
+            .iter()
+            .any(|t| t.is_excluding() && t.matches_path(v))
+        {
+            return None;
+        }
+        let mut paths = self
+            .target
+            .iter()
+            .filter(|t| !t.is_request_condition() && !t.is_excluding())
+            .peekable();
+        // if rule is target-specific, we should check each URL, otherwise we have match
+        let match_target = paths.peek().is_none() || paths.any(|t| t.matches_path(v));
+        if !match_target {
+            return None;
+        }
 
         for access in &self.access {
             match access {
                 Access::From(source) => {
                     let result = match source {
                         Source::Any => true,
-                        Source::FromIpv4(ip) => v.ip() == *ip,
-                        Source::FromIpv4Network(net) => net.contains(v.ip()),
+                        Source::FromIpv4(ip) => v.ip() == IpAddr::V4(*ip),
+                        Source::FromIpv4Network(net) => {
+                            matches!(v.ip(), IpAddr::V4(ip) if net.contains(ip))
+                        }
+                        Source::FromIpv6(ip) => v.ip() == IpAddr::V6(*ip),
+                        Source::FromIpv6Network(net) => {
+                            matches!(v.ip(), IpAddr::V6(ip) if net.contains(ip))
+                        }
                         Source::FromCountry(country) => v.country() == Some(country.to_string()),
                         Source::FromCity(city) => v.city() == Some(city.to_string()),
+                        Source::FromSubdivision(code) => v.subdivision() == Some(code.to_string()),
+                        Source::FromPostal(code) => v.postal() == Some(code.to_string()),
+                        Source::FromAsn(asn) => v.asn() == Some(*asn),
+                        Source::GeoUnknown => v.geo_unknown(),
+                        Source::FromUserAgent(_) | Source::FromUserAgentRegex(_) => {
+                            source.matches_user_agent(v)
+                        }
                     };
                     if result {
                         out = Some(self.reaction.clone());
@@ -377,10 +1188,23 @@ impl Rule {
                 Access::Excluding(source) => {
                     let result = match source {
                         Source::Any => false,
-                        Source::FromIpv4(ip) => v.ip() == *ip,
-                        Source::FromIpv4Network(net) => net.contains(v.ip()),
+                        Source::FromIpv4(ip) => v.ip() == IpAddr::V4(*ip),
+                        Source::FromIpv4Network(net) => {
+                            matches!(v.ip(), IpAddr::V4(ip) if net.contains(ip))
+                        }
+                        Source::FromIpv6(ip) => v.ip() == IpAddr::V6(*ip),
+                        Source::FromIpv6Network(net) => {
+                            matches!(v.ip(), IpAddr::V6(ip) if net.contains(ip))
+                        }
                         Source::FromCountry(country) => v.country() == Some(country.to_string()),
                         Source::FromCity(city) => v.city() == Some(city.to_string()),
+                        Source::FromSubdivision(code) => v.subdivision() == Some(code.to_string()),
+                        Source::FromPostal(code) => v.postal() == Some(code.to_string()),
+                        Source::FromAsn(asn) => v.asn() == Some(*asn),
+                        Source::GeoUnknown => v.geo_unknown(),
+                        Source::FromUserAgent(_) | Source::FromUserAgentRegex(_) => {
+                            source.matches_user_agent(v)
+                        }
                     };
                     if result {
                         out = None;
@@ -392,16 +1216,102 @@ impl Rule {
     }
 }
 
+// decoded query parameters of the visit
+fn query_pairs<V: Visitor>(v: &V) -> Vec<(String, String)> {
+    let query = v.query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+impl std::fmt::Display for Rule {
+    // function to convert rule to string representation
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.disabled {
+            write!(f, "!")?;
+        }
+        let mut out = Vec::<String>::new();
+        if self.reaction.code() != 200 || !self.reaction.headers().is_empty() {
+            out.push(self.reaction.code().to_string());
+        };
+        let mut parts = Vec::<String>::new();
+        if self.has_access_conditions() {
+            for access in &self.access {
+                let a = access.to_string();
+                if !a.is_empty() {
+                    parts.push(a);
+                }
+            }
+        }
+        parts.extend(self.methods.iter().map(Method::to_string));
+        for target in &self.target {
+            let t = target.to_string();
+            if !t.is_empty() {
+                parts.push(t);
+            }
+        }
+        parts.extend(self.schedule.iter().map(Schedule::to_string));
+        // if parts.len() > 0 {
+        out.push(parts.join(","));
+        // }
+        if let Some(redirect) = self.reaction.redirect() {
+            out.push(redirect);
+        }
+        if let Reaction::RateLimit { rpm, burst } = &self.reaction {
+            out.push(format!("ratelimit:{}:{}", rpm, burst));
+        }
+        let headers = self.reaction.headers();
+        if !headers.is_empty() {
+            let list: Vec<String> = headers
+                .iter()
+                .map(|(k, v)| format!("{}:{}", k, v))
+                .collect();
+            out.push(list.join(";"));
+        }
+        let mut out_str = out.join("|");
+        if !self.tags.is_empty() {
+            out_str.push('#');
+            out_str.push_str(&self.tags.join(","));
+        }
+        if let Some(note) = &self.note {
+            out_str.push_str(" ;; ");
+            out_str.push_str(note);
+        }
+        // let index_keys = self.index_keys();
+        // if index_keys.len() > 0 {
+        //     out_str.push_str("---");
+        //     out_str.push_str(&index_keys.join(","));
+        // }
+        write!(f, "{}", out_str)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct SecurityGroup {
     // alphanumeric name
     pub name: String,
-    // map of quick rule reqction
-    pub map_indexed: Map<String, Reaction>,
+    // map of quick rule lookup, pointing to the position in list_indexed
+    pub map_indexed: Map<String, usize>,
+    // quick rule lookup by the networks of the sources
+    #[serde(skip)]
+    map_networks: NetworkIndex,
     // list of the rules that could be searched
     list_indexed: Vec<Rule>,
     // list of rules that
     list_non_indexed: Vec<Rule>,
+    // position of each rule in the file, rules are evaluated in this order
+    order_indexed: Vec<usize>,
+    order_non_indexed: Vec<usize>,
+    // position of the next added rule
+    next_order: usize,
+    // huge IP deny lists, stored without building the rules
+    #[serde(skip)]
+    compact: Option<CompactDenyList>,
+    // comment and blank lines after the last rule of the file
+    trailing_comments: Vec<String>,
+    // reaction when none of the rules matches, `!default 403` line of the file. Allowed if not set
+    #[serde(default)]
+    pub default_reaction: Option<Reaction>,
 }
 
 impl std::fmt::Debug for SecurityGroup {
@@ -410,12 +1320,34 @@ impl std::fmt::Debug for SecurityGroup {
         let mut out = f.debug_struct("SecurityGroup");
         out.field("name", &self.name);
         out.field("map_indexed", &self.map_indexed.len());
+        out.field("map_networks", &self.map_networks.len());
         out.field("list_indexed", &self.list_indexed.len());
         out.field("list_non_indexed", &self.list_non_indexed.len());
+        out.field("compact", &self.compact);
         out.finish()
     }
 }
 
+// writes to a temporary file in the same directory and renames it over the destination,
+// so the destination is never left half-written
+pub fn atomic_write<F>(path: &str, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut File) -> anyhow::Result<()>,
+{
+    let tmp = format!("{}.{}.tmp", path, std::process::id());
+    let result = File::create(&tmp)
+        .context("create temp file")
+        .and_then(|mut f| {
+            write(&mut f)?;
+            f.sync_all().context("sync temp file")
+        })
+        .and_then(|_| std::fs::rename(&tmp, path).context("rename temp file"));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
 // contructor implementation
 impl SecurityGroup {
     pub fn new(name: &str) -> Self {
@@ -423,15 +1355,51 @@ impl SecurityGroup {
             name: name.to_string(),
             list_indexed: vec![],
             list_non_indexed: vec![],
+            order_indexed: vec![],
+            order_non_indexed: vec![],
+            next_order: 0,
             map_indexed: Map::new(),
+            map_networks: NetworkIndex::default(),
+            compact: None,
+            trailing_comments: vec![],
+            default_reaction: None,
         }
     }
+
+    // parses the status of the `!default` line
+    fn parse_default(input: &str) -> anyhow::Result<Reaction> {
+        let code = input
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|code| (100..=599).contains(code))
+            .with_context(|| format!("invalid default status {:?}", input))?;
+        Ok(Reaction::HttpStatus(code))
+    }
 }
 
 // management implementation
 impl SecurityGroup {
     pub fn count(&self) -> usize {
-        self.list_indexed.len() + self.list_non_indexed.len()
+        let compact = self.compact.as_ref().map(|c| c.len()).unwrap_or(0);
+        self.list_indexed.len() + self.list_non_indexed.len() + compact
+    }
+
+    pub fn compact(&self) -> Option<&CompactDenyList> {
+        self.compact.as_ref()
+    }
+
+    // converts compact deny list into regular rules, so the group could be modified
+    pub fn expand(&mut self) {
+        if let Some(compact) = self.compact.take() {
+            let mut comments = compact.comments.clone();
+            for rule in compact.rules() {
+                self.add(Rule {
+                    comments: std::mem::take(&mut comments),
+                    ..rule
+                });
+            }
+        }
     }
 
     pub fn list_indexed(&self) -> impl Iterator<Item = &Rule> {
@@ -442,15 +1410,157 @@ impl SecurityGroup {
         self.list_non_indexed.iter()
     }
 
+    /// non-indexed rules with their positions in the file
+    pub fn list_non_indexed_ordered(&self) -> impl Iterator<Item = (usize, &Rule)> {
+        self.order_non_indexed
+            .iter()
+            .copied()
+            .zip(&self.list_non_indexed)
+    }
+
+    /// all rules in the order of evaluation, which is the order of the file
+    pub fn list_ordered(&self) -> impl Iterator<Item = &Rule> {
+        let mut all: Vec<(usize, &Rule)> = self
+            .order_indexed
+            .iter()
+            .copied()
+            .zip(&self.list_indexed)
+            .chain(self.list_non_indexed_ordered())
+            .collect();
+        all.sort_by_key(|(order, _)| *order);
+        all.into_iter().map(|(_, r)| r)
+    }
+
+    // position in the file of the rule by its index
+    fn order_of(&self, index: usize) -> Option<usize> {
+        if index < self.order_indexed.len() {
+            self.order_indexed.get(index).copied()
+        } else {
+            self.order_non_indexed
+                .get(index - self.order_indexed.len())
+                .copied()
+        }
+    }
+
+    /// amount of rules per tag, sorted by the tag, a rule with several tags is counted for each of them
+    pub fn count_tags(&self) -> Map<String, usize> {
+        let mut out = Map::new();
+        for rule in self.list_indexed().chain(self.list_non_indexed()) {
+            for tag in &rule.tags {
+                *out.entry(tag.clone()).or_insert(0) += 1;
+            }
+        }
+        out
+    }
+
     pub fn add(&mut self, r: Rule) {
+        self.expand();
+        self.insert(self.next_order, r);
+    }
+
+    // puts the rule at its position in the order of evaluation
+    fn insert(&mut self, order: usize, r: Rule) {
+        self.next_order = self.next_order.max(order + 1);
         let keys = r.index_keys();
-        if keys.len() > 0 {
+        let networks = r.index_networks();
+        if !keys.is_empty() || !networks.is_empty() {
+            let position = self.order_indexed.partition_point(|o| *o < order);
+            self.list_indexed.insert(position, r);
+            self.order_indexed.insert(position, order);
+            if position + 1 < self.list_indexed.len() || self.list_indexed[position].disabled {
+                self.reindex();
+                return;
+            }
+            // the earlier rule keeps the key, as it is checked first
             for key in keys {
-                self.map_indexed.insert(key, r.reaction.clone());
+                self.map_indexed.entry(key).or_insert(position);
+            }
+            for net in networks {
+                self.map_networks.insert(net, position);
             }
-            self.list_indexed.push(r);
         } else {
-            self.list_non_indexed.push(r);
+            let position = self.order_non_indexed.partition_point(|o| *o < order);
+            self.list_non_indexed.insert(position, r);
+            self.order_non_indexed.insert(position, order);
+        }
+    }
+
+    /// whether the group has the same rule already, regardless of its comments
+    pub fn contains(&self, r: &Rule) -> bool {
+        let same = |x: &&Rule| {
+            x.access == r.access
+                && x.target == r.target
+                && x.reaction == r.reaction
+                && x.tags == r.tags
+                && x.methods == r.methods
+                && x.schedule == r.schedule
+                && x.disabled == r.disabled
+        };
+        self.list_indexed().any(|x| same(&x)) || self.list_non_indexed().any(|x| same(&x))
+    }
+
+    /// adds the rules, returning their indexes as addressed by `set_by_index` and `remove_by_index`.
+    /// Indexed rules come before the non-indexed ones, so the indexes are final only after all rules are added.
+    /// With `dedupe`, rules that are already in the group are skipped
+    pub fn append(&mut self, rules: Vec<Rule>, dedupe: bool) -> Vec<usize> {
+        self.expand();
+        let mut positions = vec![];
+        for r in rules {
+            if dedupe && self.contains(&r) {
+                continue;
+            }
+            positions.push(match r.is_indexed() {
+                true => (true, self.list_indexed.len()),
+                false => (false, self.list_non_indexed.len()),
+            });
+            self.add(r);
+        }
+        positions
+            .into_iter()
+            .map(|(indexed, p)| {
+                if indexed {
+                    p
+                } else {
+                    self.list_indexed.len() + p
+                }
+            })
+            .collect()
+    }
+
+    // returns indexed rule by its quick lookup key
+    #[allow(dead_code)]
+    pub fn get_indexed(&self, key: &str) -> Option<&Rule> {
+        self.get_indexed_ordered(key).map(|(_, r)| r)
+    }
+
+    // returns indexed rule by its quick lookup key, with its position in the file
+    pub fn get_indexed_ordered(&self, key: &str) -> Option<(usize, &Rule)> {
+        let position = *self.map_indexed.get(key)?;
+        let rule = self.list_indexed.get(position)?;
+        Some((self.order_indexed[position], rule))
+    }
+
+    // returns the earliest indexed rule with a network containing the IP, with its position in the file
+    pub fn get_network_ordered(&self, ip: IpAddr) -> Option<(usize, &Rule)> {
+        let position = self.map_networks.get(ip)?;
+        let rule = self.list_indexed.get(position)?;
+        Some((self.order_indexed[position], rule))
+    }
+
+    // rebuilds quick lookup maps, as positions in list_indexed could have been shifted
+    fn reindex(&mut self) {
+        self.map_indexed = Map::new();
+        self.map_networks = NetworkIndex::default();
+        for (position, rule) in self.list_indexed.iter().enumerate() {
+            if rule.disabled {
+                continue;
+            }
+            for key in rule.index_keys() {
+                self.map_indexed.entry(key).or_insert(position);
+            }
+            for net in rule.index_networks() {
+                self.map_networks.insert(net, position);
+            }
         }
     }
 
@@ -458,40 +1568,35 @@ impl SecurityGroup {
     pub fn reset(&mut self) {
         self.list_indexed = vec![];
         self.list_non_indexed = vec![];
+        self.order_indexed = vec![];
+        self.order_non_indexed = vec![];
+        self.next_order = 0;
         self.map_indexed = Map::new();
+        self.map_networks = NetworkIndex::default();
+        self.compact = None;
     }
 
-    /// remove just one rule by index
+    /// remove just one rule by index, indexes out of range are ignored
     pub fn remove_by_index(&mut self, index: usize) {
-        if index < self.list_indexed.len() {
-            self.remove_many(vec![index].into_iter())
-        } else {
-            let real_index = index - self.list_indexed.len();
-            self.list_non_indexed.remove(real_index);
-        }
+        self.remove_many(std::iter::once(index))
     }
 
     #[instrument]
     pub fn remove_many(&mut self, indexes: impl Iterator<Item = usize> + std::fmt::Debug) {
-        // remove the items from the map
-
+        self.expand();
         let mut idx_indexed: Vec<usize> = vec![];
         let mut idx_non_indexed: Vec<usize> = vec![];
         for index in indexes {
             if index < self.list_indexed.len() {
-                if let Some(rule) = self.list_indexed.get(index) {
-                    for key in rule.index_keys() {
-                        self.map_indexed.remove(&key);
-                    }
-                    idx_indexed.push(index);
-                }
+                idx_indexed.push(index);
             } else {
                 idx_non_indexed.push(index - self.list_indexed.len());
             }
         }
         // replace list_indexed with the new list, skipping indexes
-        if idx_indexed.len() > 0 {
+        if !idx_indexed.is_empty() {
             let mut new_list_indexed = vec![];
+            let mut new_order_indexed = vec![];
             for (index, rule) in self.list_indexed.iter().enumerate() {
                 let mut skip = false;
                 for i in &idx_indexed {
@@ -502,14 +1607,18 @@ impl SecurityGroup {
                 }
                 if !skip {
                     new_list_indexed.push(rule.clone());
+                    new_order_indexed.push(self.order_indexed[index]);
                 }
             }
             self.list_indexed = new_list_indexed;
+            self.order_indexed = new_order_indexed;
+            self.reindex();
         }
-        // replace list_indexed with the new list, skipping indexes
-        if idx_non_indexed.len() > 0 {
+        // replace list_non_indexed with the new list, skipping indexes
+        if !idx_non_indexed.is_empty() {
             let mut new_list_non_indexed = vec![];
-            for (index, rule) in self.list_indexed.iter().enumerate() {
+            let mut new_order_non_indexed = vec![];
+            for (index, rule) in self.list_non_indexed.iter().enumerate() {
                 let mut skip = false;
                 for i in &idx_non_indexed {
                     if *i == index {
@@ -519,61 +1628,230 @@ impl SecurityGroup {
                 }
                 if !skip {
                     new_list_non_indexed.push(rule.clone());
+                    new_order_non_indexed.push(self.order_non_indexed[index]);
                 }
             }
             self.list_non_indexed = new_list_non_indexed;
+            self.order_non_indexed = new_order_non_indexed;
         }
     }
 
-    pub fn set_by_index(&mut self, index: usize, r: Rule) {
-        if index < self.list_indexed.len() {
-            self.remove_many(vec![index].into_iter())
+    /// replaces the rule by index, the new rule takes the place of the replaced one in the file.
+    /// Comments of the replaced rule are kept, unless the new rule has its own
+    pub fn set_by_index(&mut self, index: usize, mut r: Rule) {
+        self.expand();
+        let order = self.order_of(index);
+        let replaced = if index < self.list_indexed.len() {
+            self.list_indexed.get(index)
+        } else {
+            self.list_non_indexed.get(index - self.list_indexed.len())
+        };
+        if let Some(replaced) = replaced.filter(|_| r.comments.is_empty()) {
+            r.comments = replaced.comments.clone();
+        }
+        self.remove_by_index(index);
+        match order {
+            Some(order) => self.insert(order, r),
+            None => self.add(r),
+        }
+    }
+
+    /// toggles rule by index, returns whether the rule is disabled now
+    pub fn toggle_by_index(&mut self, index: usize) -> Option<bool> {
+        self.expand();
+        let rule = if index < self.list_indexed.len() {
+            self.list_indexed.get_mut(index)
         } else {
-            let real_index = index - self.list_indexed.len();
-            self.list_non_indexed.remove(real_index);
+            self.list_non_indexed
+                .get_mut(index - self.list_indexed.len())
+        }?;
+        rule.disabled = !rule.disabled;
+        let disabled = rule.disabled;
+        if index < self.list_indexed.len() {
+            // disabled rules give their index keys to the next rules with the same keys
+            self.reindex();
         }
-        self.add(r);
+        Some(disabled)
+    }
+
+    /// renames the tag on every rule having it, returns the number of such rules.
+    /// Tags are not a part of the index keys, so rules stay where they are
+    pub fn rename_tag(&mut self, from: &str, to: &str) -> usize {
+        let mut count = 0;
+        for rule in self
+            .list_indexed
+            .iter_mut()
+            .chain(self.list_non_indexed.iter_mut())
+        {
+            if !rule.tags.iter().any(|t| t == from) {
+                continue;
+            }
+            let has_new = rule.tags.iter().any(|t| t == to);
+            let mut renamed = false;
+            rule.tags.retain_mut(|t| {
+                if t != from {
+                    return true;
+                }
+                // the first one takes the new name, unless the rule has it already
+                let keep = !has_new && !renamed;
+                if keep {
+                    *t = to.to_string();
+                    renamed = true;
+                }
+                keep
+            });
+            count += 1;
+        }
+        count
     }
 
     pub fn set_many(&mut self, indexes: impl Iterator<Item = usize> + std::fmt::Debug, r: Rule) {
-        self.remove_many(indexes);
-        self.add(r.clone());
+        self.expand();
+        let indexes: Vec<usize> = indexes.collect();
+        // the new rule takes the place of the first replaced one
+        let order = indexes.iter().filter_map(|i| self.order_of(*i)).min();
+        self.remove_many(indexes.into_iter());
+        match order {
+            Some(order) => self.insert(order, r),
+            None => self.add(r),
+        }
     }
 }
 
 impl SecurityGroup {
     // writes security group to the writer, using rule writer, one rule at a line
+    // comments are written before their rules, comments of the compact groups on top of them
     pub fn to_writer<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
-        for rule in &self.list_indexed {
-            writeln!(w, "{}", rule.to_string())?;
+        if let Some(reaction) = &self.default_reaction {
+            writeln!(w, "!default {}", reaction.code())?;
         }
-        for rule in &self.list_non_indexed {
-            writeln!(w, "{}", rule.to_string())?;
+        for rule in self.list_ordered() {
+            for comment in &rule.comments {
+                writeln!(w, "{}", comment)?;
+            }
+            writeln!(w, "{}", rule)?;
+        }
+        if let Some(compact) = &self.compact {
+            for comment in &compact.comments {
+                writeln!(w, "{}", comment)?;
+            }
+            for rule in compact.rules() {
+                writeln!(w, "{}", rule)?;
+            }
+        }
+        for comment in &self.trailing_comments {
+            writeln!(w, "{}", comment)?;
         }
         Ok(())
     }
 
     // save to local file
     pub fn save_to_file(&self, path: &str) -> anyhow::Result<()> {
-        let mut f = File::create(path)?;
-        self.to_writer(&mut f)?;
-        Ok(())
+        atomic_write(path, |f| self.to_writer(f))
     }
 
     // reads rules from reader, one rule per line
     pub fn from_reader<R: Read>(name: &str, r: &mut R) -> Self {
+        Self::from_source(name, "", r)
+    }
+
+    // reads rules from reader, one rule per line, warning about the lines that cannot be parsed
+    pub fn from_source<R: Read>(name: &str, path: &str, r: &mut R) -> Self {
+        let (out, errors) = Self::parse_source(name, path, r);
+        for (origin, e) in errors {
+            warn!("{}: {:?}", origin, e);
+        }
+        for lint in out.lint() {
+            warn!("group {}: {}", name, lint);
+        }
+        out
+    }
+
+    // reads rules from reader, one rule per line, keeping the origin of each rule.
+    // returns the group and errors for the lines that cannot be parsed
+    pub fn parse_source<R: Read>(
+        name: &str,
+        path: &str,
+        r: &mut R,
+    ) -> (Self, Vec<(Origin, anyhow::Error)>) {
         let mut out = Self::new(name);
-        let lines = BufReader::new(r).lines();
-        for next_line in lines {
-            if let Ok(line) = next_line {
-                let ln = line.trim();
-                // skipping empty lines and comments
-                if ln.len() > 0 && !ln.starts_with("#") {
-                    match Rule::parse(ln) {
-                        Ok(rule) => out.add(rule),
-                        Err(e) => warn!("{:?}", e),
-                    };
+        let mut errors = vec![];
+        // unreadable lines, e.g. not UTF-8, are reported and skipped, the next lines are still read
+        let mut lines = vec![];
+        for (n, line) in BufReader::new(r).lines().enumerate() {
+            match line {
+                Ok(line) => lines.push(Some(line)),
+                Err(e) => {
+                    errors.push((Origin::new(path, n + 1), e.into()));
+                    lines.push(None);
+                }
+            }
+        }
+        if let Some(compact) = CompactDenyList::from_lines(lines.iter().flatten()) {
+            info!("group {}: {} rules in compact form", name, compact.len());
+            out.compact = Some(compact);
+            return (out, errors);
+        }
+        // empty lines and comments are kept with the following rule
+        let mut comments = vec![];
+        for (n, line) in lines.iter().enumerate() {
+            let Some(line) = line else {
+                continue;
+            };
+            let ln = line.trim();
+            if ln.is_empty() || ln.starts_with('#') {
+                comments.push(ln.to_string());
+                continue;
+            }
+            let origin = Origin::new(path, n + 1);
+            if let Some(status) = ln.strip_prefix("!default ") {
+                match Self::parse_default(status) {
+                    Ok(reaction) => out.default_reaction = Some(reaction),
+                    Err(e) => errors.push((origin, e)),
                 }
+                continue;
+            }
+            match Rule::parse(ln) {
+                Ok(rule) => {
+                    for country in rule.unknown_countries() {
+                        warn!("{}: unknown country code {:?}", origin, country);
+                    }
+                    out.add(Rule {
+                        origin: Some(origin),
+                        comments: std::mem::take(&mut comments),
+                        ..rule
+                    })
+                }
+                Err(e) => errors.push((origin, e)),
+            };
+        }
+        out.trailing_comments = comments;
+        (out, errors)
+    }
+
+    // returns the rules that are never reached: the rules after a catch-all rule,
+    // and the rules after another one with the same conditions but a different reaction
+    pub fn lint(&self) -> Vec<Conflict> {
+        let rules: Vec<&Rule> = self.list_ordered().collect();
+        let mut out = vec![];
+        for (i, r) in rules.iter().enumerate() {
+            if r.disabled {
+                continue;
+            }
+            // the first rule that catches the requests of this one
+            let by = rules[..i]
+                .iter()
+                .find(|x| !x.disabled && (x.is_catch_all() || x.same_conditions(r)));
+            match by {
+                Some(by) if by.is_catch_all() => out.push(Conflict::Unreachable {
+                    rule: r.label(),
+                    by: by.label(),
+                }),
+                Some(by) if by.reaction != r.reaction => out.push(Conflict::Shadowed {
+                    rule: r.label(),
+                    by: by.label(),
+                }),
+                _ => {}
             }
         }
         out
@@ -582,7 +1860,7 @@ impl SecurityGroup {
     // load from local file
     pub fn from_file(name: &str, path: &str) -> anyhow::Result<Self> {
         let mut f = File::open(path)?;
-        Ok(Self::from_reader(name, &mut f))
+        Ok(Self::from_source(name, path, &mut f))
     }
 }
 
@@ -613,6 +1891,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -621,6 +1900,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -629,6 +1909,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(403),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -637,6 +1918,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(500),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -645,6 +1927,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(500),
             tags: vec!["blacklist".to_owned()],
+            ..Default::default()
         }),
     }
 
@@ -654,6 +1937,76 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(401),
             tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        host_and_scheme : ("301|http:,//www.example.com|https://{host-www.}{uri}", Rule {
+            access: vec![Access::From(Source::Any)],
+            target: vec![Target::Scheme("http".to_owned()), Target::Host("www.example.com".to_owned())],
+            reaction: Reaction::PermanentRedirect("https://{host-www.}{uri}".to_owned()),
+            tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        query_targets : ("403|?__cf_chl,?utm_source=spam", Rule {
+            access: vec![Access::From(Source::Any)],
+            target: vec![
+                Target::QueryPresent("__cf_chl".to_owned()),
+                Target::Query("utm_source".to_owned(), "spam".to_owned()),
+            ],
+            reaction: Reaction::HttpStatus(403),
+            tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        ipv6 : ("403|2001:db8::1", Rule {
+            access: vec![Access::From(Source::FromIpv6("2001:db8::1".parse().unwrap()))],
+            target: vec![Target::Any],
+            reaction: Reaction::HttpStatus(403),
+            tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        ipv6network : ("403|2001:db8::/32,/admin", Rule {
+            access: vec![Access::From(Source::FromIpv6Network("2001:db8::/32".parse().unwrap()))],
+            target: vec![Target::Path("/admin".to_owned())],
+            reaction: Reaction::HttpStatus(403),
+            tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        status_with_headers : ("403|US|X-Reason:blocked;X-Team:sec", Rule {
+            access: vec![Access::From(Source::FromCountry("US".to_owned()))],
+            target: vec![Target::Any],
+            reaction: Reaction::HttpStatusWithHeaders(403, vec![
+                ("X-Reason".to_owned(), "blocked".to_owned()),
+                ("X-Team".to_owned(), "sec".to_owned()),
+            ]),
+            tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        rate_limit : ("429|US|ratelimit:60:10#ratelimit", Rule {
+            access: vec![Access::From(Source::FromCountry("US".to_owned()))],
+            target: vec![Target::Any],
+            reaction: Reaction::RateLimit { rpm: 60, burst: 10 },
+            tags: vec!["ratelimit".to_owned()],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        asn : ("403|AS13335", Rule {
+            access: vec![Access::From(Source::FromAsn(13335))],
+            target: vec![Target::Any],
+            reaction: Reaction::HttpStatus(403),
+            tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -662,6 +2015,7 @@ pub mod tests {
             target: vec![Target::Path("/api/metrics".to_owned())],
             reaction: Reaction::PermanentRedirect("/metrics".to_owned()),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -670,6 +2024,25 @@ pub mod tests {
             target: vec![Target::Path("/api/metrics".to_owned())],
             reaction: Reaction::TemporaryRedirect("/metrics".to_owned()),
             tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        temp_redirect_keep_method : ("307|/api/v1/upload|/api/v2/upload", Rule {
+            access: vec![Access::From(Source::Any)],
+            target: vec![Target::Path("/api/v1/upload".to_owned())],
+            reaction: Reaction::TemporaryRedirectKeepMethod("/api/v2/upload".to_owned()),
+            tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        permanent_redirect_keep_method : ("308|^/api/v1|/api/v2", Rule {
+            access: vec![Access::From(Source::Any)],
+            target: vec![Target::PathPrefix("/api/v1".to_owned())],
+            reaction: Reaction::PermanentRedirectKeepMethod("/api/v2".to_owned()),
+            tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -678,6 +2051,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -686,6 +2060,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -694,6 +2069,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -702,6 +2078,7 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
         }),
     }
 
@@ -711,6 +2088,26 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        geo_unknown : ("403|geo-unknown", Rule {
+            access: vec![Access::From(Source::GeoUnknown)],
+            target: vec![Target::Any],
+            reaction: Reaction::HttpStatus(403),
+            tags: vec![],
+            ..Default::default()
+        }),
+    }
+    test_rule! {
+        disabled : ("!403|US", Rule {
+            access: vec![Access::From(Source::FromCountry("US".to_owned()))],
+            target: vec![Target::Any],
+            reaction: Reaction::HttpStatus(403),
+            tags: vec![],
+            disabled: true,
+            ..Default::default()
         }),
     }
     test_rule! {
@@ -723,9 +2120,689 @@ pub mod tests {
             target: vec![Target::Any],
             reaction: Reaction::HttpStatus(200),
             tags: vec![],
+            ..Default::default()
         }),
     }
 
+    #[test]
+    fn test_disabled_round_trip() {
+        let r = Rule::parse("!403|US,/admin#temp").unwrap();
+        assert!(r.disabled);
+        assert_eq!(r.to_string(), "!403|US,/admin#temp");
+        assert_eq!(Rule::parse("/admin").unwrap().to_string(), "/admin");
+    }
+
+    #[test]
+    fn test_parse_warning_origin() {
+        let source = ["403|ES", "", "30x|/a|/b", "# comment", "401|-JP"].join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let (sg, errors) = SecurityGroup::parse_source("default", "data/default.rules.txt", &mut r);
+        assert_eq!(sg.count(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0.to_string(), "data/default.rules.txt:3");
+        let origins: Vec<usize> = sg
+            .list_indexed()
+            .chain(sg.list_non_indexed())
+            .map(|r| r.origin.as_ref().unwrap().line)
+            .collect();
+        assert_eq!(origins, vec![1, 5]);
+    }
+
+    #[test]
+    fn test_rule_eq_ignores_origin() {
+        let a = Rule {
+            origin: Some(Origin::new("a.rules.txt", 1)),
+            ..Rule::parse("403|US").unwrap()
+        };
+        let b = Rule {
+            origin: Some(Origin::new("b.rules.txt", 7)),
+            ..a.clone()
+        };
+        assert_eq!(a, b);
+        assert_ne!(a.origin, b.origin);
+        assert_ne!(a, Rule::parse("401|US").unwrap());
+    }
+
+    #[test]
+    fn test_parse_skips_unreadable_lines() {
+        let source = b"403|1.1.1.1\n403|M\xfcnchen\n403|2.2.2.2\n403|/admin\n";
+        let (sg, errors) = SecurityGroup::parse_source("default", "g.rules.txt", &mut &source[..]);
+        assert_eq!(sg.count(), 3);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0.to_string(), "g.rules.txt:2");
+    }
+
+    #[test]
+    fn test_empty_access_and_target() {
+        let json = r#"{"access": [], "target": [], "reaction": {"code": 403}}"#;
+        let r: Rule = serde_json::from_str(json).unwrap();
+        assert!(!r.has_access_conditions());
+        assert!(!r.has_target_conditions());
+        assert!(r.index_keys().is_empty());
+        assert_eq!(r.to_string(), "403|");
+    }
+
+    #[test]
+    fn test_empty_lists_in_group() {
+        // the JSON import doesn't apply the parse defaults to the empty lists
+        let json = r#"[
+            {"access": [], "target": [{"path": "/a"}], "reaction": {"code": 403}},
+            {"access": [{"from": {"country": "US"}}], "target": [], "reaction": {"code": 401}}
+        ]"#;
+        let mut sg = SecurityGroup::new("default");
+        for rule in serde_json::from_str::<Vec<Rule>>(json).unwrap() {
+            sg.add(rule);
+        }
+        let rules: Vec<String> = sg
+            .list_indexed()
+            .chain(sg.list_non_indexed())
+            .map(|r| r.to_string())
+            .collect();
+        assert_eq!(rules, vec!["403|/a", "401|US"]);
+        assert!(sg.get_indexed("/a").is_some());
+        assert!(sg.get_indexed("US").is_some());
+        assert!(sg.lint().is_empty());
+    }
+
+    #[test]
+    fn test_index_networks() {
+        let r = Rule::parse("403|US,10.0.0.0/8,2001:db8::/32").unwrap();
+        assert_eq!(r.index_keys(), vec!["US"]);
+        assert_eq!(r.index_networks().len(), 2);
+        // every source must be indexed, otherwise the rule is scanned
+        for line in [
+            "403|10.0.0.0/8,city:Paris",
+            "403|10.0.0.0/8,-10.1.0.0/16",
+            "403|10.0.0.0/8,/admin",
+        ] {
+            let r = Rule::parse(line).unwrap();
+            assert!(!r.is_indexed(), "{}", line);
+        }
+        let mut sg = SecurityGroup::from_reader(
+            "test",
+            &mut "451|/old\n403|10.0.0.0/8\n401|10.1.0.0/16".as_bytes(),
+        );
+        let ip = "10.1.2.3".parse().unwrap();
+        assert_eq!(
+            sg.get_network_ordered(ip).unwrap().1.to_string(),
+            "403|10.0.0.0/8"
+        );
+        sg.remove_by_index(1);
+        assert_eq!(
+            sg.get_network_ordered(ip).unwrap().1.to_string(),
+            "401|10.1.0.0/16"
+        );
+        sg.toggle_by_index(1);
+        assert!(sg.get_network_ordered(ip).is_none());
+    }
+
+    #[test]
+    fn test_lint_broad_deny_with_redirect() {
+        let source = ["403|*", "301|/old|/new", "302|US,^/a|/b"].join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let (sg, _) = SecurityGroup::parse_source("default", "default.rules.txt", &mut r);
+        let lint: Vec<String> = sg.lint().iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lint,
+            vec![
+                "redirect '301|/old|/new' (default.rules.txt:2) is never reached after broad deny '403|' (default.rules.txt:1)",
+                "redirect '302|US,^/a|/b' (default.rules.txt:3) is never reached after broad deny '403|' (default.rules.txt:1)",
+            ]
+        );
+        let source = ["302|US,^/a|/b", "403|*", "!301|/old|/new"].join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let (sg, _) = SecurityGroup::parse_source("default", "", &mut r);
+        assert!(sg.lint().is_empty());
+    }
+
+    #[test]
+    fn test_lint_shadowed_rules() {
+        let source = ["403|^/api", "200|*", "401|^/admin", "!403|^/private"].join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let (sg, _) = SecurityGroup::parse_source("default", "g.rules.txt", &mut r);
+        let lint: Vec<String> = sg.lint().iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lint,
+            vec!["rule '401|^/admin' (g.rules.txt:3) is never reached after catch-all '' (g.rules.txt:2)"]
+        );
+        let source = [
+            "403|^/api",
+            "401|^/api",
+            "403|US",
+            "401|US",
+            "403|^/api#ops",
+        ]
+        .join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let (sg, _) = SecurityGroup::parse_source("default", "g.rules.txt", &mut r);
+        let lint: Vec<String> = sg.lint().iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            lint,
+            vec![
+                "rule '401|^/api' (g.rules.txt:2) is never reached, rule '403|^/api' (g.rules.txt:1) has the same conditions",
+                "rule '401|US' (g.rules.txt:4) is never reached, rule '403|US' (g.rules.txt:3) has the same conditions",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_targets() {
+        use crate::visitor::Visit;
+        let r = Rule::parse("403|?__cf_chl,?utm_source=spam").unwrap();
+        assert_eq!(r.to_string(), "403|?__cf_chl,?utm_source=spam");
+        assert!(r.react(&Visit::no_ip("/?__cf_chl")).is_some());
+        assert!(r.react(&Visit::no_ip("/login?a=1&__cf_chl=x")).is_some());
+        assert!(r.react(&Visit::no_ip("/?utm_source=spam")).is_some());
+        assert!(r.react(&Visit::no_ip("/?utm_source=other")).is_none());
+        assert!(r.react(&Visit::no_ip("/__cf_chl")).is_none());
+        assert!(r.react(&Visit::no_ip("/")).is_none());
+
+        // query of the visit is decoded before matching
+        let r = Rule::parse("403|?utm_source=spam bot").unwrap();
+        assert!(r.react(&Visit::no_ip("/?utm_source=spam%20bot")).is_some());
+        assert!(r.react(&Visit::no_ip("/?utm_source=spam+bot")).is_some());
+    }
+
+    #[test]
+    fn test_ipv6_sources() {
+        use crate::visitor::Visit;
+        let visit = |ip: &str| Visit {
+            ip: ip.parse().unwrap(),
+            ..Visit::no_ip("/")
+        };
+        for line in [
+            "403|2001:db8::/32",
+            "403|2001:db8::1",
+            "403|-2001:db8::/32,*",
+        ] {
+            assert_eq!(Rule::parse(line).unwrap().to_string(), line);
+        }
+        let r = Rule::parse("403|2001:db8::/32").unwrap();
+        assert!(r.react(&visit("2001:db8:0:1::5")).is_some());
+        assert!(r.react(&visit("2001:db9::1")).is_none());
+        assert!(r.react(&visit("32.1.13.184")).is_none());
+        let r = Rule::parse("403|2001:db8::1").unwrap();
+        assert_eq!(r.index_keys(), vec!["2001:db8::1"]);
+        assert!(r.react(&visit("2001:0db8:0000::0001")).is_some());
+        let r = Rule::parse("403|192.168.0.0/16").unwrap();
+        assert!(r.react(&visit("::ffff:192.168.0.1")).is_none());
+        assert!(r.react(&visit("192.168.0.1")).is_some());
+    }
+
+    #[test]
+    fn test_headers_round_trip() {
+        for line in [
+            "403|US|X-Reason:blocked;X-Team:sec",
+            "200|/api|X-Api:1#tagged",
+            "451|-GB,^/news|X-Reason:legal",
+        ] {
+            assert_eq!(Rule::parse(line).unwrap().to_string(), line);
+        }
+        let r = Rule::parse("403|US|X-Reason: geo ; X-Team:sec;").unwrap();
+        assert_eq!(r.to_string(), "403|US|X-Reason:geo;X-Team:sec");
+        assert_eq!(r.reaction.code(), 403);
+        assert!(r.reaction.redirect().is_none());
+        assert!(Rule::parse("403|US|X-Reason").is_err());
+        assert!(Rule::parse("403|US|").is_err());
+        assert!(Rule::parse("40x|US|X-Reason:geo").is_err());
+    }
+
+    #[test]
+    fn test_rate_limit_parsing() {
+        let r = Rule::parse("429|/api|ratelimit:30").unwrap();
+        assert_eq!(r.reaction, Reaction::RateLimit { rpm: 30, burst: 30 });
+        assert_eq!(r.to_string(), "429|/api|ratelimit:30:30");
+        assert_eq!(r.reaction.code(), 429);
+        assert!(Rule::parse("403|US|ratelimit:60:10").is_err());
+        assert!(Rule::parse("429|US|ratelimit:0:10").is_err());
+        assert!(Rule::parse("429|US|ratelimit:x").is_err());
+        assert!(Rule::parse("429|US|ratelimit:60:10:1").is_err());
+    }
+
+    #[test]
+    fn test_asn_source() {
+        use crate::visitor::Visit;
+        let r = Rule::parse("403|AS13335,-AS15169").unwrap();
+        assert_eq!(r.to_string(), "403|AS13335,-AS15169");
+        let cloudflare = Visit {
+            asn: Some(13335),
+            ..Visit::no_ip("/")
+        };
+        assert!(r.react(&cloudflare).is_some());
+        // without ASN database, ASN rules never match
+        assert!(r.react(&Visit::no_ip("/")).is_none());
+        assert_eq!(
+            Source::parse("ASTI").unwrap(),
+            Source::FromCity("ASTI".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_subdivision_source() {
+        use crate::visitor::Visit;
+        let r = Rule::parse("403|US-CA,-US-NY").unwrap();
+        assert_eq!(r.to_string(), "403|US-CA,-US-NY");
+        assert_eq!(
+            r.access[0],
+            Access::From(Source::FromSubdivision("US-CA".to_owned()))
+        );
+        let california = Visit {
+            country: Some("US".to_owned()),
+            subdivision: Some("US-CA".to_owned()),
+            ..Visit::no_ip("/")
+        };
+        assert!(r.react(&california).is_some());
+        let texas = Visit {
+            subdivision: Some("US-TX".to_owned()),
+            ..california.clone()
+        };
+        assert!(r.react(&texas).is_none());
+        assert_eq!(
+            Source::parse("GB-LND").unwrap(),
+            Source::FromSubdivision("GB-LND".to_owned())
+        );
+        assert_eq!(
+            Source::parse("FR-75").unwrap(),
+            Source::FromSubdivision("FR-75".to_owned())
+        );
+        assert_eq!(
+            Source::parse("Ho-Chi-Minh").unwrap(),
+            Source::FromCity("Ho-Chi-Minh".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_schedule() {
+        use crate::visitor::Visit;
+        use chrono::TimeZone;
+        // 2024-01-01 is monday
+        let at = |day: u32, h: u32, m: u32| Visit {
+            time: Utc.with_ymd_and_hms(2024, 1, day, h, m, 0).unwrap(),
+            ..Visit::no_ip("/")
+        };
+        let r = Rule::parse("302|*,@02:00-03:00|/maintenance").unwrap();
+        assert_eq!(r.to_string(), "302|@02:00-03:00|/maintenance");
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&at(1, 2, 0)).is_some());
+        assert!(r.react(&at(1, 2, 59)).is_some());
+        assert!(r.react(&at(1, 3, 0)).is_none());
+        assert!(r.react(&at(1, 1, 59)).is_none());
+
+        // overnight window on working days only
+        let r = Rule::parse("403|/admin,@18:00-08:00/mon-fri").unwrap();
+        assert_eq!(r.to_string(), "403|/admin,@18:00-08:00/mon-fri");
+        let admin = |day, h| Visit {
+            uri: "/admin".to_string(),
+            ..at(day, h, 0)
+        };
+        assert!(r.react(&admin(1, 19)).is_some()); // monday evening
+        assert!(r.react(&admin(2, 7)).is_some()); // tuesday morning
+        assert!(r.react(&admin(2, 12)).is_none()); // tuesday noon
+        assert!(r.react(&admin(5, 19)).is_some()); // friday evening
+        assert!(r.react(&admin(6, 7)).is_some()); // saturday morning, after friday
+        assert!(r.react(&admin(6, 19)).is_none()); // saturday evening
+        assert!(r.react(&admin(1, 7)).is_none()); // monday morning, after sunday
+
+        for line in [
+            "403|@00:00-24:00/sat+sun",
+            "403|@10:00-11:00/mon+wed+fri",
+            "403|@10:00-11:00/mon-wed+sun",
+        ] {
+            assert_eq!(Rule::parse(line).unwrap().to_string(), line);
+        }
+        assert_eq!(
+            Rule::parse("403|@10:00-11:00/fri-mon").unwrap().to_string(),
+            "403|@10:00-11:00/mon+fri-sun"
+        );
+        assert!(Rule::parse("403|@10:00-10:00").is_err());
+        assert!(Rule::parse("403|@25:00-26:00").is_err());
+        assert!(Rule::parse("403|@10:00").is_err());
+        assert!(Rule::parse("403|@10:00-11:00/xyz").is_err());
+        assert!(Rule::parse("403|@10:00-11:00,@12:00-13:00").is_err());
+    }
+
+    #[test]
+    fn test_regex_target() {
+        use crate::visitor::Visit;
+        let line = "403|~^/user/[0-9]+/settings$";
+        let r = Rule::parse(line).unwrap();
+        assert_eq!(r.to_string(), line);
+        assert_eq!(r, Rule::parse(line).unwrap());
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&Visit::no_ip("/user/42/settings")).is_some());
+        assert!(r.react(&Visit::no_ip("/user/42/settings?tab=1")).is_some());
+        assert!(r.react(&Visit::no_ip("/user/me/settings")).is_none());
+        assert!(r.react(&Visit::no_ip("/user/42/settings/extra")).is_none());
+
+        let e = Rule::parse("403|~^/user/[0-9+/settings$").unwrap_err();
+        assert!(format!("{:?}", e).contains("^/user/[0-9+/settings$"));
+    }
+
+    #[test]
+    fn test_rule_json_round_trip() {
+        let line = "!403|US,-1.2.3.4,POST,/admin,^/wp-,-/public,@18:00-08:00/mon-fri#geo,temp";
+        let r = Rule::parse(line).unwrap();
+        let json = serde_json::to_value(&r).unwrap();
+        assert_eq!(json["tags"], serde_json::json!(["geo", "temp"]));
+        assert_eq!(json["methods"], serde_json::json!(["POST"]));
+        assert_eq!(json["disabled"], true);
+        assert_eq!(json["target"].as_array().unwrap().len(), 3);
+        let back: Rule = serde_json::from_value(json).unwrap();
+        assert_eq!(back, r);
+        assert_eq!(back.to_string(), line);
+
+        // optional parts can be omitted
+        let json = serde_json::json!({
+            "access": [{"from": {"country": "GB"}}],
+            "target": ["any"],
+            "reaction": {"301": "https://example.com/"},
+        });
+        let r: Rule = serde_json::from_value(json).unwrap();
+        assert_eq!(r.to_string(), "301|GB|https://example.com/");
+    }
+
+    #[test]
+    fn test_excluded_target() {
+        use crate::visitor::Visit;
+        let from_us = |uri: &str| Visit {
+            country: Some("US".to_owned()),
+            ..Visit::no_ip(uri)
+        };
+        let line = "403|US,-/public";
+        let r = Rule::parse(line).unwrap();
+        assert_eq!(r.to_string(), line);
+        assert_eq!(
+            r.access,
+            vec![Access::From(Source::FromCountry("US".to_owned()))]
+        );
+        assert!(r.has_target_conditions());
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&from_us("/admin")).is_some());
+        assert!(r.react(&from_us("/")).is_some());
+        assert!(r.react(&from_us("/public")).is_none());
+        assert!(r.react(&from_us("/public/")).is_none());
+        assert!(r.react(&Visit::no_ip("/admin")).is_none());
+
+        // exclusion next to the path targets
+        let line = "403|^/api,-^/api/health,-?debug";
+        let r = Rule::parse(line).unwrap();
+        assert_eq!(r.to_string(), line);
+        assert!(r.react(&Visit::no_ip("/api/users")).is_some());
+        assert!(r.react(&Visit::no_ip("/api/health/live")).is_none());
+        assert!(r.react(&Visit::no_ip("/api/users?debug")).is_none());
+        assert!(r.react(&Visit::no_ip("/web")).is_none());
+        // excluded countries are still access conditions
+        assert_eq!(
+            Rule::parse("403|-GB").unwrap().access,
+            vec![Access::Excluding(Source::FromCountry("GB".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn test_host_conditions() {
+        use crate::visitor::Visit;
+        let on_host = |host: &str, uri: &str| Visit {
+            host: Some(host.to_string()),
+            ..Visit::no_ip(uri)
+        };
+        let r = Rule::parse("403|host:Admin.Example.com,/admin").unwrap();
+        assert_eq!(r.to_string(), "403|//admin.example.com,/admin");
+        assert_eq!(r, Rule::parse("403|//admin.example.com,/admin").unwrap());
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&on_host("admin.example.com", "/admin")).is_some());
+        assert!(r.react(&on_host("ADMIN.example.com", "/admin")).is_some());
+        assert!(r.react(&on_host("www.example.com", "/admin")).is_none());
+        assert!(r.react(&on_host("admin.example.com", "/")).is_none());
+        assert!(r.react(&Visit::no_ip("/admin")).is_none());
+
+        let r = Rule::parse("403|//*.example.com,^/debug").unwrap();
+        assert_eq!(r.to_string(), "403|//*.example.com,^/debug");
+        assert!(r
+            .react(&on_host("api.example.com", "/debug/vars"))
+            .is_some());
+        assert!(r.react(&on_host("a.b.Example.COM", "/debug")).is_some());
+        assert!(r.react(&on_host("example.com", "/debug")).is_none());
+        assert!(r.react(&on_host("notexample.com", "/debug")).is_none());
+        assert!(Rule::parse("403|host:").is_err());
+    }
+
+    #[test]
+    fn test_postal_sources() {
+        use crate::visitor::Visit;
+        let with_postal = |postal: &str| Visit {
+            country: Some("US".to_string()),
+            postal: Some(postal.to_string()),
+            ..Visit::no_ip("/")
+        };
+        let r = Rule::parse("403|zip:10001,zip:SW1A 1").unwrap();
+        assert_eq!(
+            r.access[0],
+            Access::From(Source::FromPostal("10001".to_owned()))
+        );
+        assert_eq!(r.to_string(), "403|zip:10001,zip:SW1A 1");
+        assert!(r.index_keys().is_empty());
+        assert_eq!(
+            r.react(&with_postal("10001")),
+            Some(Reaction::HttpStatus(403))
+        );
+        assert_eq!(
+            r.react(&with_postal("SW1A 1")),
+            Some(Reaction::HttpStatus(403))
+        );
+        assert_eq!(r.react(&with_postal("10002")), None);
+        assert_eq!(r.react(&Visit::no_ip("/")), None);
+        let r = Rule::parse("403|US,-zip:10001").unwrap();
+        assert_eq!(r.react(&with_postal("10001")), None);
+        assert_eq!(
+            r.react(&with_postal("94105")),
+            Some(Reaction::HttpStatus(403))
+        );
+        assert!(Rule::parse("403|zip:").is_err());
+    }
+
+    #[test]
+    fn test_explicit_country_and_city() {
+        let r = Rule::parse("403|city:Ub,-country:GB,city:London").unwrap();
+        assert_eq!(
+            r.access,
+            vec![
+                Access::From(Source::FromCity("Ub".to_owned())),
+                Access::Excluding(Source::FromCountry("GB".to_owned())),
+                Access::From(Source::FromCity("London".to_owned())),
+            ]
+        );
+        // the prefix is kept only where it is needed to read the rule back
+        assert_eq!(r.to_string(), "403|city:Ub,-GB,London");
+        assert_eq!(Rule::parse(&r.to_string()).unwrap(), r);
+        let r = Rule::parse("403|city:AS15169,city:1.2.3.4").unwrap();
+        assert_eq!(r.to_string(), "403|city:AS15169,city:1.2.3.4");
+        assert!(Rule::parse("403|city:").is_err());
+        assert!(Rule::parse("403|country:").is_err());
+    }
+
+    #[test]
+    fn test_rule_note() {
+        let r = Rule::parse("403|US#legal,geo ;; blocked per legal ticket #42, see wiki").unwrap();
+        assert_eq!(r.tags, vec!["legal", "geo"]);
+        assert_eq!(
+            r.note.as_deref(),
+            Some("blocked per legal ticket #42, see wiki")
+        );
+        assert_eq!(
+            r.access,
+            vec![Access::From(Source::FromCountry("US".to_owned()))]
+        );
+        assert_eq!(
+            r.to_string(),
+            "403|US#legal,geo ;; blocked per legal ticket #42, see wiki"
+        );
+        assert_eq!(Rule::parse(&r.to_string()).unwrap(), r);
+        let json = serde_json::to_value(&r).unwrap();
+        assert_eq!(json["note"], "blocked per legal ticket #42, see wiki");
+
+        let r = Rule::parse("401|^/admin;;").unwrap();
+        assert_eq!(r.note, None);
+        assert_eq!(r.to_string(), "401|^/admin");
+    }
+
+    #[test]
+    fn test_unknown_countries() {
+        let rule = Rule::parse("403|GB,-UK,Uk,ua:ZZ,London").unwrap();
+        assert_eq!(rule.unknown_countries(), vec!["UK", "Uk"]);
+        assert!(Rule::parse("403|US,-GB")
+            .unwrap()
+            .unknown_countries()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_user_agent_sources() {
+        use crate::visitor::Visit;
+        let with_ua = |ua: &str| Visit {
+            user_agent: Some(ua.to_string()),
+            ..Visit::no_ip("/")
+        };
+        let r = Rule::parse("403|ua:Bot").unwrap();
+        assert_eq!(r.to_string(), "403|ua:Bot");
+        assert!(r.index_keys().is_empty());
+        assert!(r
+            .react(&with_ua("Mozilla/5.0 (compatible; Googlebot/2.1)"))
+            .is_some());
+        assert!(r
+            .react(&with_ua("Mozilla/5.0 (X11; Linux x86_64)"))
+            .is_none());
+        assert!(r.react(&Visit::no_ip("/")).is_none());
+
+        let r = Rule::parse("403|ua:~^curl/[0-9.]+$").unwrap();
+        assert_eq!(r.to_string(), "403|ua:~^curl/[0-9.]+$");
+        assert!(r.react(&with_ua("curl/8.0.1")).is_some());
+        assert!(r.react(&with_ua("libcurl/8.0.1")).is_none());
+        assert!(Rule::parse("403|ua:~[").is_err());
+        assert!(Rule::parse("403|ua:").is_err());
+
+        // any of the sources matches, as with the geo sources
+        let r = Rule::parse("403|US,ua:bot").unwrap();
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&with_ua("crawler-bot")).is_some());
+        let from_us = Visit {
+            country: Some("US".to_string()),
+            ..with_ua("Mozilla/5.0")
+        };
+        assert!(r.react(&from_us).is_some());
+        // excluded user agent turns the rule off
+        let r = Rule::parse("403|US,-ua:Googlebot").unwrap();
+        let googlebot = Visit {
+            country: Some("US".to_string()),
+            ..with_ua("Googlebot/2.1")
+        };
+        assert!(r.react(&googlebot).is_none());
+        assert!(r.react(&from_us).is_some());
+    }
+
+    #[test]
+    fn test_glob_target() {
+        use crate::visitor::Visit;
+        let line = "403|/api/*/internal";
+        let r = Rule::parse(line).unwrap();
+        assert_eq!(r.target, vec![Target::Glob("/api/*/internal".to_string())]);
+        assert_eq!(r.to_string(), line);
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&Visit::no_ip("/api/v1/internal")).is_some());
+        assert!(r.react(&Visit::no_ip("/api/v1/internal/")).is_some());
+        assert!(r.react(&Visit::no_ip("/api//internal")).is_some());
+        assert!(r.react(&Visit::no_ip("/api/v1/v2/internal")).is_none());
+        assert!(r.react(&Visit::no_ip("/api/v1/internal/users")).is_none());
+
+        // nested segments
+        let r = Rule::parse("403|/static/**.map").unwrap();
+        assert!(r.react(&Visit::no_ip("/static/app.js.map")).is_some());
+        assert!(r
+            .react(&Visit::no_ip("/static/js/vendor/app.js.map"))
+            .is_some());
+        assert!(r.react(&Visit::no_ip("/static/app.js")).is_none());
+
+        // trailing wildcards
+        let r = Rule::parse("403|/files/*").unwrap();
+        assert!(r.react(&Visit::no_ip("/files/")).is_some());
+        assert!(r.react(&Visit::no_ip("/files/report.pdf")).is_some());
+        assert!(r.react(&Visit::no_ip("/files/2024/report.pdf")).is_none());
+        let r = Rule::parse("403|/files/**").unwrap();
+        assert!(r.react(&Visit::no_ip("/files/2024/report.pdf")).is_some());
+        assert!(r.react(&Visit::no_ip("/filesystem")).is_none());
+
+        let r = Rule::parse("403|/v?/users,-/v2/*").unwrap();
+        assert_eq!(r.to_string(), "403|/v?/users,-/v2/*");
+        assert!(r.react(&Visit::no_ip("/v1/users")).is_some());
+        assert!(r.react(&Visit::no_ip("/v2/users")).is_none());
+        assert!(r.react(&Visit::no_ip("/v10/users")).is_none());
+    }
+
+    #[test]
+    fn test_paths_ignore_case() {
+        use crate::visitor::Visit;
+        let visit = |uri: &str, paths_ignore_case| Visit {
+            paths_ignore_case,
+            ..Visit::no_ip(uri)
+        };
+        let r = Rule::parse("403|/admin,^/wp-").unwrap();
+        assert!(r.react(&visit("/Admin", false)).is_none());
+        assert!(r.react(&visit("/Admin", true)).is_some());
+        assert!(r.react(&visit("/ADMIN/", true)).is_some());
+        assert!(r.react(&visit("/WP-login.php", true)).is_some());
+        assert!(r.react(&visit("/Admins", true)).is_none());
+        // mixed-case paths in the rule are matched too, but not indexed
+        let r = Rule::parse("403|/Admin").unwrap();
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&visit("/admin", true)).is_some());
+        assert!(r.react(&visit("/admin", false)).is_none());
+        assert!(r.react(&visit("/Admin", false)).is_some());
+    }
+
+    #[test]
+    fn test_method_conditions() {
+        use crate::visitor::Visit;
+        let visit = |method: &str, uri: &str| Visit {
+            method: Some(method.to_string()),
+            ..Visit::no_ip(uri)
+        };
+        let r = Rule::parse("403|POST,/admin").unwrap();
+        assert_eq!(r.methods, vec![Method::Post]);
+        assert_eq!(r.to_string(), "403|POST,/admin");
+        assert!(r.index_keys().is_empty());
+        assert!(r.react(&visit("POST", "/admin")).is_some());
+        assert!(r.react(&visit("post", "/admin")).is_some());
+        assert!(r.react(&visit("GET", "/admin")).is_none());
+        assert!(r.react(&visit("POST", "/public")).is_none());
+
+        let r = Rule::parse("403|US,PUT,DELETE").unwrap();
+        assert_eq!(r.to_string(), "403|US,PUT,DELETE");
+        // no methods in the rule match any method
+        let r = Rule::parse("403|/admin").unwrap();
+        assert!(r.react(&visit("PATCH", "/admin")).is_some());
+    }
+
+    #[test]
+    fn test_atomic_write_keeps_original_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("default.rules.txt");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "403|US\n").unwrap();
+
+        let result = atomic_write(path, |f| {
+            f.write_all(b"401|")?;
+            bail!("disk full")
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "403|US\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let mut sg = SecurityGroup::new("default");
+        sg.add(Rule::parse("401|GB").unwrap());
+        sg.save_to_file(path).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "401|GB\n");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
     #[test]
     fn test_reaction_codes() {
         assert_eq!(Reaction::HttpStatus(200).code(), 200);
@@ -736,7 +2813,7 @@ pub mod tests {
 
     #[test]
     fn test_security_group_read_write() {
-        let source = vec!["403|ES", "401|-JP", "301|127.0.0.1,/a/|/b/"].join("\n");
+        let source = ["403|ES", "401|-JP", "301|127.0.0.1,/a/|/b/"].join("\n");
 
         let mut r = BufReader::new(source.as_bytes());
         let sg = SecurityGroup::from_reader("default", &mut r);
@@ -747,9 +2824,56 @@ pub mod tests {
         assert_eq!(s, format!("{}\n", source));
     }
 
+    #[test]
+    fn test_security_group_keeps_comments() {
+        let source = [
+            "# --- blocked countries ---",
+            "403|ES",
+            "",
+            "# --- admin ---",
+            "401|^/admin",
+            "401|/login",
+            "# end of file",
+        ]
+        .join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let mut sg = SecurityGroup::from_reader("default", &mut r);
+        assert_eq!(sg.count(), 3);
+        let write = |sg: &SecurityGroup| {
+            let mut writer = BufWriter::new(Vec::new());
+            sg.to_writer(&mut writer).unwrap();
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        };
+        // rules are written in the order of the file, with their comments
+        assert_eq!(write(&sg), format!("{}\n", source));
+        // replaced rule keeps the comments
+        sg.set_by_index(2, Rule::parse("403|^/admin").unwrap());
+        assert!(write(&sg).contains("# --- admin ---\n403|^/admin\n"));
+    }
+
+    #[test]
+    fn test_security_group_default_reaction() {
+        let source = "!default 403\n# allowed\nUS\n";
+        let mut r = BufReader::new(source.as_bytes());
+        let sg = SecurityGroup::from_reader("default", &mut r);
+        assert_eq!(sg.default_reaction, Some(Reaction::HttpStatus(403)));
+        assert_eq!(sg.count(), 1);
+        let mut writer = BufWriter::new(Vec::new());
+        sg.to_writer(&mut writer).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            source
+        );
+
+        let mut r = BufReader::new("!default 42\n403|CN\n".as_bytes());
+        let (sg, errors) = SecurityGroup::parse_source("default", "", &mut r);
+        assert_eq!(sg.default_reaction, None);
+        assert_eq!(errors.len(), 1);
+    }
+
     #[test]
     fn test_security_group_indexes() {
-        let source = vec![
+        let source = [
             "200|51.138.72.171,20.198.223.70,161.156.174.216,161.156.87.230,20.113.168.212",
             "401|*",
         ]
@@ -761,4 +2885,125 @@ pub mod tests {
         // let rule1 = sg.list_indexed.get(0).unwrap();
         assert_eq!(sg.map_indexed.len(), 5);
     }
+
+    fn group(source: &[&str]) -> SecurityGroup {
+        let source = source.join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        SecurityGroup::from_reader("default", &mut r)
+    }
+
+    fn group_lines(sg: &SecurityGroup) -> Vec<String> {
+        sg.list_indexed()
+            .chain(sg.list_non_indexed())
+            .map(Rule::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_security_group_remove_many() {
+        // indexed: 0..=2, non-indexed: 3..=5
+        let mut sg = group(&[
+            "403|1.1.1.1",
+            "403|2.2.2.2",
+            "401|/admin",
+            "403|^/wp-",
+            "401|US,/api",
+            "403|GB,/login",
+        ]);
+        assert_eq!(sg.list_indexed.len(), 3);
+        sg.remove_many(vec![1, 4].into_iter());
+        assert_eq!(
+            group_lines(&sg),
+            ["403|1.1.1.1", "401|/admin", "403|^/wp-", "403|GB,/login"]
+        );
+        assert_eq!(sg.map_indexed.get("1.1.1.1"), Some(&0));
+        assert_eq!(sg.map_indexed.get("/admin"), Some(&1));
+        assert_eq!(sg.map_indexed.get("2.2.2.2"), None);
+        assert_eq!(sg.get_indexed("/admin/").unwrap().to_string(), "401|/admin");
+
+        // only non-indexed ones
+        sg.remove_many(vec![2, 3].into_iter());
+        assert_eq!(group_lines(&sg), ["403|1.1.1.1", "401|/admin"]);
+        assert_eq!(sg.map_indexed.len(), 3);
+    }
+
+    #[test]
+    fn test_security_group_set_by_index() {
+        let mut sg = group(&["403|1.1.1.1", "401|/admin", "403|^/wp-"]);
+        // IP-indexed rule, taking the place of the replaced one
+        sg.set_by_index(0, Rule::parse("403|3.3.3.3").unwrap());
+        assert_eq!(group_lines(&sg), ["403|3.3.3.3", "401|/admin", "403|^/wp-"]);
+        assert!(sg.get_indexed("1.1.1.1").is_none());
+        assert_eq!(
+            sg.get_indexed("3.3.3.3").unwrap().to_string(),
+            "403|3.3.3.3"
+        );
+        assert_eq!(sg.get_indexed("/admin").unwrap().to_string(), "401|/admin");
+
+        // path-indexed rule
+        sg.set_by_index(1, Rule::parse("401|/private").unwrap());
+        assert_eq!(
+            group_lines(&sg),
+            ["403|3.3.3.3", "401|/private", "403|^/wp-"]
+        );
+        assert!(sg.get_indexed("/admin").is_none());
+        assert_eq!(
+            sg.get_indexed("/private/").unwrap().to_string(),
+            "401|/private"
+        );
+        assert_eq!(
+            sg.get_indexed("3.3.3.3").unwrap().to_string(),
+            "403|3.3.3.3"
+        );
+
+        // non-indexed rule becoming indexed
+        sg.set_by_index(2, Rule::parse("403|4.4.4.4").unwrap());
+        assert_eq!(
+            group_lines(&sg),
+            ["403|3.3.3.3", "401|/private", "403|4.4.4.4"]
+        );
+        assert_eq!(sg.count(), 3);
+    }
+
+    #[test]
+    fn test_security_group_reset() {
+        let source = ["403|ES", "401|1.2.3.4,5.6.7.8", "401|*"].join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let mut sg = SecurityGroup::from_reader("default", &mut r);
+        assert_eq!(sg.count(), 3);
+        sg.reset();
+        assert_eq!(sg.count(), 0);
+        assert!(sg.map_indexed.is_empty());
+        assert!(sg.list_non_indexed.is_empty());
+    }
+
+    #[test]
+    fn test_security_group_compact() {
+        let mut source = vec!["# feed".to_string()];
+        source.extend((0..2000u32).map(|i| format!("403|{}", Ipv4Addr::from(0x0b00_0000 + i))));
+        let source = source.join("\n");
+        let mut r = BufReader::new(source.as_bytes());
+        let mut sg = SecurityGroup::from_reader("feed", &mut r);
+        assert!(sg.compact().is_some());
+        assert_eq!(sg.list_indexed.len(), 0);
+        assert_eq!(sg.count(), 2000);
+
+        let mut writer = BufWriter::new(Vec::new());
+        sg.to_writer(&mut writer).unwrap();
+        let s = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(s, format!("{}\n", source));
+
+        // any modification turns the group into the regular rules
+        sg.add(Rule::parse("401|US").unwrap());
+        assert!(sg.compact().is_none());
+        assert_eq!(sg.count(), 2001);
+        assert_eq!(sg.list_indexed.len(), 2001);
+        // comments of the compact form are kept with the first rule
+        let first = sg.list_ordered().next().unwrap();
+        assert_eq!(first.comments, vec!["# feed"]);
+        let mut writer = BufWriter::new(Vec::new());
+        sg.to_writer(&mut writer).unwrap();
+        let s = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert!(s.starts_with("# feed\n403|11.0.0.0\n"));
+    }
 }
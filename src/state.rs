@@ -1,9 +1,15 @@
+use super::cache::ReactionCache;
+use super::error::GuardError;
 use super::proto::*;
+use super::store::*;
 use super::tags::TagMap;
 use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap as Map;
-use std::fs;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
 use tracing::*;
+use utoipa::ToSchema;
 
 /*
 service
@@ -22,11 +28,152 @@ pub enum RulesRef {
     Tag(TagMap),
 }
 
+// the way the matched rule was found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lookup {
+    Compact,
+    Indexed,
+    Scan,
+}
+
+impl Lookup {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Lookup::Compact => "compact",
+            Lookup::Indexed => "indexed",
+            Lookup::Scan => "scan",
+        }
+    }
+}
+
+// details of the rule that produced the reaction
+#[derive(Debug, Clone)]
+pub struct MatchInfo {
+    // the rule as it is written in the rules file
+    pub rule: String,
+    pub lookup: Lookup,
+    pub tags: Vec<String>,
+    // reaction depends on the source of the visitor (IP, geo), not only on the request
+    pub per_visitor: bool,
+}
+
+impl MatchInfo {
+    pub fn new(rule: &Rule, lookup: Lookup) -> Self {
+        Self {
+            rule: rule.to_string(),
+            lookup,
+            tags: rule.tags.clone(),
+            per_visitor: rule.has_access_conditions()
+                || matches!(rule.reaction, Reaction::RateLimit { .. }),
+        }
+    }
+
+    // Cache-Control directives, set on the rule with "cache:" tags
+    pub fn cache_control(&self) -> Option<String> {
+        let directives: Vec<&str> = self
+            .tags
+            .iter()
+            .filter_map(|t| t.strip_prefix("cache:"))
+            .collect();
+        if directives.is_empty() {
+            None
+        } else {
+            Some(directives.join(", "))
+        }
+    }
+}
+
+// amount of rules in the group, by the way they are looked up
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct RulesCount {
+    pub total: usize,
+    pub indexed: usize,
+    pub non_indexed: usize,
+    pub compact: usize,
+}
+
+// indexes of the rules created in the group, and the amount of skipped duplicates
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct CreatedRules {
+    pub indexes: Vec<usize>,
+    pub skipped: usize,
+}
+
+// name of the security group with the amount of its rules
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct GroupInfo {
+    pub name: String,
+    pub rules: usize,
+}
+
+// tag of the rules with the amount of rules having it
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct TagInfo {
+    pub tag: String,
+    pub rules: usize,
+}
+
+// result of re-reading all the groups from the storage path
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct ReloadReport {
+    pub groups: usize,
+    pub rules: usize,
+    // problems of each group: lines that cannot be parsed, or the file that cannot be read
+    #[schema(value_type = Object)]
+    pub errors: Map<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExportedGroup {
+    name: String,
+    rules: Vec<Rule>,
+    // reaction when none of the rules matches
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<Reaction>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Export {
+    groups: Vec<ExportedGroup>,
+}
+
+// parses the rule provided by the client, so its failure can be told apart from IO errors
+// Unknown country codes are rejected here, while the rules files only warn about them
+fn parse_rule(line: &str) -> anyhow::Result<Rule> {
+    // stored as a disabled rule, the line would turn into the default reaction on the next load
+    if line.trim().starts_with("!default ") {
+        let e = anyhow!("the default reaction is set in the rules file, not as a rule");
+        return Err(GuardError::parse(line, e).into());
+    }
+    let rule = Rule::parse(line).map_err(|e| GuardError::parse(line, e))?;
+    if let Some(country) = rule.unknown_countries().first() {
+        let e = anyhow!("unknown country code {:?}", country);
+        return Err(GuardError::parse(line, e).into());
+    }
+    Ok(rule)
+}
+
 // service structure as a state with map of security groups
-#[derive(Clone)]
 pub struct SecurityGroupService {
-    pub storage_path: String,
+    pub store: Box<dyn RuleStore>,
     pub groups: Map<String, SecurityGroup>,
+    // time of the last successful load of the groups from the store, none if never loaded
+    pub loaded_at: Option<chrono::DateTime<chrono::Utc>>,
+    // groups changed through the API and not saved yet
+    dirty: BTreeSet<String>,
+    // rules of the groups seeded inline, they are never written to the store
+    // and replace the groups of the same name on every reload
+    seeded: Map<String, String>,
+    // groups with the scheduled rules, their reactions depend on the time and are not cached
+    scheduled: BTreeSet<String>,
+    cache: ReactionCache,
+}
+
+// nothing is persisted by default
+impl Default for SecurityGroupService {
+    fn default() -> Self {
+        Self::with_store(Box::new(MemoryStore))
+    }
 }
 
 impl std::fmt::Debug for SecurityGroupService {
@@ -42,100 +189,338 @@ impl std::fmt::Debug for SecurityGroupService {
 
 // implementation of the service
 impl SecurityGroupService {
-    // function to load all security groups from the given path, each group is stored in a separate file
-    #[instrument(ret)]
-    pub fn from_local_path(path: &str) -> anyhow::Result<Self> {
-        let mut groups = Map::new();
-        // for each file in the given path, add a new group from it
-        let paths = fs::read_dir(path).context("read dir")?;
-        for path in paths {
-            let path = path.context("read path")?;
-            let full_file_name = format!("{}", path.path().display());
-
-            let basename = path
-                .file_name()
-                .into_string()
-                .unwrap()
-                .replace(".rules.txt", "");
+    // function to load all security groups from the store
+    #[instrument(skip(store), fields(store = store.name()))]
+    pub fn from_store(store: Box<dyn RuleStore>) -> anyhow::Result<Self> {
+        let loaded = store.load_all()?;
+        for (name, problems) in &loaded.errors {
+            for problem in problems {
+                warn!("group {}: {}", name, problem);
+            }
+        }
+        for (name, group) in &loaded.groups {
+            for lint in group.lint() {
+                warn!("group {}: {}", name, lint);
+            }
+        }
+        let mut out = Self {
+            groups: loaded.groups,
+            store,
+            loaded_at: Some(chrono::Utc::now()),
+            dirty: BTreeSet::new(),
+            seeded: Map::new(),
+            scheduled: BTreeSet::new(),
+            cache: ReactionCache::default(),
+        };
+        out.groups_changed();
+        Ok(out)
+    }
 
-            match SecurityGroup::from_file(&basename, &full_file_name) {
-                Ok(group) => {
-                    // info!("Loaded group {}, {} rules", basename, group.list.len());
-                    groups.insert(basename, group);
-                }
-                Err(e) => {
-                    warn!("Failed to load group {}: {}", full_file_name, e);
-                }
-            };
+    // function to start with no groups, saving them to the given store
+    pub fn with_store(store: Box<dyn RuleStore>) -> Self {
+        Self {
+            groups: Map::new(),
+            store,
+            loaded_at: None,
+            dirty: BTreeSet::new(),
+            seeded: Map::new(),
+            scheduled: BTreeSet::new(),
+            cache: ReactionCache::default(),
         }
-        Ok(Self {
-            groups,
-            storage_path: path.to_string(),
+    }
+
+    // function to replace the group with the rules of the given text, returns the amount of rules.
+    // The group is kept in memory only, its file in the store is never overwritten
+    pub fn seed_group(&mut self, group_name: &str, rules: &str) -> usize {
+        let group = SecurityGroup::from_reader(group_name, &mut rules.as_bytes());
+        let count = group.count();
+        self.groups.insert(group_name.to_string(), group);
+        self.seeded
+            .insert(group_name.to_string(), rules.to_string());
+        self.dirty.remove(group_name);
+        self.groups_changed();
+        count
+    }
+
+    // function to keep the reactions of the visitors in memory, 0 disables the cache
+    pub fn set_reaction_cache(&mut self, capacity: usize, shards: usize) {
+        self.cache = ReactionCache::new(capacity, shards);
+    }
+
+    // function to refresh what is derived from the groups, once any of them is changed
+    fn groups_changed(&mut self) {
+        self.scheduled = self
+            .groups
+            .iter()
+            .filter(|(_, g)| g.list_ordered().any(|r| r.schedule.is_some()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        self.cache.clear();
+        self.update_metrics();
+    }
+
+    fn update_metrics(&self) {
+        crate::endpoints::metrics::set_rules_count(
+            self.groups
+                .iter()
+                .map(|(name, g)| (name.as_str(), g.count())),
+        );
+    }
+
+    // function to replace all the groups with the ones from the storage path.
+    // Lines that cannot be parsed are skipped, the group that cannot be read is kept as it was
+    #[instrument(skip(self))]
+    pub fn reload(&mut self) -> anyhow::Result<ReloadReport> {
+        let loaded = self.store.load_all()?;
+        let mut groups = loaded.groups;
+        for name in loaded.errors.keys() {
+            if let Some(group) = self.groups.get(name).filter(|_| !groups.contains_key(name)) {
+                groups.insert(name.clone(), group.clone());
+            }
+        }
+        for (name, rules) in &self.seeded {
+            let group = SecurityGroup::from_reader(name, &mut rules.as_bytes());
+            groups.insert(name.clone(), group);
+        }
+        let errors = loaded.errors;
+        self.groups = groups;
+        self.dirty.clear();
+        self.loaded_at = Some(chrono::Utc::now());
+        self.groups_changed();
+        Ok(ReloadReport {
+            groups: self.groups.len(),
+            rules: self.groups.values().map(SecurityGroup::count).sum(),
+            errors,
         })
     }
 
-    // function to save each security group to a separate file
+    // function to reload the security group from its file after it was changed on disk.
+    // Returns the name of the group and the amount of rules, or None if the file is not a rules file
+    // or belongs to the inline group, which replaces it
+    pub fn reload_file(&mut self, file_name: &str) -> Option<(String, usize)> {
+        let name = std::path::Path::new(file_name)
+            .file_name()?
+            .to_str()?
+            .strip_suffix(".rules.txt")?
+            .to_string();
+        if self.seeded.contains_key(&name) {
+            return None;
+        }
+        self.dirty.remove(&name);
+        if !std::path::Path::new(file_name).exists() {
+            self.groups.remove(&name);
+            self.groups_changed();
+            return Some((name, 0));
+        }
+        match SecurityGroup::from_file(&name, file_name) {
+            Ok(group) => {
+                let count = group.count();
+                self.groups.insert(name.clone(), group);
+                self.loaded_at = Some(chrono::Utc::now());
+                self.groups_changed();
+                Some((name, count))
+            }
+            Err(e) => {
+                warn!("Failed to reload group {}: {}", file_name, e);
+                None
+            }
+        }
+    }
+
+    // function to remove the whole group from the store.
+    // Returns false if there was no such group
     #[instrument(skip(self))]
-    pub fn save(&self) {
-        if self.storage_path == "" {
-            return;
-        }
-        for (name, group) in &self.groups {
-            let file_name = format!(
-                "{}/{}.rules.txt",
-                self.storage_path.trim_end_matches('/'),
-                name
-            );
-            match group.save_to_file(&file_name) {
-                Ok(_) => {} // info!("Saved group {} to {}", name, file_name),
-                Err(e) => warn!("Failed to save group {} to {}: {}", name, file_name, e),
+    pub fn delete_group(&mut self, group_name: &str) -> anyhow::Result<bool> {
+        if !self.groups.contains_key(group_name) {
+            return Ok(false);
+        }
+        // the group keeps guarding until its file is gone
+        self.store.delete_group(group_name)?;
+        self.groups.remove(group_name);
+        self.dirty.remove(group_name);
+        self.seeded.remove(group_name);
+        self.groups_changed();
+        Ok(true)
+    }
+
+    // function to export all groups with their rules in the structured form
+    pub fn export_json(&self) -> serde_json::Value {
+        let groups = self
+            .groups
+            .iter()
+            .map(|(name, group)| {
+                let compact = group.compact().into_iter().flat_map(|c| c.rules());
+                let rules = compact
+                    .chain(group.list_indexed().cloned())
+                    .chain(group.list_non_indexed().cloned())
+                    .collect();
+                ExportedGroup {
+                    name: name.clone(),
+                    rules,
+                    default: group.default_reaction.clone(),
+                }
+            })
+            .collect();
+        serde_json::to_value(Export { groups }).unwrap()
+    }
+
+    // function to replace the groups with the ones from the JSON export, the other groups are kept.
+    // Returns the amount of the imported groups
+    #[instrument(skip(self, value))]
+    pub fn import_json(&mut self, value: serde_json::Value) -> anyhow::Result<usize> {
+        let export: Export = serde_json::from_value(value).context("invalid export")?;
+        let count = export.groups.len();
+        for exported in export.groups {
+            let mut group = SecurityGroup::new(&exported.name);
+            group.default_reaction = exported.default;
+            for rule in exported.rules {
+                group.add(rule);
             }
+            self.dirty.insert(exported.name.clone());
+            self.groups.insert(exported.name, group);
         }
+        self.save();
+        Ok(count)
     }
 
-    // function to create rule for a given group, returns index of the rule
+    // function to save the groups changed through the API to the store.
+    // Inline groups are skipped, the groups that failed to save are kept for the next attempt
+    #[instrument(skip(self))]
+    pub fn save(&mut self) {
+        // every mutation is saved, so the cache and the gauges are refreshed here even without the storage
+        self.groups_changed();
+        for name in std::mem::take(&mut self.dirty) {
+            if self.seeded.contains_key(&name) {
+                continue;
+            }
+            let Some(group) = self.groups.get(&name) else {
+                continue;
+            };
+            if let Err(e) = self.store.save_group(&name, group) {
+                warn!(
+                    "Failed to save group {} to {}: {}",
+                    name,
+                    self.store.name(),
+                    e
+                );
+                self.dirty.insert(name);
+            }
+        }
+    }
+
+    // function to remember the group is changed, so it is written on the next save
+    fn touch(&mut self, group_name: &str) {
+        self.dirty.insert(group_name.to_string());
+    }
+
+    // function to create rules for a given group, returns indexes of the created rules.
+    // With dedupe, the rules that are already in the group are skipped
     #[instrument(skip(self, rule), fields(result))]
-    pub fn create_rule(&mut self, group_name: &str, rule: &str) -> anyhow::Result<usize> {
+    pub fn create_rule(
+        &mut self,
+        group_name: &str,
+        rule: &str,
+        dedupe: bool,
+    ) -> anyhow::Result<CreatedRules> {
+        // all lines are parsed first, so an invalid one doesn't leave the group half-updated
+        let rules = rule
+            .lines()
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(parse_rule)
+            .collect::<anyhow::Result<Vec<_>>>()?;
         // get or create group
         let group = self
             .groups
             .entry(group_name.to_string())
             .or_insert_with(|| SecurityGroup::new(group_name));
-        for r in rule.lines() {
-            if r.trim().len() > 0 {
-                group.add(Rule::parse(r.trim())?);
-            }
-        }
-        let index = group.count() - 1;
+        let total = rules.len();
+        let indexes = group.append(rules, dedupe);
+        let skipped = total - indexes.len();
+        self.touch(group_name);
         self.save();
-        Ok(index)
+        Ok(CreatedRules { indexes, skipped })
     }
 
-    // function to list all rules for a given group
+    // function to list all rules for a given group,
+    // optionally only the indexed (fast) or only the non-indexed (slow) ones
     #[instrument(skip(self))]
-    pub fn list_rules_as_str(&self, group_name: &str, tags: &TagMap) -> anyhow::Result<String> {
+    pub fn list_rules(&self, group_name: &str, tags: &TagMap, indexed: Option<bool>) -> Vec<Rule> {
         let group = match self.groups.get(group_name) {
             Some(x) => x,
-            None => return Ok("".to_string()), // no rules if there is no group
+            None => return vec![], // no rules if there is no group
         };
 
-        let mut out = "".to_string();
-        group
+        let with_indexed = indexed != Some(false);
+        let with_non_indexed = indexed != Some(true);
+        let mut out: Vec<Rule> = group
             .list_indexed()
+            .filter(|_| with_indexed)
+            .chain(group.list_non_indexed().filter(|_| with_non_indexed))
             .filter(|r| tags.matches(&r.tags))
-            .for_each(|r| {
-                out.push_str(&r.to_string());
-                out.push_str("\n");
-            });
-        group
-            .list_non_indexed()
-            .filter(|r| tags.matches(&r.tags))
-            .for_each(|r| {
-                out.push_str(&r.to_string());
-                out.push_str("\n");
-            });
+            .cloned()
+            .collect();
+        // compact rules are looked up without the linear scan, so they are listed as indexed
+        if let Some(compact) = group.compact().filter(|_| with_indexed) {
+            out.extend(compact.rules().filter(|r| tags.matches(&r.tags)));
+        }
+        out
+    }
 
-        Ok(out)
+    // same as list_rules, in plain text with one rule per line
+    pub fn list_rules_as_str(
+        &self,
+        group_name: &str,
+        tags: &TagMap,
+        indexed: Option<bool>,
+    ) -> anyhow::Result<String> {
+        Ok(self
+            .list_rules(group_name, tags, indexed)
+            .iter()
+            .map(|r| format!("{}\n", r))
+            .collect())
+    }
+
+    // function to count rules for a given group, without listing them
+    pub fn count_rules(&self, group_name: &str) -> RulesCount {
+        match self.groups.get(group_name) {
+            Some(group) => RulesCount {
+                total: group.count(),
+                indexed: group.list_indexed().count(),
+                non_indexed: group.list_non_indexed().count(),
+                compact: group.compact().map(|c| c.len()).unwrap_or(0),
+            },
+            None => RulesCount::default(), // no rules if there is no group
+        }
+    }
+
+    // function to tell whether the group was created or loaded from the storage
+    pub fn group_exists(&self, group_name: &str) -> bool {
+        self.groups.contains_key(group_name)
+    }
+
+    // function to list all security groups, sorted by name
+    pub fn list_groups(&self) -> Vec<GroupInfo> {
+        self.groups
+            .iter()
+            .map(|(name, group)| GroupInfo {
+                name: name.clone(),
+                rules: group.count(),
+            })
+            .collect()
+    }
+
+    // function to list tags of the given group, sorted by the tag
+    pub fn list_tags(&self, group_name: &str) -> Vec<TagInfo> {
+        let group = match self.groups.get(group_name) {
+            Some(x) => x,
+            None => return vec![], // no tags if there is no group
+        };
+        group
+            .count_tags()
+            .into_iter()
+            .map(|(tag, rules)| TagInfo { tag, rules })
+            .collect()
     }
 
     // function to update rule by its index for a given group
@@ -149,18 +534,19 @@ impl SecurityGroupService {
         let group = self
             .groups
             .get_mut(group_name)
-            .ok_or_else(|| anyhow!("group {} not found", group_name))?;
+            .ok_or_else(|| GuardError::NotFound(format!("group {}", group_name)))?;
         match rule_ref {
             RulesRef::All => {
                 bail!("please use index or tag to update rule");
             }
             RulesRef::Index(index) => {
                 if *index >= group.count() {
-                    bail!("index {} out of range", index);
+                    return Err(GuardError::NotFound(format!("rule {}", index)).into());
                 }
-                group.set_by_index(*index, Rule::parse(input)?);
+                group.set_by_index(*index, parse_rule(input)?);
             }
             RulesRef::Tag(tag) => {
+                group.expand();
                 let mut indexes = vec![];
                 for (index, r) in group.list_indexed().enumerate() {
                     if tag.matches(&r.tags) {
@@ -172,11 +558,12 @@ impl SecurityGroupService {
                         indexes.push(index + group.list_indexed().count());
                     }
                 }
-                if indexes.len() > 0 {
-                    group.set_many(indexes.into_iter(), Rule::parse(input)?);
+                if !indexes.is_empty() {
+                    group.set_many(indexes.into_iter(), parse_rule(input)?);
                 }
             }
         }
+        self.touch(group_name);
         self.save();
         Ok(())
     }
@@ -197,11 +584,12 @@ impl SecurityGroupService {
             }
             RulesRef::Index(index) => {
                 if *index >= group.count() {
-                    bail!("index {} out of range", index);
+                    return Err(GuardError::NotFound(format!("rule {}", index)).into());
                 }
                 group.remove_by_index(*index);
             }
             RulesRef::Tag(tag) => {
+                group.expand();
                 let mut indexes = vec![];
                 for (index, r) in group.list_indexed().enumerate() {
                     if tag.matches(&r.tags) {
@@ -213,15 +601,54 @@ impl SecurityGroupService {
                         indexes.push(index + group.list_indexed().count());
                     }
                 }
-                if indexes.len() > 0 {
+                if !indexes.is_empty() {
                     group.remove_many(indexes.into_iter());
                 }
             }
         };
+        self.touch(group_name);
         self.save();
         Ok(())
     }
 
+    // function to disable or enable back the rule by its index, returns whether the rule is disabled now
+    #[instrument(skip(self))]
+    pub fn toggle_rule(&mut self, group_name: &str, index: usize) -> anyhow::Result<bool> {
+        let group = self
+            .groups
+            .get_mut(group_name)
+            .ok_or_else(|| GuardError::NotFound(format!("group {}", group_name)))?;
+        let disabled = group
+            .toggle_by_index(index)
+            .ok_or_else(|| GuardError::NotFound(format!("rule {}", index)))?;
+        self.touch(group_name);
+        self.save();
+        Ok(disabled)
+    }
+
+    // function to rename the tag on all rules of the group, returns the number of rules touched
+    #[instrument(skip(self))]
+    pub fn rename_tag(&mut self, group_name: &str, from: &str, to: &str) -> anyhow::Result<usize> {
+        // separators of the rule line and the tag filters can't be a part of the tag
+        let valid = !to.is_empty()
+            && !to.starts_with('-')
+            && !to.contains([',', '#', '|', '+'])
+            && !to.contains(char::is_whitespace);
+        if !valid {
+            return Err(GuardError::parse(to, anyhow!("invalid tag name")).into());
+        }
+        let group = self
+            .groups
+            .get_mut(group_name)
+            .ok_or_else(|| GuardError::NotFound(format!("group {}", group_name)))?;
+        let count = group.rename_tag(from, to);
+        if count > 0 {
+            self.touch(group_name);
+            self.save();
+        }
+        Ok(count)
+    }
+
     // function to react on visitor by checking all rules for a given group
     #[instrument(skip(self), ret, level = "debug")]
     pub fn react<V: Visitor + std::fmt::Debug>(
@@ -229,37 +656,784 @@ impl SecurityGroupService {
         group_name: &str,
         visitor: &V,
     ) -> anyhow::Result<Reaction> {
+        let (reaction, _) = self.react_explain(group_name, visitor)?;
+        Ok(reaction)
+    }
+
+    // function to react on visitor, also returning the details of the rule that fired.
+    // No details are returned when the fallback reaction is used
+    #[instrument(skip(self), level = "debug")]
+    pub fn react_explain<V: Visitor + std::fmt::Debug>(
+        &self,
+        group_name: &str,
+        visitor: &V,
+    ) -> anyhow::Result<(Reaction, Option<MatchInfo>)> {
         let group = match self.groups.get(group_name) {
             Some(x) => x,
-            None => return Ok(Reaction::HttpStatus(200)), // no rules if there is no group
+            None => return Ok((Reaction::HttpStatus(200), None)), // no rules if there is no group
         };
-        let indexes = visitor_index_keys(visitor);
-        for index in indexes {
-            if let Some(reaction) = group.map_indexed.get(&index) {
-                return Ok(reaction.clone());
+        if self.scheduled.contains(group_name) {
+            return Ok(Self::react_group(group, visitor));
+        }
+        let react = || Self::react_group(group, visitor);
+        Ok(self.cache.get_or_insert_with(group_name, visitor, react))
+    }
+
+    // reaction of the group on the visitor with the rule that produced it, if any
+    fn react_group<V: Visitor>(
+        group: &SecurityGroup,
+        visitor: &V,
+    ) -> (Reaction, Option<MatchInfo>) {
+        if let (Some(compact), IpAddr::V4(ip)) = (group.compact(), visitor.ip()) {
+            if let Some(rule) = compact.react(ip) {
+                let info = MatchInfo::new(&rule, Lookup::Compact);
+                return (rule.reaction.clone(), Some(info));
             }
         }
-        for rule in group.list_non_indexed() {
+        // rules are evaluated in the order of the file: the earliest indexed match
+        // is taken, unless a non-indexed rule before it reacts
+        let keys = IndexKeys::new(visitor);
+        let indexed = keys
+            .iter()
+            .filter_map(|key| group.get_indexed_ordered(key))
+            .chain(group.get_network_ordered(visitor.ip()))
+            .filter(|(_, r)| !r.disabled)
+            .min_by_key(|(order, _)| *order);
+        let before = indexed.map(|(order, _)| order).unwrap_or(usize::MAX);
+        for (_, rule) in group
+            .list_non_indexed_ordered()
+            .take_while(|(order, _)| *order < before)
+        {
             if let Some(reaction) = rule.react(visitor) {
-                return Ok(reaction.clone());
+                return (reaction, Some(MatchInfo::new(rule, Lookup::Scan)));
             }
         }
-        // fallback to no reaction
-        Ok(Reaction::HttpStatus(200))
+        if let Some((_, rule)) = indexed {
+            let info = MatchInfo::new(rule, Lookup::Indexed);
+            return (rule.reaction.clone(), Some(info));
+        }
+        // fallback to the default of the group, allowed if not set
+        let reaction = group
+            .default_reaction
+            .clone()
+            .unwrap_or(Reaction::HttpStatus(200));
+        (reaction, None)
+    }
+
+    // function to react on visitor by a single rule, without adding it to any group.
+    // Returns the parsed rule and its reaction, none if the rule doesn't match
+    pub fn test_rule<V: Visitor>(
+        line: &str,
+        visitor: &V,
+    ) -> anyhow::Result<(Rule, Option<Reaction>)> {
+        let line = line.trim();
+        if line.contains('\n') {
+            return Err(GuardError::parse(line, anyhow!("one rule line is expected")).into());
+        }
+        let rule = parse_rule(line)?;
+        let reaction = rule.react(visitor);
+        Ok((rule, reaction))
+    }
+}
+
+// quick lookup keys of the visitor: ip, country, path with the trailing slash and the path.
+// Both paths share one string, as this is built for every request
+struct IndexKeys {
+    ip: String,
+    country: Option<String>,
+    path: String,
+    slash_added: bool,
+}
+
+impl IndexKeys {
+    fn new(visitor: &impl Visitor) -> Self {
+        let mut path = match visitor.paths_ignore_case() {
+            // indexed paths are lowercase only
+            true => visitor.uri().to_lowercase(),
+            false => visitor.uri(),
+        };
+        let slash_added = !path.is_empty() && !path.ends_with('/');
+        if slash_added {
+            path.push('/');
+        }
+        Self {
+            ip: visitor.ip().to_string(),
+            country: visitor.country(),
+            path,
+            slash_added,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &str> {
+        let path = match self.slash_added {
+            true => &self.path[..self.path.len() - 1],
+            false => self.path.as_str(),
+        };
+        [
+            Some(self.ip.as_str()),
+            self.country.as_deref(),
+            self.slash_added.then_some(self.path.as_str()),
+            Some(path),
+        ]
+        .into_iter()
+        .flatten()
     }
 }
 
-fn visitor_index_keys(visitor: &impl Visitor) -> Vec<String> {
-    let uri = visitor.uri();
-    let mut keys = vec![visitor.ip().to_string()];
-    if let Some(country) = visitor.country() {
-        keys.push(country.to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visitor::Visit;
+
+    fn visit(country: &str, uri: &str) -> Visit {
+        Visit {
+            country: Some(country.to_string()),
+            ..Visit::no_ip(uri)
+        }
+    }
+
+    #[test]
+    fn test_index_keys() {
+        let keys = IndexKeys::new(&visit("US", "/admin"));
+        assert_eq!(
+            keys.iter().collect::<Vec<_>>(),
+            ["127.0.0.1", "US", "/admin/", "/admin"]
+        );
+        let keys = IndexKeys::new(&Visit::no_ip("/"));
+        assert_eq!(keys.iter().collect::<Vec<_>>(), ["127.0.0.1", "/"]);
+    }
+
+    #[test]
+    fn test_toggle_rule() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "!403|US\n401|/admin", false)
+            .unwrap();
+        assert_eq!(svc.react("default", &visit("US", "/")).unwrap().code(), 200);
+
+        assert!(!svc.toggle_rule("default", 0).unwrap());
+        assert_eq!(svc.react("default", &visit("US", "/")).unwrap().code(), 403);
+
+        assert!(svc.toggle_rule("default", 1).unwrap());
+        assert_eq!(
+            svc.react("default", &visit("GB", "/admin")).unwrap().code(),
+            200
+        );
+        let tm = TagMap::new();
+        assert_eq!(
+            svc.list_rules_as_str("default", &tm, None).unwrap(),
+            "403|US\n!401|/admin\n"
+        );
+        let e = svc.toggle_rule("default", 2).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<GuardError>(),
+            Some(&GuardError::NotFound("rule 2".to_string()))
+        );
+        let e = svc.toggle_rule("missing", 0).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<GuardError>(),
+            Some(&GuardError::NotFound("group missing".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_indexed_paths_ignore_case() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "401|/admin\n403|/Private", false)
+            .unwrap();
+        let visit = |uri: &str| Visit {
+            paths_ignore_case: true,
+            ..Visit::no_ip(uri)
+        };
+        for (uri, code) in [
+            ("/Admin", 401),
+            ("/ADMIN/", 401),
+            ("/private", 403),
+            ("/", 200),
+        ] {
+            let (reaction, _) = svc.react_explain("default", &visit(uri)).unwrap();
+            assert_eq!(reaction.code(), code, "{}", uri);
+        }
+        assert_eq!(
+            svc.react("default", &Visit::no_ip("/Admin"))
+                .unwrap()
+                .code(),
+            200
+        );
+    }
+
+    #[test]
+    fn test_rename_tag() {
+        let mut svc = SecurityGroupService::default();
+        let rules = "403|US#temp\n401|/admin#temporary,temp\n403|GB#geo,temp,temp\n403|FR#geo";
+        svc.create_rule("default", rules, false).unwrap();
+        assert_eq!(svc.rename_tag("default", "temp", "temporary").unwrap(), 3);
+        let tm = TagMap::new();
+        assert_eq!(
+            svc.list_rules_as_str("default", &tm, None).unwrap(),
+            "403|US#temporary\n401|/admin#temporary\n403|GB#geo,temporary\n403|FR#geo\n"
+        );
+        assert_eq!(svc.rename_tag("default", "temp", "temporary").unwrap(), 0);
+        assert!(svc.rename_tag("default", "geo", "a,b").is_err());
+        assert!(svc.rename_tag("default", "geo", "").is_err());
+        let e = svc.rename_tag("missing", "geo", "country").unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<GuardError>(),
+            Some(&GuardError::NotFound("group missing".to_string()))
+        );
     }
-    if let Some(last_char) = uri.chars().last() {
-        if last_char != '/' {
-            keys.push(format!("{}/", uri));
+
+    #[test]
+    fn test_list_tags() {
+        let mut svc = SecurityGroupService::default();
+        let rules = "403|US#blacklist,temporary\n401|/admin#blacklist\n403|^/wp-#scanners\n403|GB";
+        svc.create_rule("default", rules, false).unwrap();
+        let tags: Vec<(String, usize)> = svc
+            .list_tags("default")
+            .into_iter()
+            .map(|t| (t.tag, t.rules))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                ("blacklist".to_string(), 2),
+                ("scanners".to_string(), 1),
+                ("temporary".to_string(), 1),
+            ]
+        );
+        assert!(svc.list_tags("missing").is_empty());
+    }
+
+    #[test]
+    fn test_delete_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut svc = SecurityGroupService::with_store(Box::new(FileStore::new(
+            dir.path().to_str().unwrap(),
+        )));
+        svc.create_rule("default", "403|US", false).unwrap();
+        svc.create_rule("admin", "401|/admin", false).unwrap();
+        let file = dir.path().join("default.rules.txt");
+        assert!(file.exists());
+
+        assert!(svc.delete_group("default").unwrap());
+        assert!(!file.exists());
+        svc.save();
+        assert!(!file.exists());
+        assert!(dir.path().join("admin.rules.txt").exists());
+        assert!(!svc.delete_group("default").unwrap());
+    }
+
+    #[test]
+    fn test_delete_group_keeps_it_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut svc = SecurityGroupService::with_store(Box::new(FileStore::new(
+            dir.path().to_str().unwrap(),
+        )));
+        svc.create_rule("default", "403|US", false).unwrap();
+        // a directory in place of the rules file cannot be removed as a file
+        let file = dir.path().join("default.rules.txt");
+        std::fs::remove_file(&file).unwrap();
+        std::fs::create_dir(&file).unwrap();
+        assert!(svc.delete_group("default").is_err());
+        assert_eq!(svc.count_rules("default").total, 1);
+    }
+
+    #[test]
+    fn test_delete_all_rules_saves_empty_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut svc = SecurityGroupService::with_store(Box::new(FileStore::new(
+            dir.path().to_str().unwrap(),
+        )));
+        svc.create_rule("default", "403|US\n401|/admin", false)
+            .unwrap();
+        svc.delete_rule("default", &RulesRef::All).unwrap();
+        assert_eq!(svc.count_rules("default").total, 0);
+        let saved = std::fs::read_to_string(dir.path().join("default.rules.txt")).unwrap();
+        assert_eq!(saved, "");
+    }
+
+    #[test]
+    fn test_create_rule_returns_indexes() {
+        let mut svc = SecurityGroupService::default();
+        let created = svc.create_rule("default", "403|US\n403|^/api", false);
+        assert_eq!(created.unwrap().indexes, [0, 1]);
+        let posted = ["401|^/admin", "403|CN", "403|^/private", "403|GB"];
+        let created = svc.create_rule("default", &posted.join("\n"), false);
+        let indexes = created.unwrap().indexes;
+        assert_eq!(indexes, [4, 1, 5, 2]);
+        let listed = svc
+            .list_rules_as_str("default", &TagMap::new(), None)
+            .unwrap();
+        let listed: Vec<&str> = listed.lines().collect();
+        for (rule, index) in posted.iter().zip(indexes) {
+            assert_eq!(listed[index], *rule);
         }
+        // the returned index addresses the rule for the later update
+        svc.update_rule("default", &RulesRef::Index(4), "401|^/root")
+            .unwrap();
+        assert!(!svc
+            .list_rules_as_str("default", &TagMap::new(), None)
+            .unwrap()
+            .contains("/admin"));
+    }
+
+    #[test]
+    fn test_create_rule_dedupe() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|US\n401|^/admin#ops", false)
+            .unwrap();
+        let posted = "403|US\n401|^/admin#ops\n401|^/admin\n403|CN\n403|CN";
+        let created = svc.create_rule("default", posted, true).unwrap();
+        assert_eq!(created.skipped, 3);
+        assert_eq!(created.indexes, [3, 1]);
+        assert_eq!(svc.count_rules("default").total, 4);
+        // without dedupe, the same rules are added again
+        let created = svc.create_rule("default", "403|US", false).unwrap();
+        assert_eq!(created.skipped, 0);
+        assert_eq!(svc.count_rules("default").total, 5);
+    }
+
+    #[test]
+    fn test_create_rule_unknown_country() {
+        let mut svc = SecurityGroupService::default();
+        let err = svc
+            .create_rule("default", "403|US\n403|UK", false)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid rule \"403|UK\": unknown country code \"UK\""
+        );
+        assert!(!svc.group_exists("default"));
+    }
+
+    #[test]
+    fn test_create_rule_rejects_default_line() {
+        let mut svc = SecurityGroupService::default();
+        let err = svc
+            .create_rule("default", "403|US\n!default 403", false)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GuardError>(),
+            Some(GuardError::Parse { .. })
+        ));
+        assert!(!svc.group_exists("default"));
+        svc.create_rule("default", "403|US", false).unwrap();
+        let index = RulesRef::Index(0);
+        assert!(svc.update_rule("default", &index, "!default 403").is_err());
+        assert_eq!(
+            svc.list_rules_as_str("default", &TagMap::new(), None)
+                .unwrap(),
+            "403|US\n"
+        );
+    }
+
+    #[test]
+    fn test_react_in_file_order() {
+        let visit = Visit {
+            ip: "8.8.8.8".parse().unwrap(),
+            country: Some("US".to_string()),
+            ..Visit::no_ip("/admin")
+        };
+        let react = |rules: &str| {
+            let mut svc = SecurityGroupService::default();
+            svc.create_rule("default", rules, false).unwrap();
+            svc.react("default", &visit).unwrap().code()
+        };
+        // non-indexed rule before the indexed one
+        assert_eq!(react("403|*\n200|8.8.8.8"), 403);
+        assert_eq!(react("200|8.8.8.8\n403|*"), 200);
+        // the earliest of the indexed rules matching different keys
+        assert_eq!(react("403|US\n200|8.8.8.8"), 403);
+        assert_eq!(react("200|8.8.8.8\n403|US"), 200);
+        // the earliest of the indexed rules with the same key
+        assert_eq!(react("403|/admin\n401|/admin"), 403);
+
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|/admin\n401|/admin", false)
+            .unwrap();
+        svc.toggle_rule("default", 0).unwrap();
+        assert_eq!(svc.react("default", &visit).unwrap().code(), 401);
+        // updated rule keeps its place
+        svc.toggle_rule("default", 0).unwrap();
+        svc.update_rule("default", &RulesRef::Index(0), "402|/admin")
+            .unwrap();
+        assert_eq!(svc.react("default", &visit).unwrap().code(), 402);
+    }
+
+    #[test]
+    fn test_react_with_default_reaction() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("open", "403|CN", false).unwrap();
+        svc.create_rule("closed", "200|US", false).unwrap();
+        svc.groups.get_mut("closed").unwrap().default_reaction = Some(Reaction::HttpStatus(403));
+        let react = |group: &str, country: &str| svc.react(group, &visit(country, "/")).unwrap();
+        assert_eq!(react("open", "CN"), Reaction::HttpStatus(403));
+        assert_eq!(react("open", "GB"), Reaction::HttpStatus(200));
+        assert_eq!(react("closed", "US"), Reaction::HttpStatus(200));
+        assert_eq!(react("closed", "GB"), Reaction::HttpStatus(403));
+        // the default is kept through the export
+        let mut imported = SecurityGroupService::default();
+        imported.import_json(svc.export_json()).unwrap();
+        assert_eq!(
+            imported.groups["closed"].default_reaction,
+            Some(Reaction::HttpStatus(403))
+        );
+        assert_eq!(imported.groups["open"].default_reaction, None);
+    }
+
+    #[test]
+    fn test_seed_group() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|CN", false).unwrap();
+        svc.create_rule("admin", "401|/admin", false).unwrap();
+        let count = svc.seed_group("default", "# inline\n403|US\n\n403|/debug\n");
+        assert_eq!(count, 2);
+        assert_eq!(
+            svc.list_rules_as_str("default", &TagMap::new(), None)
+                .unwrap(),
+            "403|US\n403|/debug\n"
+        );
+        assert_eq!(svc.count_rules("admin").total, 1);
+    }
+
+    #[test]
+    fn test_save_skips_inline_and_unchanged_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        // the trailing spaces would be trimmed if the file was written back
+        let default = "403|US \n";
+        let admin = "401|/admin \n";
+        std::fs::write(dir.path().join("default.rules.txt"), default).unwrap();
+        std::fs::write(dir.path().join("admin.rules.txt"), admin).unwrap();
+        let mut svc = SecurityGroupService::from_store(Box::new(FileStore::new(path))).unwrap();
+        svc.seed_group("default", "403|CN");
+        svc.create_rule("default", "403|GB", false).unwrap();
+        svc.create_rule("api", "403|/debug", false).unwrap();
+        svc.save();
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("default.rules.txt"), default);
+        assert_eq!(read("admin.rules.txt"), admin);
+        assert_eq!(read("api.rules.txt"), "403|/debug\n");
+        assert_eq!(svc.count_rules("default").total, 2);
+
+        // the group is written once it is changed through the API
+        svc.toggle_rule("admin", 0).unwrap();
+        assert_eq!(read("admin.rules.txt"), "!401|/admin\n");
+    }
+
+    #[test]
+    fn test_reaction_cache_keeps_decisions() {
+        let rules = "403|CN\n401|/admin\n403|10.0.0.0/8\n451|~^/old\n403|?bot=1";
+        let visits: Vec<Visit> = (0..64u8)
+            .flat_map(|i| {
+                let country = ["CN", "US", "DE"][i as usize % 3];
+                ["/", "/admin", "/old/a", "/x?bot=1"].map(|uri| Visit {
+                    ip: IpAddr::from([10 + i % 2, 0, 0, i]),
+                    ..visit(country, uri)
+                })
+            })
+            .collect();
+        let decide = |svc: &SecurityGroupService| -> Vec<(u16, Option<String>)> {
+            visits
+                .iter()
+                .map(|v| {
+                    let (reaction, info) = svc.react_explain("default", v).unwrap();
+                    (reaction.code(), info.map(|i| i.rule))
+                })
+                .collect()
+        };
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", rules, false).unwrap();
+        let expected = decide(&svc);
+        // small cache, so the reactions are evicted too
+        for shards in [1, 4, 16] {
+            svc.set_reaction_cache(100, shards);
+            assert_eq!(decide(&svc), expected, "{} shards", shards);
+            assert_eq!(decide(&svc), expected, "{} shards, cached", shards);
+            assert!(!svc.cache.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_reaction_cache_follows_rules() {
+        let mut svc = SecurityGroupService::default();
+        svc.set_reaction_cache(1000, 4);
+        svc.create_rule("default", "401|/admin", false).unwrap();
+        let v = visit("US", "/");
+        assert_eq!(svc.react("default", &v).unwrap().code(), 200);
+        assert_eq!(svc.cache.len(), 1);
+        svc.create_rule("default", "403|US", false).unwrap();
+        assert!(svc.cache.is_empty());
+        assert_eq!(svc.react("default", &v).unwrap().code(), 403);
+        svc.toggle_rule("default", 1).unwrap();
+        assert_eq!(svc.react("default", &v).unwrap().code(), 200);
+
+        // scheduled rules depend on the time of the visit
+        svc.create_rule("night", "403|*,@22:00-06:00", false)
+            .unwrap();
+        let noon = Visit {
+            time: "2024-01-01T12:00:00Z".parse().unwrap(),
+            ..visit("US", "/")
+        };
+        assert_eq!(svc.react("night", &noon).unwrap().code(), 200);
+        assert!(svc.cache.is_empty());
+    }
+
+    #[test]
+    fn test_react_on_ipv6() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|2001:db8::1\n401|2001:db8::/32", false)
+            .unwrap();
+        let visit = |ip: &str| Visit {
+            ip: ip.parse().unwrap(),
+            ..Visit::no_ip("/")
+        };
+        let keys = IndexKeys::new(&visit("2001:db8::1"));
+        assert_eq!(keys.iter().next(), Some("2001:db8::1"));
+        assert_eq!(
+            svc.react("default", &visit("2001:db8::1")).unwrap().code(),
+            403
+        );
+        assert_eq!(
+            svc.react("default", &visit("2001:db8::2")).unwrap().code(),
+            401
+        );
+        assert_eq!(
+            svc.react("default", &visit("2001:db9::1")).unwrap().code(),
+            200
+        );
+    }
+
+    #[test]
+    fn test_react_on_query_with_path() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|/a,?bot=1", false).unwrap();
+        assert_eq!(
+            svc.react("default", &visit("US", "/a")).unwrap().code(),
+            403
+        );
+        let v = visit("US", "/x?bot=1");
+        assert_eq!(svc.react("default", &v).unwrap().code(), 403);
+        let v = visit("US", "/x?bot=0");
+        assert_eq!(svc.react("default", &v).unwrap().code(), 200);
+    }
+
+    #[test]
+    fn test_react_on_regex_with_path() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|/a,~^/b", false).unwrap();
+        assert_eq!(
+            svc.react("default", &visit("US", "/a")).unwrap().code(),
+            403
+        );
+        assert_eq!(
+            svc.react("default", &visit("US", "/b")).unwrap().code(),
+            403
+        );
+        assert_eq!(
+            svc.react("default", &visit("US", "/c")).unwrap().code(),
+            200
+        );
+    }
+
+    #[test]
+    fn test_react_on_glob_with_path() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule("default", "403|/a,/api/*/x", false)
+            .unwrap();
+        assert_eq!(
+            svc.react("default", &visit("US", "/a")).unwrap().code(),
+            403
+        );
+        let v = visit("US", "/api/q/x");
+        assert_eq!(svc.react("default", &v).unwrap().code(), 403);
+        let v = visit("US", "/api/q/y");
+        assert_eq!(svc.react("default", &v).unwrap().code(), 200);
+    }
+
+    #[test]
+    fn test_react_on_indexed_networks() {
+        let mut svc = SecurityGroupService::default();
+        let rules = "451|~^/old\n!403|10.0.0.0/8\n401|10.1.0.0/16,2001:db8::/32\n403|US,1.0.0.0/8";
+        svc.create_rule("default", rules, false).unwrap();
+        let visit = |ip: &str, uri: &str| Visit {
+            ip: ip.parse().unwrap(),
+            country: Some("DE".to_string()),
+            ..Visit::no_ip(uri)
+        };
+        let explain = |v: &Visit| {
+            let (reaction, info) = svc.react_explain("default", v).unwrap();
+            (reaction.code(), info.map(|i| i.lookup))
+        };
+        assert_eq!(
+            explain(&visit("10.1.2.3", "/")),
+            (401, Some(Lookup::Indexed))
+        );
+        assert_eq!(
+            explain(&visit("2001:db8::5", "/")),
+            (401, Some(Lookup::Indexed))
+        );
+        // the network is a part of the rule with the country
+        assert_eq!(
+            explain(&visit("1.2.3.4", "/")),
+            (403, Some(Lookup::Indexed))
+        );
+        // non-indexed rule before the network rule is checked first
+        assert_eq!(
+            explain(&visit("10.1.2.3", "/old")),
+            (451, Some(Lookup::Scan))
+        );
+        assert_eq!(explain(&visit("10.2.0.1", "/")), (200, None));
+    }
+
+    #[test]
+    fn test_export_import_json() {
+        let mut svc = SecurityGroupService::default();
+        let rules = [
+            "403|US,-1.2.3.4#geo",
+            "429|/api|ratelimit:60:10",
+            "301|//www.example.com|https://example.com{uri}",
+            "!401|POST,~^/admin/[0-9]+$,?debug",
+            "451|GB|X-Reason:legal;X-Team:sec",
+        ]
+        .join("\n");
+        svc.create_rule("default", &rules, false).unwrap();
+        svc.create_rule("admin", "401|/admin", false).unwrap();
+
+        let exported = svc.export_json();
+        assert_eq!(exported["groups"][0]["name"], "admin");
+        assert_eq!(exported["groups"][0]["rules"][0]["reaction"]["code"], 401);
+        let mut imported = SecurityGroupService::default();
+        assert_eq!(imported.import_json(exported.clone()).unwrap(), 2);
+        // importing the same export again changes nothing
+        assert_eq!(imported.import_json(exported.clone()).unwrap(), 2);
+        assert_eq!(imported.export_json(), exported);
+        let tm = TagMap::new();
+        for group in ["default", "admin"] {
+            assert_eq!(
+                imported.list_rules_as_str(group, &tm, None).unwrap(),
+                svc.list_rules_as_str(group, &tm, None).unwrap()
+            );
+        }
+        assert!(imported
+            .import_json(serde_json::json!({"groups": 1}))
+            .is_err());
+    }
+
+    #[test]
+    fn test_reload_all_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_path = dir.path().to_str().unwrap().to_string();
+        std::fs::write(dir.path().join("web.rules.txt"), "403|US\n").unwrap();
+        std::fs::write(dir.path().join("api.rules.txt"), "403|US\n").unwrap();
+        let mut svc =
+            SecurityGroupService::from_store(Box::new(FileStore::new(&storage_path))).unwrap();
+
+        std::fs::remove_file(dir.path().join("api.rules.txt")).unwrap();
+        std::fs::write(dir.path().join("web.rules.txt"), "403|US\n401|GB\n").unwrap();
+        std::fs::write(
+            dir.path().join("admin.rules.txt"),
+            "403|/a\n40x|/b\n401|/c\n",
+        )
+        .unwrap();
+        let report = svc.reload().unwrap();
+        assert_eq!(report.groups, 2);
+        assert_eq!(report.rules, 4);
+        assert!(!svc.groups.contains_key("api"));
+        assert_eq!(svc.count_rules("web").total, 2);
+        let admin_errors = &report.errors["admin"];
+        assert_eq!(admin_errors.len(), 1);
+        assert!(admin_errors[0].contains("admin.rules.txt:2"));
+        assert!(!report.errors.contains_key("web"));
+    }
+
+    #[test]
+    fn test_reload_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("default.rules.txt");
+        let file_name = file.to_str().unwrap();
+        std::fs::write(&file, "403|US\n").unwrap();
+        let store = FileStore::new(dir.path().to_str().unwrap());
+        let mut svc = SecurityGroupService::from_store(Box::new(store)).unwrap();
+        assert_eq!(svc.count_rules("default").total, 1);
+
+        std::fs::write(&file, "403|US\n401|GB\n").unwrap();
+        assert_eq!(svc.reload_file(file_name), Some(("default".to_string(), 2)));
+        assert_eq!(svc.count_rules("default").total, 2);
+
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(svc.reload_file(file_name), Some(("default".to_string(), 0)));
+        assert!(!svc.groups.contains_key("default"));
+        assert_eq!(svc.reload_file(&format!("{}.1.tmp", file_name)), None);
+    }
+
+    #[test]
+    fn test_reload_keeps_inline_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("default.rules.txt");
+        let file_name = file.to_str().unwrap();
+        std::fs::write(&file, "403|US\n").unwrap();
+        let store = FileStore::new(dir.path().to_str().unwrap());
+        let mut svc = SecurityGroupService::from_store(Box::new(store)).unwrap();
+        svc.seed_group("default", "403|CN\n401|/admin");
+
+        std::fs::write(&file, "403|US\n401|GB\n403|DE\n").unwrap();
+        assert_eq!(svc.reload().unwrap().rules, 2);
+        assert_eq!(svc.reload_file(file_name), None);
+        assert_eq!(svc.count_rules("default").total, 2);
+        // the inline group is still never written
+        svc.create_rule("default", "403|GB", false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&file).unwrap(),
+            "403|US\n401|GB\n403|DE\n"
+        );
+    }
+
+    #[test]
+    fn test_count_rules() {
+        let mut svc = SecurityGroupService::default();
+        assert_eq!(svc.count_rules("default").total, 0);
+        svc.create_rule("default", "403|US\n401|/admin\n403|-GB,^/api", false)
+            .unwrap();
+        assert_eq!(
+            svc.count_rules("default"),
+            RulesCount {
+                total: 3,
+                indexed: 2,
+                non_indexed: 1,
+                compact: 0,
+            }
+        );
+        svc.delete_rule("default", &RulesRef::Index(0)).unwrap();
+        assert_eq!(svc.count_rules("default").total, 2);
+        assert_eq!(svc.count_rules("default").indexed, 1);
+        svc.delete_rule("default", &RulesRef::All).unwrap();
+        assert_eq!(svc.count_rules("default").total, 0);
+    }
+
+    #[test]
+    fn test_list_by_index_eligibility() {
+        let mut svc = SecurityGroupService::default();
+        svc.create_rule(
+            "default",
+            "403|US\n401|/admin\n403|-GB,^/api\n302|^/old|/new",
+            false,
+        )
+        .unwrap();
+        let tm = TagMap::new();
+        assert_eq!(
+            svc.list_rules_as_str("default", &tm, Some(true)).unwrap(),
+            "403|US\n401|/admin\n"
+        );
+        assert_eq!(
+            svc.list_rules_as_str("default", &tm, Some(false)).unwrap(),
+            "403|-GB,^/api\n302|^/old|/new\n"
+        );
+        assert_eq!(
+            svc.list_rules_as_str("default", &tm, None)
+                .unwrap()
+                .lines()
+                .count(),
+            4
+        );
     }
-    keys.push(uri);
-    keys
 }
@@ -0,0 +1,181 @@
+use crate::error::GuardError;
+use crate::proto::SecurityGroup;
+use anyhow::Context;
+use std::collections::BTreeMap as Map;
+use std::fs;
+use tracing::*;
+
+// security groups read from the store, with the problems of each group.
+// The group that could not be read at all has only the problems
+#[derive(Default)]
+pub struct LoadedGroups {
+    pub groups: Map<String, SecurityGroup>,
+    pub errors: Map<String, Vec<String>>,
+}
+
+// storage of the security groups, shared by the service for loading and saving
+pub trait RuleStore: Send + Sync {
+    // name of the backend, as in --storage-backend
+    fn name(&self) -> &'static str;
+    fn load_all(&self) -> anyhow::Result<LoadedGroups>;
+    fn save_group(&self, name: &str, group: &SecurityGroup) -> anyhow::Result<()>;
+    // removing the group that is not stored is not an error
+    fn delete_group(&self, name: &str) -> anyhow::Result<()>;
+    // directory of the rules files, for the backends that keep them on the local disk
+    fn local_path(&self) -> Option<&str> {
+        None
+    }
+}
+
+// each security group is stored in a separate `{name}.rules.txt` file of the directory
+pub struct FileStore {
+    path: String,
+    // missing directory is created on load, instead of failing
+    create_missing: bool,
+}
+
+impl FileStore {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            create_missing: false,
+        }
+    }
+
+    pub fn create_missing(mut self, create: bool) -> Self {
+        self.create_missing = create;
+        self
+    }
+
+    fn file_name(&self, name: &str) -> String {
+        format!("{}/{}.rules.txt", self.path.trim_end_matches('/'), name)
+    }
+}
+
+impl RuleStore for FileStore {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn load_all(&self) -> anyhow::Result<LoadedGroups> {
+        let mut out = LoadedGroups::default();
+        if !std::path::Path::new(&self.path).exists() {
+            if !self.create_missing {
+                return Err(GuardError::MissingStorage(self.path.clone()).into());
+            }
+            info!("Creating storage path {}", self.path);
+            fs::create_dir_all(&self.path)
+                .with_context(|| format!("failed to create {}", self.path))?;
+            return Ok(out);
+        }
+        for path in fs::read_dir(&self.path).context("read dir")? {
+            let path = path.context("read path")?;
+            let full_file_name = format!("{}", path.path().display());
+            let file_name = path.file_name().to_string_lossy().to_string();
+            // skipping other files, e.g. temporary files of the interrupted saves
+            let basename = match file_name.strip_suffix(".rules.txt") {
+                Some(basename) => basename.to_string(),
+                None => continue,
+            };
+            match fs::File::open(&full_file_name) {
+                Ok(mut f) => {
+                    let (group, problems) =
+                        SecurityGroup::parse_source(&basename, &full_file_name, &mut f);
+                    if !problems.is_empty() {
+                        let problems = problems
+                            .iter()
+                            .map(|(origin, e)| format!("{}: {}", origin, e))
+                            .collect::<Vec<_>>();
+                        out.errors.insert(basename.clone(), problems);
+                    }
+                    out.groups.insert(basename, group);
+                }
+                Err(e) => {
+                    warn!("Failed to load group {}: {}", full_file_name, e);
+                    out.errors.insert(basename, vec![e.to_string()]);
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn save_group(&self, name: &str, group: &SecurityGroup) -> anyhow::Result<()> {
+        group.save_to_file(&self.file_name(name))
+    }
+
+    fn delete_group(&self, name: &str) -> anyhow::Result<()> {
+        let file_name = self.file_name(name);
+        match fs::remove_file(&file_name) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).context(format!("failed to remove {}", file_name)),
+        }
+    }
+
+    fn local_path(&self) -> Option<&str> {
+        Some(&self.path)
+    }
+}
+
+// nothing is stored, the groups live only as long as the process
+pub struct MemoryStore;
+
+impl RuleStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn load_all(&self) -> anyhow::Result<LoadedGroups> {
+        Ok(LoadedGroups::default())
+    }
+
+    fn save_group(&self, _name: &str, _group: &SecurityGroup) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn delete_group(&self, _name: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().to_str().unwrap());
+        let mut group = SecurityGroup::new("web");
+        group.add(crate::proto::Rule::parse("403|US").unwrap());
+        store.save_group("web", &group).unwrap();
+        std::fs::write(dir.path().join("bad.rules.txt"), "403|US\n403|~[\n").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "403|CN\n").unwrap();
+
+        let loaded = store.load_all().unwrap();
+        assert_eq!(loaded.groups.keys().collect::<Vec<_>>(), ["bad", "web"]);
+        assert_eq!(loaded.groups["web"].count(), 1);
+        assert_eq!(loaded.errors.keys().collect::<Vec<_>>(), ["bad"]);
+
+        store.delete_group("web").unwrap();
+        store.delete_group("web").unwrap();
+        assert!(!dir.path().join("web.rules.txt").exists());
+    }
+
+    #[test]
+    fn test_file_store_missing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let path = path.to_str().unwrap();
+        let err = FileStore::new(path).load_all().err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<GuardError>(),
+            Some(&GuardError::MissingStorage(path.to_string()))
+        );
+        assert!(err.to_string().contains("--create-storage"));
+
+        let store = FileStore::new(path).create_missing(true);
+        assert!(store.load_all().unwrap().groups.is_empty());
+        assert!(dir.path().join("data").is_dir());
+    }
+}
@@ -1,38 +1,67 @@
 use std::collections::HashMap as Map;
 
+// how the including tags are combined
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TagMode {
+    // rule has at least one of the tags, `a,b`
+    #[default]
+    Any,
+    // rule has all of the tags, `a+b`
+    All,
+}
+
 #[derive(Clone)]
 pub struct TagMap {
     pub including: Map<String, u8>,
     pub excluding: Map<String, u8>,
+    pub mode: TagMode,
 }
 
 impl std::fmt::Debug for TagMap {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut out: Vec<String> = Vec::new();
-        for (k, _) in &self.including {
+        for k in self.including.keys() {
             out.push(k.to_string());
         }
-        for (k, _) in &self.excluding {
+        let including = match self.mode {
+            TagMode::Any => out.join(","),
+            TagMode::All => out.join("+"),
+        };
+        let mut out = vec![including];
+        for k in self.excluding.keys() {
             out.push(format!("-{}", k));
         }
         write!(f, "{}", out.join(","))
     }
 }
 
+impl Default for TagMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TagMap {
     pub fn new() -> Self {
         Self {
             including: Map::new(),
             excluding: Map::new(),
+            mode: TagMode::Any,
         }
     }
 
+    /// parses `a,b,-c` list of tags. Tags joined with `+` (or space, as `+` is decoded
+    /// from the query string) are all required: `a+b,-c`
     pub fn from_query(input: &str) -> Self {
         let mut including = Map::new();
         let mut excluding = Map::new();
-        for tag in input.split(',') {
-            if tag.starts_with('-') {
-                excluding.insert(tag[1..].to_string(), 1);
+        let mode = match input.contains(['+', ' ']) {
+            true => TagMode::All,
+            false => TagMode::Any,
+        };
+        for tag in input.split([',', '+', ' ']) {
+            if let Some(tag) = tag.strip_prefix('-') {
+                excluding.insert(tag.to_string(), 1);
             } else {
                 including.insert(tag.to_string(), 1);
             }
@@ -40,6 +69,7 @@ impl TagMap {
         Self {
             including,
             excluding,
+            mode,
         }
     }
 
@@ -55,11 +85,44 @@ impl TagMap {
         if self.including.is_empty() {
             return true;
         }
-        for tag in tags {
-            if self.including.contains_key(tag) {
-                return true;
-            }
+        match self.mode {
+            TagMode::Any => tags.iter().any(|tag| self.including.contains_key(tag)),
+            TagMode::All => self.including.keys().all(|tag| tags.contains(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(list: &[&str]) -> Vec<String> {
+        list.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_any_of_tags() {
+        let tm = TagMap::from_query("blacklist,temporary");
+        assert_eq!(tm.mode, TagMode::Any);
+        assert!(tm.matches(&tags(&["blacklist"])));
+        assert!(tm.matches(&tags(&["temporary", "geo"])));
+        assert!(tm.matches(&tags(&["blacklist", "temporary"])));
+        assert!(!tm.matches(&tags(&["geo"])));
+        assert!(!tm.matches(&tags(&[])));
+    }
+
+    #[test]
+    fn test_all_of_tags() {
+        for query in ["blacklist+temporary", "blacklist temporary"] {
+            let tm = TagMap::from_query(query);
+            assert_eq!(tm.mode, TagMode::All);
+            assert!(tm.matches(&tags(&["blacklist", "temporary"])));
+            assert!(tm.matches(&tags(&["geo", "temporary", "blacklist"])));
+            assert!(!tm.matches(&tags(&["blacklist"])));
+            assert!(!tm.matches(&tags(&["temporary", "geo"])));
         }
-        false
+        let tm = TagMap::from_query("blacklist+temporary,-geo");
+        assert!(tm.matches(&tags(&["blacklist", "temporary"])));
+        assert!(!tm.matches(&tags(&["blacklist", "temporary", "geo"])));
     }
 }
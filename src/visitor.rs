@@ -4,6 +4,11 @@ use maxminddb::{geoip2, Reader};
 use std::net::{IpAddr, Ipv4Addr};
 use tracing::*;
 
+// query string of the URI, if any
+fn uri_query(uri: &str) -> Option<String> {
+    uri.split_once('?').map(|(_, q)| q.to_string())
+}
+
 fn nice_uri(uri: &str) -> String {
     let out = uri.to_string();
     if out.contains('?') {
@@ -13,30 +18,107 @@ fn nice_uri(uri: &str) -> String {
     out
 }
 
+// IPv4-mapped IPv6 address (::ffff:a.b.c.d) of dual-stack sockets is the IPv4 client
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip6) => match ip6.to_ipv4_mapped() {
+            Some(ip4) => IpAddr::V4(ip4),
+            None => IpAddr::V6(ip6),
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
 pub trait IntoVisitor {
-    fn visit(&self, ip: Ipv4Addr, uri: &str) -> anyhow::Result<Visit>;
+    fn visit(&self, ip: IpAddr, uri: &str) -> anyhow::Result<Visit>;
+}
+
+/// time of the latest change of the MaxMind databases (City and optional ASN) in the path
+pub fn maxmind_modified(path: &str) -> Option<std::time::SystemTime> {
+    ["GeoLite2-City.mmdb", "GeoLite2-ASN.mmdb"]
+        .iter()
+        .filter_map(|db| std::fs::metadata(format!("{}/{}", path, db)).ok())
+        .filter_map(|meta| meta.modified().ok())
+        .max()
+}
+
+// ASN database is optional, rules with ASN never match without it
+fn open_asn_reader(path: &str) -> Option<Reader<Vec<u8>>> {
+    let db = format!("{}/GeoLite2-ASN.mmdb", path);
+    if !std::path::Path::new(&db).exists() {
+        return None;
+    }
+    match Reader::open_readfile(&db) {
+        Ok(reader) => Some(reader),
+        Err(e) => {
+            warn!("cannot open ASN database {} {:?}", db, e);
+            None
+        }
+    }
+}
+
+fn lookup_asn(reader: Option<&Reader<Vec<u8>>>, ip: IpAddr) -> Option<u32> {
+    let asn: geoip2::Asn = reader?.lookup(ip).ok()?;
+    asn.autonomous_system_number
+}
+
+// ISO 3166-2 code of the top-level subdivision, e.g. "US-CA" for the state
+fn subdivision_code(gc: &geoip2::City) -> Option<String> {
+    let country = gc.country.as_ref()?.iso_code?;
+    let region = gc.subdivisions.as_ref()?.first()?.iso_code?;
+    Some(format!("{}-{}", country, region))
 }
 
 pub struct MmKeepInMemory {
-    reader: Reader<Vec<u8>>,
+    // no geo data without the City database, only IP, ASN and path rules can match
+    reader: Option<Reader<Vec<u8>>>,
+    asn_reader: Option<Reader<Vec<u8>>>,
 }
 
 impl MmKeepInMemory {
     pub fn new(path: &str) -> anyhow::Result<Self> {
         let db = format!("{}/GeoLite2-City.mmdb", path);
         let reader = Reader::open_readfile(db).context("open maxmind db")?;
-        Ok(Self { reader })
+        let asn_reader = open_asn_reader(path);
+        Ok(Self {
+            reader: Some(reader),
+            asn_reader,
+        })
+    }
+
+    // opens the databases, falling back to the reader without geo data if City database can't be opened
+    pub fn new_or_no_geo(path: &str) -> Self {
+        match Self::new(path) {
+            Ok(mm) => mm,
+            Err(e) => {
+                warn!("{:?}, country and city rules will never match", e);
+                Self {
+                    reader: None,
+                    asn_reader: open_asn_reader(path),
+                }
+            }
+        }
     }
 }
 
 impl IntoVisitor for MmKeepInMemory {
     #[instrument(skip(self), level = "debug")]
-    fn visit(&self, ip: Ipv4Addr, uri: &str) -> anyhow::Result<Visit> {
-        // convert Ipv4Addr into IpAddr
-        let gc: geoip2::City = self
-            .reader
-            .lookup(IpAddr::V4(ip))
-            .context("lookup ip in maxmind db")?;
+    fn visit(&self, ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
+        let asn = lookup_asn(self.asn_reader.as_ref(), ip);
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            // location is not unknown, it is just not looked up
+            None => {
+                return Ok(Visit {
+                    ip,
+                    asn,
+                    ..Visit::no_ip(uri)
+                })
+            }
+        };
+        let gc: geoip2::City = reader.lookup(ip).context("lookup ip in maxmind db")?;
+        let subdivision = subdivision_code(&gc);
+        let postal = gc.postal.as_ref().and_then(|p| p.code).map(str::to_string);
         let country: Option<String> = match gc.country {
             Some(c) => c.iso_code.map(|x| x.to_string()),
             None => None,
@@ -49,7 +131,18 @@ impl IntoVisitor for MmKeepInMemory {
             ip,
             country,
             city,
+            subdivision,
+            postal,
+            asn,
             uri: nice_uri(uri),
+            method: None,
+            geo_unknown: false,
+            host: None,
+            scheme: None,
+            query: uri_query(uri),
+            time: chrono::Utc::now(),
+            paths_ignore_case: false,
+            user_agent: None,
         })
     }
 }
@@ -68,14 +161,13 @@ impl MmFromDiskReader {
 
 impl IntoVisitor for MmFromDiskReader {
     #[instrument(skip(self), level = "debug")]
-    fn visit(&self, ip: Ipv4Addr, uri: &str) -> anyhow::Result<Visit> {
+    fn visit(&self, ip: IpAddr, uri: &str) -> anyhow::Result<Visit> {
         let db = format!("{}/GeoLite2-City.mmdb", self.path);
         let reader = Reader::open_readfile(db).context("open maxmind db")?;
 
-        // convert Ipv4Addr into IpAddr
-        let gc: geoip2::City = reader
-            .lookup(IpAddr::V4(ip))
-            .context("lookup ip in maxmind db")?;
+        let gc: geoip2::City = reader.lookup(ip).context("lookup ip in maxmind db")?;
+        let subdivision = subdivision_code(&gc);
+        let postal = gc.postal.as_ref().and_then(|p| p.code).map(str::to_string);
         let country: Option<String> = match gc.country {
             Some(c) => c.iso_code.map(|x| x.to_string()),
             None => None,
@@ -88,32 +180,76 @@ impl IntoVisitor for MmFromDiskReader {
             ip,
             country,
             city,
+            subdivision,
+            postal,
+            asn: lookup_asn(open_asn_reader(&self.path).as_ref(), ip),
             uri: nice_uri(uri),
+            method: None,
+            geo_unknown: false,
+            host: None,
+            scheme: None,
+            query: uri_query(uri),
+            time: chrono::Utc::now(),
+            paths_ignore_case: false,
+            user_agent: None,
         })
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Visit {
-    ip: Ipv4Addr,
-    country: Option<String>,
-    city: Option<String>,
-    uri: String,
+    pub(crate) ip: IpAddr,
+    pub(crate) country: Option<String>,
+    pub(crate) city: Option<String>,
+    pub(crate) subdivision: Option<String>,
+    pub(crate) postal: Option<String>,
+    // autonomous system number, if ASN database is available
+    pub(crate) asn: Option<u32>,
+    pub(crate) uri: String,
+    pub(crate) method: Option<String>,
+    // geo location lookup has failed
+    pub(crate) geo_unknown: bool,
+    pub(crate) host: Option<String>,
+    pub(crate) scheme: Option<String>,
+    // query is kept aside from the uri, for the query targets only
+    pub(crate) query: Option<String>,
+    pub(crate) time: chrono::DateTime<chrono::Utc>,
+    pub(crate) paths_ignore_case: bool,
+    pub(crate) user_agent: Option<String>,
 }
 
 impl Visit {
     pub fn no_ip(uri: &str) -> Self {
         Self {
-            ip: Ipv4Addr::new(127, 0, 0, 1),
+            ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             country: None,
             city: None,
+            subdivision: None,
+            postal: None,
+            asn: None,
             uri: nice_uri(uri),
+            method: None,
+            geo_unknown: false,
+            host: None,
+            scheme: None,
+            query: uri_query(uri),
+            time: chrono::Utc::now(),
+            paths_ignore_case: false,
+            user_agent: None,
+        }
+    }
+
+    // visit of the IP that could not be geo located
+    pub fn geo_unknown(uri: &str) -> Self {
+        Self {
+            geo_unknown: true,
+            ..Self::no_ip(uri)
         }
     }
 }
 
 impl Visitor for Visit {
-    fn ip(&self) -> Ipv4Addr {
+    fn ip(&self) -> IpAddr {
         self.ip
     }
     fn country(&self) -> Option<String> {
@@ -122,7 +258,72 @@ impl Visitor for Visit {
     fn city(&self) -> Option<String> {
         self.city.clone()
     }
+    fn subdivision(&self) -> Option<String> {
+        self.subdivision.clone()
+    }
+    fn postal(&self) -> Option<String> {
+        self.postal.clone()
+    }
+    fn asn(&self) -> Option<u32> {
+        self.asn
+    }
     fn uri(&self) -> String {
         self.uri.clone()
     }
+    fn method(&self) -> Option<String> {
+        self.method.clone()
+    }
+    fn geo_unknown(&self) -> bool {
+        self.geo_unknown
+    }
+    fn host(&self) -> Option<String> {
+        self.host.clone()
+    }
+    fn scheme(&self) -> Option<String> {
+        self.scheme.clone()
+    }
+    fn user_agent(&self) -> Option<String> {
+        self.user_agent.clone()
+    }
+    fn query(&self) -> Option<String> {
+        self.query.clone()
+    }
+    fn time(&self) -> chrono::DateTime<chrono::Utc> {
+        self.time
+    }
+    fn paths_ignore_case(&self) -> bool {
+        self.paths_ignore_case
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_geo_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let mm = MmKeepInMemory::new_or_no_geo(dir.path().to_str().unwrap());
+        let ip = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+        let v = mm.visit(ip, "/admin?x=1").unwrap();
+        assert_eq!(v.ip, ip);
+        assert_eq!(v.uri, "/admin");
+        assert_eq!(v.country, None);
+        assert_eq!(v.city, None);
+        assert!(!v.geo_unknown);
+        assert!(MmKeepInMemory::new(dir.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_canonical_ip() {
+        let mapped: IpAddr = "::ffff:8.8.8.8".parse().unwrap();
+        assert_eq!(canonical_ip(mapped), IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)));
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(canonical_ip(v6), v6);
+        // IPv4-compatible addresses are deprecated and stay as IPv6
+        let compatible: IpAddr = "::8.8.8.8".parse().unwrap();
+        assert_eq!(canonical_ip(compatible), compatible);
+        let v4 = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        assert_eq!(canonical_ip(v4), v4);
+    }
 }